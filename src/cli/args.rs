@@ -0,0 +1,118 @@
+use crate::util::error::Error;
+use std::collections::VecDeque;
+use std::str::FromStr;
+
+/// A single command line argument: an option without its leading dashes, or a
+/// positional value.
+#[derive(Debug, PartialEq)]
+pub enum Argument {
+    Option(String),
+    Positional(String),
+}
+
+/// Minimal parser for `-x`, `--name`, `--name value` and `--name=value` style options.
+/// Everything after `--` is positional.
+pub struct Arguments {
+    remaining: VecDeque<String>,
+    // the value of a `--name=value` option, until the caller asks for it
+    inline_value: Option<(String, String)>,
+    only_positional: bool,
+}
+
+impl Arguments {
+    pub fn new<I: IntoIterator<Item = String>>(args: I) -> Arguments {
+        Arguments {
+            remaining: args.into_iter().collect(),
+            inline_value: None,
+            only_positional: false,
+        }
+    }
+
+    pub fn next(&mut self) -> Result<Option<Argument>, Error> {
+        if let Some((name, _)) = self.inline_value.take() {
+            return Err(Error::new(
+                &format!("Option --{} does not take a value", name),
+                libc::EINVAL,
+            ));
+        }
+
+        let argument = match self.remaining.pop_front() {
+            Some(argument) => argument,
+            None => return Ok(None),
+        };
+        if self.only_positional || argument == "-" || !argument.starts_with('-') {
+            return Ok(Some(Argument::Positional(argument)));
+        }
+        if argument == "--" {
+            self.only_positional = true;
+            return self.next();
+        }
+
+        let name = argument.trim_start_matches('-');
+        match name.split_once('=') {
+            Some((name, value)) if argument.starts_with("--") => {
+                self.inline_value = Some((name.to_string(), value.to_string()));
+                Ok(Some(Argument::Option(name.to_string())))
+            }
+            _ => Ok(Some(Argument::Option(name.to_string()))),
+        }
+    }
+
+    /// Returns the value belonging to `option`, which was just returned by `next`.
+    pub fn value(&mut self, option: &str) -> Result<String, Error> {
+        if let Some((_, value)) = self.inline_value.take() {
+            return Ok(value);
+        }
+        self.remaining.pop_front().ok_or(Error::new(
+            &format!("Option --{} requires a value", option),
+            libc::EINVAL,
+        ))
+    }
+
+    pub fn parsed_value<T: FromStr>(&mut self, option: &str) -> Result<T, Error> {
+        let value = self.value(option)?;
+        value.parse().map_err(|_| {
+            Error::new(
+                &format!("Invalid value for --{}: {}", option, value),
+                libc::EINVAL,
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn arguments(args: &[&str]) -> Arguments {
+        Arguments::new(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_arguments() {
+        let mut args = arguments(&["-r", "--size", "10", "--block-size=512", "a", "--", "-b"]);
+        let option = |name: &str| Some(Argument::Option(name.to_string()));
+        let positional = |value: &str| Some(Argument::Positional(value.to_string()));
+
+        assert_eq!(args.next().unwrap(), option("r"));
+        assert_eq!(args.next().unwrap(), option("size"));
+        assert_eq!(args.parsed_value::<u64>("size").unwrap(), 10);
+        assert_eq!(args.next().unwrap(), option("block-size"));
+        assert_eq!(args.value("block-size").unwrap(), "512");
+        assert_eq!(args.next().unwrap(), positional("a"));
+        assert_eq!(args.next().unwrap(), positional("-b"));
+        assert_eq!(args.next().unwrap(), None);
+    }
+
+    #[test]
+    fn test_arguments_errors() {
+        let mut args = arguments(&["--force=yes"]);
+        args.next().unwrap();
+        assert!(args.next().is_err());
+
+        let mut args = arguments(&["--size", "big"]);
+        args.next().unwrap();
+        assert!(args.parsed_value::<u64>("size").is_err());
+        assert!(args.value("size").is_err());
+    }
+}
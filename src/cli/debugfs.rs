@@ -0,0 +1,565 @@
+use crate::cli::args::{Argument, Arguments};
+use crate::consts::BlockPointer;
+use crate::driver::file_drive::FileDrive;
+use crate::ops::meta::{InodeType, Metadata};
+use crate::ops::JourneyFS;
+use crate::structure::inode::{BlockMapping, BlockUse, Inode, InodeId};
+use crate::structure::layout::MountOptions;
+use crate::structure::Structure;
+use crate::util::error::Error;
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+pub const USAGE: &str = "\
+Usage: jfs debugfs [options] <image>
+
+Opens an image read-only to look at its structures. Commands are read from standard
+input unless one is given with --request, see `help` for the list.
+
+Options:
+  -R, --request <command>    run a single command and exit
+      --sector-size <bytes>  sector size of the image (default: 512)
+  -h, --help                 print this help";
+
+const COMMANDS: &str = "\
+Commands:
+  superblock           show the superblock and where everything is
+  stat <inode>         show an inode
+  ls <inode>           list a directory
+  cat <inode>          print the contents of an inode
+  blocks <inode>       list the blocks an inode refers to
+  icheck <block>...    find the inodes using blocks
+  dump-block <block>   print a block as hex
+  bitmap               show which blocks and inodes are in use
+  help                 show this list
+  quit                 leave debugfs
+
+Inodes are given as a path from the root directory or as a number in angle brackets,
+like <1>.";
+
+const DEFAULT_SECTOR_SIZE: usize = 512;
+const PROMPT: &str = "debugfs: ";
+const DUMP_WIDTH: usize = 16;
+
+#[derive(Debug, PartialEq)]
+struct DebugfsArguments {
+    image: PathBuf,
+    request: Option<String>,
+    sector_size: usize,
+}
+
+pub fn run(args: Arguments) -> Result<(), Error> {
+    let arguments = match parse(args)? {
+        Some(arguments) => arguments,
+        None => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+    };
+
+    let file = OpenOptions::new()
+        .read(true)
+        .open(&arguments.image)
+        .map_err(|error| Error::io("Cannot open image", error))?;
+    let options = MountOptions {
+        read_only: true,
+        ..MountOptions::default()
+    };
+    let journey_fs =
+        JourneyFS::mount_with(FileDrive::open(file, arguments.sector_size)?, &options)?;
+    let mut debugfs = Debugfs { journey_fs };
+
+    if let Some(request) = arguments.request {
+        let output = debugfs.execute(&request)?.unwrap_or_default();
+        return write_output(&output);
+    }
+
+    let stdin = io::stdin();
+    let interactive = stdin.is_terminal();
+    let mut lines = stdin.lock().lines();
+    loop {
+        if interactive {
+            print!("{}", PROMPT);
+            io::stdout()
+                .flush()
+                .map_err(|error| Error::io("Cannot write output", error))?;
+        }
+        let line = match lines.next() {
+            Some(line) => line.map_err(|error| Error::io("Cannot read command", error))?,
+            None => return Ok(()),
+        };
+        match debugfs.execute(&line) {
+            Ok(Some(output)) => write_output(&output)?,
+            Ok(None) => return Ok(()),
+            Err(error) => eprintln!("debugfs: {}", error),
+        }
+    }
+}
+
+fn write_output(output: &[u8]) -> Result<(), Error> {
+    io::stdout()
+        .write_all(output)
+        .map_err(|error| Error::io("Cannot write output", error))
+}
+
+struct Debugfs {
+    journey_fs: JourneyFS,
+}
+
+impl Debugfs {
+    // Runs one command line and returns what it printed, or `None` to quit.
+    fn execute(&mut self, line: &str) -> Result<Option<Vec<u8>>, Error> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (command, arguments) = match words.split_first() {
+            Some((command, arguments)) => (*command, arguments),
+            None => return Ok(Some(Vec::new())),
+        };
+
+        let lines = match (command, arguments) {
+            ("superblock", []) => self.superblock(),
+            ("stat", [inode]) => self.stat(inode)?,
+            ("ls", [inode]) => self.ls(inode)?,
+            ("cat", [inode]) => return self.cat(inode).map(Some),
+            ("blocks", [inode]) => self.blocks(inode)?,
+            ("icheck", blocks) if !blocks.is_empty() => self.icheck(blocks)?,
+            ("dump-block", [block]) => self.dump_block(block)?,
+            ("bitmap", []) => self.bitmap(),
+            ("help", []) => vec![COMMANDS.to_string()],
+            ("quit", []) | ("exit", []) => return Ok(None),
+            _ => {
+                return Err(Error::new(
+                    &format!("Unknown command or wrong arguments: {}", line.trim()),
+                    libc::EINVAL,
+                ))
+            }
+        };
+
+        let mut output = lines.join("\n");
+        output.push('\n');
+        Ok(Some(output.into_bytes()))
+    }
+
+    fn structure(&self) -> &Structure<Metadata> {
+        self.journey_fs.structure()
+    }
+
+    // Finds an inode by path from the root, or by number as `<n>`.
+    fn resolve(&self, argument: &str) -> Result<InodeId, Error> {
+        let structure = self.structure();
+        if let Some(number) = argument
+            .strip_prefix('<')
+            .and_then(|rest| rest.strip_suffix('>'))
+        {
+            let id = parse_number(number)?;
+            if id == 0 || id >= structure.inode_count() || structure.is_inode_free(id) {
+                return Err(Error::new(
+                    &format!("Inode {} is not in use", id),
+                    libc::ENOENT,
+                ));
+            }
+            return Ok(id);
+        }
+
+        let mut id = structure.super_block.root_inode;
+        for name in argument.split('/').filter(|name| !name.is_empty()) {
+            id = self.journey_fs.lookup(id, OsStr::new(name))?.id.unwrap();
+        }
+        Ok(id)
+    }
+
+    fn superblock(&self) -> Vec<String> {
+        let super_block = &self.structure().super_block;
+        vec![
+            format!("Magic:        {:#x}", super_block.magic),
+            format!("Root inode:   {}", super_block.root_inode),
+            format!("Data start:   {}", self.structure().first_data_block()),
+            format!("Free blocks:  {}", super_block.free_blocks),
+            format!("Free inodes:  {}", super_block.free_inodes),
+            format!("Bad csums:    {}", self.journey_fs.checksum_errors()),
+            self.journey_fs.layout().to_string(),
+        ]
+    }
+
+    fn stat(&self, argument: &str) -> Result<Vec<String>, Error> {
+        let id = self.resolve(argument)?;
+        let inode = self.structure().read_inode(id)?;
+        let meta = &inode.meta;
+        let mapping = match &inode.mapping {
+            BlockMapping::Pointers(_) => "block pointers",
+            BlockMapping::Extents(_) => "extents",
+            BlockMapping::Inline(_) => "inline",
+        };
+
+        let mut lines = vec![
+            format!(
+                "Inode: {}   Type: {}   Mode: {:04o}   Flags: {:#x}",
+                id,
+                type_name(meta.inode_type),
+                meta.permissions,
+                meta.flags
+            ),
+            format!(
+                "Links: {}   User: {}   Group: {}   Device: {:#x}",
+                meta.nlinks, meta.user_id, meta.group_id, meta.rdev
+            ),
+            format!(
+                "Size: {}   Allocated: {}   Mapping: {}",
+                inode.size, inode.allocated_size, mapping
+            ),
+            format!("Created:  {}", format_time(meta.created_at)),
+            format!("Modified: {}", format_time(meta.modified_at)),
+            format!("Accessed: {}", format_time(meta.accessed_at)),
+            format!("Changed:  {}", format_time(meta.changed_at)),
+        ];
+        if meta.inode_type == InodeType::Symlink {
+            let target = self.journey_fs.readlink(id)?;
+            lines.push(format!("Target:   {}", target.to_string_lossy()));
+        }
+        Ok(lines)
+    }
+
+    fn ls(&mut self, argument: &str) -> Result<Vec<String>, Error> {
+        let id = self.resolve(argument)?;
+        let handle = self.journey_fs.opendir(id)?;
+        let entries = self.journey_fs.readdir(handle, 0)?.to_vec();
+        self.journey_fs.releasedir(handle)?;
+
+        let structure = self.structure();
+        Ok(entries
+            .iter()
+            .map(|entry| {
+                let kind =
+                    if entry.id >= structure.inode_count() || structure.is_inode_free(entry.id) {
+                        "<unused>"
+                    } else {
+                        match structure.read_inode(entry.id) {
+                            Ok(inode) => type_name(inode.meta.inode_type),
+                            Err(_) => "<damaged>",
+                        }
+                    };
+                format!(
+                    "{:>10}  {:<16}  {}",
+                    entry.id,
+                    kind,
+                    entry.name.to_string_lossy()
+                )
+            })
+            .collect())
+    }
+
+    fn cat(&self, argument: &str) -> Result<Vec<u8>, Error> {
+        let id = self.resolve(argument)?;
+        let inode = self.structure().read_inode(id)?;
+        inode.read_at(self.structure(), 0, inode.size as usize)
+    }
+
+    fn blocks(&self, argument: &str) -> Result<Vec<String>, Error> {
+        let id = self.resolve(argument)?;
+        let inode = self.structure().read_inode(id)?;
+        let mut data = BTreeMap::new();
+        let mut map = Vec::new();
+        self.walk(&inode, &mut |usage| match usage {
+            BlockUse::Data(index, block) => {
+                data.insert(index, block);
+            }
+            BlockUse::Node(block) => map.push(block),
+        })?;
+
+        let data: Vec<BlockPointer> = data.into_values().collect();
+        Ok(vec![
+            format!("Data: {}", format_ranges(&data)),
+            format!("Map:  {}", format_ranges(&map)),
+        ])
+    }
+
+    fn icheck(&self, arguments: &[&str]) -> Result<Vec<String>, Error> {
+        let structure = self.structure();
+        let blocks = arguments
+            .iter()
+            .map(|argument| parse_number(argument))
+            .collect::<Result<Vec<BlockPointer>, Error>>()?;
+
+        let mut owners = BTreeMap::new();
+        for id in 1..structure.inode_count() {
+            if structure.is_inode_free(id) {
+                continue;
+            }
+            let inode = structure.read_inode(id)?;
+            self.walk(&inode, &mut |usage| {
+                let block = match usage {
+                    BlockUse::Data(_, block) | BlockUse::Node(block) => block,
+                };
+                if blocks.contains(&block) {
+                    owners.entry(block).or_insert(id);
+                }
+            })?;
+        }
+
+        Ok(blocks
+            .iter()
+            .map(|block| {
+                let owner = if *block >= structure.block_count() {
+                    "past the end of the device".to_string()
+                } else if *block < structure.first_data_block() {
+                    "filesystem structures".to_string()
+                } else if let Some(id) = owners.get(block) {
+                    format!("inode {}", id)
+                } else if structure.is_block_free(*block) {
+                    "free".to_string()
+                } else {
+                    "marked used, but not referenced".to_string()
+                };
+                format!("Block {}: {}", block, owner)
+            })
+            .collect())
+    }
+
+    fn dump_block(&self, argument: &str) -> Result<Vec<String>, Error> {
+        let block = parse_number(argument)?;
+        if block >= self.structure().block_count() {
+            return Err(Error::new(
+                &format!("Block {} is past the end of the device", block),
+                libc::EINVAL,
+            ));
+        }
+
+        let data = self.structure().read_block(block)?;
+        let mut lines = Vec::new();
+        let mut previous: Option<&[u8]> = None;
+        for (row, chunk) in data.chunks(DUMP_WIDTH).enumerate() {
+            // runs of identical rows are shown once, like hexdump does
+            if previous == Some(chunk) {
+                if lines.last().map(String::as_str) != Some("*") {
+                    lines.push("*".to_string());
+                }
+                continue;
+            }
+            previous = Some(chunk);
+
+            let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+            let text: String = chunk
+                .iter()
+                .map(|byte| match byte.is_ascii_graphic() || *byte == b' ' {
+                    true => *byte as char,
+                    false => '.',
+                })
+                .collect();
+            lines.push(format!(
+                "{:08x}  {}  |{}|",
+                row * DUMP_WIDTH,
+                hex.join(" "),
+                text
+            ));
+        }
+        lines.push(format!("{:08x}", data.len()));
+        Ok(lines)
+    }
+
+    fn bitmap(&self) -> Vec<String> {
+        let structure = self.structure();
+        let blocks: Vec<u64> = (0..structure.block_count())
+            .filter(|block| !structure.is_block_free(*block))
+            .collect();
+        let inodes: Vec<u64> = (0..structure.inode_count())
+            .filter(|id| !structure.is_inode_free(*id))
+            .collect();
+        vec![
+            format!(
+                "Blocks in use: {} of {}",
+                blocks.len(),
+                structure.block_count()
+            ),
+            format!("  {}", format_ranges(&blocks)),
+            format!(
+                "Inodes in use: {} of {}",
+                inodes.len(),
+                structure.inode_count()
+            ),
+            format!("  {}", format_ranges(&inodes)),
+        ]
+    }
+
+    // Like `Inode::walk_blocks`, but keeps away from blocks past the end of the device.
+    fn walk(&self, inode: &Inode<Metadata>, visit: &mut dyn FnMut(BlockUse)) -> Result<(), Error> {
+        let block_count = self.structure().block_count();
+        inode.walk_blocks(self.structure(), &mut |usage| {
+            visit(usage);
+            match usage {
+                BlockUse::Data(_, block) | BlockUse::Node(block) => block < block_count,
+            }
+        })
+    }
+}
+
+fn type_name(inode_type: InodeType) -> &'static str {
+    match inode_type {
+        InodeType::File => "regular file",
+        InodeType::Directory => "directory",
+        InodeType::Symlink => "symlink",
+        InodeType::CharDevice => "character device",
+        InodeType::BlockDevice => "block device",
+        InodeType::Fifo => "fifo",
+        InodeType::Socket => "socket",
+    }
+}
+
+fn format_time(time: SystemTime) -> String {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(since_epoch) => format!(
+            "{}.{:09}",
+            since_epoch.as_secs(),
+            since_epoch.subsec_nanos()
+        ),
+        Err(_) => "before 1970".to_string(),
+    }
+}
+
+// Collapses runs of consecutive numbers, e.g. `1-4 7 9-10`.
+fn format_ranges(numbers: &[u64]) -> String {
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for number in numbers {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == *number => *end = *number,
+            _ => ranges.push((*number, *number)),
+        }
+    }
+    if ranges.is_empty() {
+        return "none".to_string();
+    }
+
+    ranges
+        .iter()
+        .map(|(start, end)| match start == end {
+            true => start.to_string(),
+            false => format!("{}-{}", start, end),
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+fn parse_number(text: &str) -> Result<u64, Error> {
+    text.parse()
+        .map_err(|_| Error::new(&format!("Invalid number: {}", text), libc::EINVAL))
+}
+
+fn parse(mut args: Arguments) -> Result<Option<DebugfsArguments>, Error> {
+    let mut positional = Vec::new();
+    let mut arguments = DebugfsArguments {
+        image: PathBuf::new(),
+        request: None,
+        sector_size: DEFAULT_SECTOR_SIZE,
+    };
+
+    while let Some(argument) = args.next()? {
+        let name = match argument {
+            Argument::Positional(value) => {
+                positional.push(PathBuf::from(value));
+                continue;
+            }
+            Argument::Option(name) => name,
+        };
+
+        match name.as_str() {
+            "R" | "request" => arguments.request = Some(args.value(&name)?),
+            "sector-size" => arguments.sector_size = args.parsed_value(&name)?,
+            "h" | "help" => return Ok(None),
+            _ => {
+                return Err(Error::new(
+                    &format!("Unknown option `{}`\n\n{}", name, USAGE),
+                    libc::EINVAL,
+                ))
+            }
+        }
+    }
+
+    match <[PathBuf; 1]>::try_from(positional) {
+        Ok([image]) => {
+            arguments.image = image;
+            Ok(Some(arguments))
+        }
+        Err(_) => Err(Error::new(
+            &format!("Expected exactly one image\n\n{}", USAGE),
+            libc::EINVAL,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{create_fs, memory_drive};
+    use std::ffi::OsString;
+
+    fn create_debugfs() -> Debugfs {
+        let mut journey_fs = create_fs(memory_drive(), 0);
+        let directory = journey_fs
+            .mkdir(1, &OsString::from("dir"), 0, 0, 0o755)
+            .unwrap();
+        let (_, handle) = journey_fs
+            .create(
+                directory.inode.id.unwrap(),
+                &OsString::from("file"),
+                0,
+                0,
+                0o644,
+                libc::O_RDWR,
+            )
+            .unwrap();
+        journey_fs.write(handle, 0, &vec![b'x'; 3000]).unwrap();
+        journey_fs.release(handle).unwrap();
+        Debugfs { journey_fs }
+    }
+
+    fn execute(debugfs: &mut Debugfs, line: &str) -> String {
+        String::from_utf8(debugfs.execute(line).unwrap().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_debugfs_commands() {
+        let mut debugfs = create_debugfs();
+        let file = debugfs.resolve("/dir/file").unwrap();
+        let block = debugfs
+            .structure()
+            .read_inode(file)
+            .unwrap()
+            .block_pointer(debugfs.structure(), 0)
+            .unwrap();
+
+        assert!(execute(&mut debugfs, "superblock").contains("Block size:   1024"));
+        assert!(execute(&mut debugfs, "ls /").contains("directory         dir"));
+        let stat = execute(&mut debugfs, &format!("stat <{}>", file));
+        assert!(stat.contains("Type: regular file   Mode: 0644"));
+        assert!(stat.contains("Size: 3000   Allocated: 3072"));
+        assert_eq!(execute(&mut debugfs, "cat dir/file"), "x".repeat(3000));
+        assert_eq!(
+            execute(&mut debugfs, "blocks /dir/file"),
+            format!("Data: {}-{}\nMap:  none\n", block, block + 2)
+        );
+        assert_eq!(
+            execute(&mut debugfs, &format!("icheck {} 0", block + 1)),
+            format!(
+                "Block {}: inode {}\nBlock 0: filesystem structures\n",
+                block + 1,
+                file
+            )
+        );
+        assert!(execute(&mut debugfs, &format!("dump-block {}", block))
+            .starts_with("00000000  78 78 78"));
+        assert!(execute(&mut debugfs, "bitmap").contains("Inodes in use: 4 of"));
+
+        assert!(debugfs.execute("stat /missing").is_err());
+        assert!(debugfs.execute("stat <9999>").is_err());
+        assert!(debugfs.execute("frobnicate").is_err());
+        assert_eq!(debugfs.execute("quit").unwrap(), None);
+    }
+
+    #[test]
+    fn test_format_ranges() {
+        assert_eq!(format_ranges(&[]), "none");
+        assert_eq!(format_ranges(&[1, 2, 3, 4, 7, 9, 10]), "1-4 7 9-10");
+    }
+}
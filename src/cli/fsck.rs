@@ -0,0 +1,154 @@
+use crate::cli::args::{Argument, Arguments};
+use crate::driver::file_drive::FileDrive;
+use crate::ops::JourneyFS;
+use crate::structure::layout::MountOptions;
+use crate::util::error::Error;
+use std::fs::OpenOptions;
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+Usage: jfs fsck [options] <image>
+
+Checks that the block map, the inode table, the inodes and the directory tree of an
+unmounted filesystem agree with each other.
+
+Options:
+  -r, --repair               fix the problems found, orphaned files are moved to
+                             lost+found
+  -b, --backup <block>       read the superblock from the backup in this block, with
+                             --repair it is copied back to block 0
+      --sector-size <bytes>  sector size of the image (default: 512)
+  -h, --help                 print this help";
+
+const DEFAULT_SECTOR_SIZE: usize = 512;
+
+#[derive(Debug, PartialEq)]
+struct FsckArguments {
+    image: PathBuf,
+    repair: bool,
+    backup: Option<u64>,
+    sector_size: usize,
+}
+
+pub fn run(args: Arguments) -> Result<(), Error> {
+    let arguments = match parse(args)? {
+        Some(arguments) => arguments,
+        None => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+    };
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(arguments.repair)
+        .open(&arguments.image)
+        .map_err(|error| Error::io("Cannot open image", error))?;
+    let options = MountOptions {
+        backup: arguments.backup,
+        read_only: !arguments.repair,
+        ..MountOptions::default()
+    };
+    let mut journey_fs =
+        JourneyFS::mount_with(FileDrive::open(file, arguments.sector_size)?, &options)?;
+    if let (Some(backup), true) = (arguments.backup, arguments.repair) {
+        journey_fs.restore_superblock()?;
+        journey_fs.sync()?;
+        println!("Restored the superblock from block {}", backup);
+    }
+
+    let problems = if arguments.repair {
+        journey_fs.repair()?
+    } else {
+        journey_fs.check()?
+    };
+    for problem in &problems {
+        println!("{}", problem);
+    }
+
+    match (problems.len(), arguments.repair) {
+        (0, _) => {
+            println!("{}: clean", arguments.image.display());
+            Ok(())
+        }
+        (count, true) => {
+            journey_fs.sync()?;
+            println!("{}: fixed {} problems", arguments.image.display(), count);
+            Ok(())
+        }
+        (count, false) => Err(Error::new(
+            &format!("Found {} problems, run with --repair to fix them", count),
+            libc::EUCLEAN,
+        )),
+    }
+}
+
+fn parse(mut args: Arguments) -> Result<Option<FsckArguments>, Error> {
+    let mut positional = Vec::new();
+    let mut arguments = FsckArguments {
+        image: PathBuf::new(),
+        repair: false,
+        backup: None,
+        sector_size: DEFAULT_SECTOR_SIZE,
+    };
+
+    while let Some(argument) = args.next()? {
+        let name = match argument {
+            Argument::Positional(value) => {
+                positional.push(PathBuf::from(value));
+                continue;
+            }
+            Argument::Option(name) => name,
+        };
+
+        match name.as_str() {
+            "r" | "repair" => arguments.repair = true,
+            "b" | "backup" => arguments.backup = Some(args.parsed_value(&name)?),
+            "sector-size" => arguments.sector_size = args.parsed_value(&name)?,
+            "h" | "help" => return Ok(None),
+            _ => {
+                return Err(Error::new(
+                    &format!("Unknown option `{}`\n\n{}", name, USAGE),
+                    libc::EINVAL,
+                ))
+            }
+        }
+    }
+
+    match <[PathBuf; 1]>::try_from(positional) {
+        Ok([image]) => {
+            arguments.image = image;
+            Ok(Some(arguments))
+        }
+        Err(_) => Err(Error::new(
+            &format!("Expected exactly one image\n\n{}", USAGE),
+            libc::EINVAL,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Option<FsckArguments>, Error> {
+        parse(Arguments::new(args.iter().map(|arg| arg.to_string())))
+    }
+
+    #[test]
+    fn test_parse_fsck_arguments() {
+        assert_eq!(
+            parse_args(&["--repair", "-b", "8191", "disk.img"]).unwrap(),
+            Some(FsckArguments {
+                image: PathBuf::from("disk.img"),
+                repair: true,
+                backup: Some(8191),
+                sector_size: 512,
+            })
+        );
+        assert_eq!(parse_args(&["-h"]).unwrap(), None);
+        assert!(parse_args(&[]).is_err());
+        assert!(parse_args(&["-x", "disk.img"]).is_err());
+        assert!(parse_args(&["--backup", "last", "disk.img"]).is_err());
+    }
+}
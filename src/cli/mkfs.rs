@@ -0,0 +1,275 @@
+use crate::cli::args::{Argument, Arguments};
+use crate::driver::file_drive::FileDrive;
+use crate::io::IO;
+use crate::ops::meta::Metadata;
+use crate::ops::JourneyFS;
+use crate::structure::layout::FormatOptions;
+use crate::structure::superblock::{FEATURE_DATA_CHECKSUMS, FEATURE_EXTENTS};
+use crate::structure::Structure;
+use crate::util::error::Error;
+use crate::util::format::parse_size;
+use crate::util::uuid;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+Usage: jfs mkfs [options] <image>
+
+Options:
+  -s, --size <size>          size of the image, required if it does not exist yet
+                             (accepts K, M, G and T suffixes)
+      --sector-size <bytes>  sector size of the image (default: 512)
+  -b, --block-size <bytes>   block size, a power of two (default: 4096)
+  -i, --inode-ratio <bytes>  create one inode for every this many bytes
+                             (default: 16384)
+  -L, --label <label>        volume label, at most 16 bytes
+  -U, --uuid <uuid>          volume UUID (default: random)
+  -e, --extents              map file data with extents instead of block pointers
+  -J, --journal-blocks <n>   size of the metadata journal in blocks, 0 disables it
+                             (default: 1/64 of the blocks, 16 to 8192)
+  -C, --data-checksums       keep checksums of the file contents, see `jfs scrub`
+  -F, --force                overwrite an existing filesystem
+  -h, --help                 print this help";
+
+const DEFAULT_SECTOR_SIZE: usize = 512;
+
+struct MkfsArguments {
+    image: PathBuf,
+    size: Option<u64>,
+    sector_size: usize,
+    options: FormatOptions,
+    force: bool,
+}
+
+pub fn run(args: Arguments) -> Result<(), Error> {
+    let arguments = match parse(args)? {
+        Some(arguments) => arguments,
+        None => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+    };
+
+    let file = open_image(&arguments)?;
+    let drive = FileDrive::open(file, arguments.sector_size)?;
+    let (user_id, group_id) = unsafe { (libc::getuid(), libc::getgid()) };
+    let journey_fs = JourneyFS::format(
+        drive,
+        &arguments.options,
+        user_id,
+        group_id,
+        arguments.force,
+    )
+    .map_err(|error| match error.errno() {
+        libc::EEXIST => Error::new(
+            &format!("{}, use --force to overwrite it", error),
+            libc::EEXIST,
+        ),
+        _ => error,
+    })?;
+
+    println!("Created a filesystem on {}", arguments.image.display());
+    println!("{}", journey_fs.layout());
+    Ok(())
+}
+
+// Creates the image if it does not exist yet. Existing images are only resized once
+// it is clear that no filesystem would be lost by it.
+fn open_image(arguments: &MkfsArguments) -> Result<File, Error> {
+    let file = match OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&arguments.image)
+    {
+        Ok(file) => file,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            let size = arguments.size.ok_or(Error::new(
+                "The image does not exist, use --size to create it",
+                libc::ENOENT,
+            ))?;
+            let file = File::create_new(&arguments.image)
+                .map_err(|error| Error::io("Cannot create image", error))?;
+            file.set_len(size)
+                .map_err(|error| Error::io("Cannot resize image", error))?;
+            return Ok(file);
+        }
+        Err(error) => return Err(Error::io("Cannot open image", error)),
+    };
+
+    if let Some(size) = arguments.size {
+        let clone = file
+            .try_clone()
+            .map_err(|error| Error::io("Cannot open image", error))?;
+        if !arguments.force && contains_filesystem(clone, arguments.sector_size)? {
+            return Err(Error::new(
+                "Image already contains a filesystem, use --force to overwrite it",
+                libc::EEXIST,
+            ));
+        }
+        file.set_len(size)
+            .map_err(|error| Error::io("Cannot resize image", error))?;
+    }
+    Ok(file)
+}
+
+// Only looks at the superblock and its backups. Mounting would replay the journal,
+// which changes the image even if mkfs then refuses to touch it.
+fn contains_filesystem(file: File, sector_size: usize) -> Result<bool, Error> {
+    let drive = FileDrive::open(file, sector_size)?;
+    let mut io = IO::new(drive, sector_size);
+    match Structure::<Metadata>::is_initialized(&mut io) {
+        // a superblock that is only damaged counts as well
+        Err(error) if error.errno() == libc::EUCLEAN => Ok(true),
+        result => result,
+    }
+}
+
+fn parse(mut args: Arguments) -> Result<Option<MkfsArguments>, Error> {
+    let mut positional = Vec::new();
+    let mut arguments = MkfsArguments {
+        image: PathBuf::new(),
+        size: None,
+        sector_size: DEFAULT_SECTOR_SIZE,
+        options: FormatOptions {
+            uuid: uuid::generate()?,
+            ..FormatOptions::default()
+        },
+        force: false,
+    };
+
+    while let Some(argument) = args.next()? {
+        let name = match argument {
+            Argument::Positional(value) => {
+                positional.push(PathBuf::from(value));
+                continue;
+            }
+            Argument::Option(name) => name,
+        };
+
+        match name.as_str() {
+            "s" | "size" => {
+                let value = args.value(&name)?;
+                let size = parse_size(&value).ok_or(Error::new(
+                    &format!("Invalid size: {}", value),
+                    libc::EINVAL,
+                ))?;
+                arguments.size = Some(size);
+            }
+            "sector-size" => arguments.sector_size = args.parsed_value(&name)?,
+            "b" | "block-size" => arguments.options.block_size = args.parsed_value(&name)?,
+            "i" | "inode-ratio" => arguments.options.bytes_per_inode = args.parsed_value(&name)?,
+            "L" | "label" => arguments.options.label = args.value(&name)?,
+            "U" | "uuid" => {
+                let value = args.value(&name)?;
+                arguments.options.uuid = uuid::parse(&value).ok_or(Error::new(
+                    &format!("Invalid UUID: {}", value),
+                    libc::EINVAL,
+                ))?;
+            }
+            "e" | "extents" => arguments.options.features |= FEATURE_EXTENTS,
+            "C" | "data-checksums" => arguments.options.features |= FEATURE_DATA_CHECKSUMS,
+            "J" | "journal-blocks" => {
+                arguments.options.journal_blocks = Some(args.parsed_value(&name)?)
+            }
+            "F" | "force" => arguments.force = true,
+            "h" | "help" => return Ok(None),
+            _ => {
+                return Err(Error::new(
+                    &format!("Unknown option `{}`\n\n{}", name, USAGE),
+                    libc::EINVAL,
+                ))
+            }
+        }
+    }
+
+    if !arguments.sector_size.is_power_of_two() {
+        return Err(Error::new(
+            "Sector size must be a power of two",
+            libc::EINVAL,
+        ));
+    }
+
+    match <[PathBuf; 1]>::try_from(positional) {
+        Ok([image]) => {
+            arguments.image = image;
+            Ok(Some(arguments))
+        }
+        Err(_) => Err(Error::new(
+            &format!("Expected exactly one image\n\n{}", USAGE),
+            libc::EINVAL,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{create_fs, memory_drive};
+    use std::ffi::OsString;
+
+    fn parse_args(args: &[&str]) -> Result<Option<MkfsArguments>, Error> {
+        parse(Arguments::new(args.iter().map(|arg| arg.to_string())))
+    }
+
+    #[test]
+    fn test_parse_mkfs_arguments() {
+        let arguments = parse_args(&[
+            "-s",
+            "64M",
+            "--block-size=1024",
+            "-i",
+            "4096",
+            "-L",
+            "data",
+            "--uuid",
+            "0123abcd-4567-89ef-0123-456789abcdef",
+            "-e",
+            "-J",
+            "128",
+            "-C",
+            "disk.img",
+        ])
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(arguments.image, PathBuf::from("disk.img"));
+        assert_eq!(arguments.size, Some(64 * 1024 * 1024));
+        assert_eq!(arguments.sector_size, 512);
+        assert_eq!(arguments.options.block_size, 1024);
+        assert_eq!(arguments.options.bytes_per_inode, 4096);
+        assert_eq!(arguments.options.label, "data");
+        assert_eq!(arguments.options.uuid[0..2], [0x01, 0x23]);
+        assert_eq!(
+            arguments.options.features,
+            FEATURE_EXTENTS | FEATURE_DATA_CHECKSUMS
+        );
+        assert_eq!(arguments.options.journal_blocks, Some(128));
+        assert!(!arguments.force);
+
+        assert!(parse_args(&["-s", "lots", "disk.img"]).is_err());
+        assert!(parse_args(&["--uuid", "nope", "disk.img"]).is_err());
+        assert!(parse_args(&["a.img", "b.img"]).is_err());
+    }
+
+    #[test]
+    fn test_refused_mkfs_leaves_image_alone() {
+        let drive = memory_drive();
+        let mut journey_fs = create_fs(drive.clone(), 0);
+        journey_fs
+            .mkdir(fuser::FUSE_ROOT_ID, &OsString::from("dir"), 0, 0, 0o755)
+            .unwrap();
+        // the last transaction is still waiting in the journal, a mount would replay it
+        let path = std::env::temp_dir().join(format!("jfs_test_mkfs_{}.img", std::process::id()));
+        drive.export(&path).unwrap();
+
+        let arguments = parse_args(&["-s", "20M", path.to_str().unwrap()])
+            .unwrap()
+            .unwrap();
+        let error = open_image(&arguments).err().unwrap();
+        let image = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(error.errno(), libc::EEXIST);
+        assert!(image == drive.snapshot());
+    }
+}
@@ -0,0 +1,42 @@
+use crate::cli::args::Arguments;
+use crate::util::error::Error;
+
+mod args;
+mod debugfs;
+mod fsck;
+mod mkfs;
+mod mount;
+mod scrub;
+
+pub const USAGE: &str = "\
+Usage: jfs <command> [options]
+
+Commands:
+  debugfs  inspect the structures of a filesystem image
+  fsck     check and repair a filesystem image
+  mkfs     create a filesystem image
+  mount    mount a filesystem image
+  scrub    verify the file contents of a filesystem image against their checksums
+
+Run `jfs <command> --help` for the options of a command.";
+
+/// Runs the command named by the first of `args`.
+pub fn run(args: Vec<String>) -> Result<(), Error> {
+    let mut args = args.into_iter();
+    match args.next().as_deref() {
+        Some("debugfs") => debugfs::run(Arguments::new(args)),
+        Some("fsck") => fsck::run(Arguments::new(args)),
+        Some("mkfs") => mkfs::run(Arguments::new(args)),
+        Some("mount") => mount::run(Arguments::new(args)),
+        Some("scrub") => scrub::run(Arguments::new(args)),
+        Some("-h") | Some("--help") => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        Some(command) => Err(Error::new(
+            &format!("Unknown command `{}`\n\n{}", command, USAGE),
+            libc::EINVAL,
+        )),
+        None => Err(Error::new(USAGE, libc::EINVAL)),
+    }
+}
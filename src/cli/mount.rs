@@ -0,0 +1,424 @@
+use crate::cli::args::{Argument, Arguments};
+use crate::driver::file_drive::FileDrive;
+use crate::driver::memory_drive::MemoryDrive;
+use crate::driver::DeviceDriver;
+use crate::fuse::FuseDriver;
+use crate::ops::scrub::Scrub;
+use crate::ops::JourneyFS;
+use crate::structure::layout::{DataMode, FormatOptions, MountOptions};
+use crate::util::error::Error;
+use crate::util::format::parse_size;
+use crate::util::uuid;
+use fuser::{BackgroundSession, MountOption};
+use libc::c_int;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{fs, io, process, ptr, thread};
+
+pub const USAGE: &str = "\
+Usage: jfs mount [options] <image> <mountpoint>
+       jfs mount [options] --memory [<image>] <mountpoint>
+
+Options:
+  -r, --read-only      mount the filesystem read-only
+      --data <mode>    how file contents are journaled: journal, ordered or
+                       writeback (default: ordered, always journal with data
+                       checksums)
+  -b, --backup <block> recover from the backup superblock in this block, see
+                       `jfs debugfs` for where the backups are
+      --scrub          verify the file contents against their checksums in the
+                       background, bad blocks are reported on stderr
+  -m, --memory         keep the filesystem in memory, the image is only read from
+  -s, --size <size>    with --memory and no image, create an empty filesystem of
+                       this size (accepts K, M, G and T suffixes)
+      --save <image>   with --memory, write the filesystem to this image on unmount
+      --allow-other    allow other users to access the filesystem
+      --auto-unmount   unmount automatically when jfs exits
+  -f, --foreground     stay in the foreground instead of running as a daemon
+  -h, --help           print this help";
+
+const SECTOR_SIZE: usize = 512;
+// how often to check whether the filesystem was unmounted from the outside
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+// the background scrub holds the filesystem for this many inodes at a time
+const SCRUB_BATCH: u64 = 16;
+const SCRUB_PAUSE: Duration = Duration::from_millis(10);
+
+#[derive(Debug, PartialEq)]
+struct MountArguments {
+    image: Option<PathBuf>,
+    mount_point: PathBuf,
+    memory: bool,
+    size: Option<u64>,
+    save: Option<PathBuf>,
+    read_only: bool,
+    data_mode: DataMode,
+    backup: Option<u64>,
+    scrub: bool,
+    allow_other: bool,
+    auto_unmount: bool,
+    foreground: bool,
+}
+
+pub fn run(args: Arguments) -> Result<(), Error> {
+    let arguments = match parse(args)? {
+        Some(arguments) => arguments,
+        None => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+    };
+
+    let mount_options = MountOptions {
+        data_mode: arguments.data_mode,
+        backup: arguments.backup,
+        read_only: arguments.read_only,
+    };
+    let (mut journey_fs, fs_name, memory) = match arguments.memory {
+        true => {
+            let drive = open_memory(&arguments)?;
+            if arguments.image.is_none() {
+                format_memory(drive.clone())?;
+            }
+            let journey_fs = mount_image(drive.clone(), &mount_options)?;
+            (journey_fs, String::from("memory"), Some(drive))
+        }
+        false => {
+            let image = canonical_image(&arguments)?;
+            let file = OpenOptions::new()
+                .read(true)
+                .write(!arguments.read_only)
+                .open(&image)
+                .map_err(|error| Error::io("Cannot open image", error))?;
+            let journey_fs = mount_image(FileDrive::open(file, SECTOR_SIZE)?, &mount_options)?;
+            (journey_fs, image.display().to_string(), None)
+        }
+    };
+    if arguments.backup.is_some() && !arguments.read_only {
+        journey_fs.restore_superblock()?;
+    }
+    if arguments.scrub && !journey_fs.has_data_checksums() {
+        return Err(Error::new(
+            "--scrub needs a filesystem created with data checksums",
+            libc::EINVAL,
+        ));
+    }
+
+    let mut options = vec![
+        MountOption::FSName(fs_name),
+        MountOption::Subtype(String::from("jfs")),
+    ];
+    if arguments.read_only {
+        options.push(MountOption::RO);
+    }
+    if arguments.allow_other {
+        options.push(MountOption::AllowOther);
+    }
+    if arguments.auto_unmount {
+        options.push(MountOption::AutoUnmount);
+    }
+
+    // forking has to happen before fuser starts any threads
+    let ready = match arguments.foreground {
+        true => None,
+        false => Some(daemonize()?),
+    };
+    // blocked before the session thread is spawned, so that it inherits the mask
+    // and the signals are left for us to pick up
+    let signals = block_signals();
+
+    let journey_fs = Arc::new(Mutex::new(journey_fs));
+    let driver = FuseDriver::new(journey_fs.clone());
+    let session = fuser::spawn_mount2(driver, &arguments.mount_point, &options)
+        .map_err(|error| Error::io("Failed to mount", error))?;
+    if let Some(ready) = ready {
+        detach(ready);
+    }
+    if arguments.scrub {
+        spawn_scrub(journey_fs);
+    }
+
+    wait_for_exit(&signals, &session);
+    // unmounts if still mounted and waits for the session to flush everything
+    session.join();
+    match (memory, arguments.save) {
+        (Some(drive), Some(path)) => drive.export(&path),
+        _ => Ok(()),
+    }
+}
+
+fn canonical_image(arguments: &MountArguments) -> Result<PathBuf, Error> {
+    let image = arguments.image.as_deref().unwrap_or(Path::new(""));
+    fs::canonicalize(image).map_err(|error| Error::io("Cannot open image", error))
+}
+
+// Loads the image into memory, or creates an empty device of the requested size.
+fn open_memory(arguments: &MountArguments) -> Result<MemoryDrive, Error> {
+    match arguments.size {
+        Some(size) => Ok(MemoryDrive::new(size, SECTOR_SIZE)),
+        None => {
+            let file = File::open(canonical_image(arguments)?)
+                .map_err(|error| Error::io("Cannot open image", error))?;
+            MemoryDrive::load(file, SECTOR_SIZE)
+        }
+    }
+}
+
+fn mount_image<D: DeviceDriver + 'static>(
+    drive: D,
+    options: &MountOptions,
+) -> Result<JourneyFS, Error> {
+    JourneyFS::mount_with(drive, options).map_err(|error| {
+        Error::new(
+            &format!("{}, use `jfs mkfs` to create one", error),
+            error.errno(),
+        )
+    })
+}
+
+fn format_memory(drive: MemoryDrive) -> Result<(), Error> {
+    let options = FormatOptions {
+        uuid: uuid::generate()?,
+        ..FormatOptions::default()
+    };
+    let (user_id, group_id) = unsafe { (libc::getuid(), libc::getgid()) };
+    JourneyFS::format(drive, &options, user_id, group_id, false)?;
+    Ok(())
+}
+
+// Scrubs the mounted filesystem once, a few inodes at a time so that requests are only
+// held up briefly.
+fn spawn_scrub(journey_fs: Arc<Mutex<JourneyFS>>) {
+    thread::spawn(move || {
+        let mut scrub = Scrub::new(false);
+        let mut bad_blocks = 0;
+        loop {
+            let result = JourneyFS::lock(&journey_fs).scrub_step(&mut scrub, SCRUB_BATCH);
+            for bad_block in scrub.bad_blocks.drain(..) {
+                eprintln!("{}", bad_block);
+                bad_blocks += 1;
+            }
+            match result {
+                Ok(false) => thread::sleep(SCRUB_PAUSE),
+                Ok(true) => return eprintln!("Scrub finished, found {} bad blocks", bad_blocks),
+                Err(error) => return eprintln!("Scrub failed: {}", error),
+            }
+        }
+    });
+}
+
+fn parse(mut args: Arguments) -> Result<Option<MountArguments>, Error> {
+    let mut positional = Vec::new();
+    let mut arguments = MountArguments {
+        image: None,
+        mount_point: PathBuf::new(),
+        memory: false,
+        size: None,
+        save: None,
+        read_only: false,
+        data_mode: DataMode::default(),
+        backup: None,
+        scrub: false,
+        allow_other: false,
+        auto_unmount: false,
+        foreground: false,
+    };
+
+    while let Some(argument) = args.next()? {
+        match argument {
+            Argument::Positional(value) => positional.push(PathBuf::from(value)),
+            Argument::Option(name) => match name.as_str() {
+                "r" | "read-only" => arguments.read_only = true,
+                "data" => arguments.data_mode = args.parsed_value(&name)?,
+                "b" | "backup" => arguments.backup = Some(args.parsed_value(&name)?),
+                "scrub" => arguments.scrub = true,
+                "m" | "memory" => arguments.memory = true,
+                "s" | "size" => {
+                    let value = args.value(&name)?;
+                    let size = parse_size(&value).ok_or(Error::new(
+                        &format!("Invalid size: {}", value),
+                        libc::EINVAL,
+                    ))?;
+                    arguments.size = Some(size);
+                }
+                "save" => arguments.save = Some(PathBuf::from(args.value(&name)?)),
+                "allow-other" => arguments.allow_other = true,
+                "auto-unmount" => arguments.auto_unmount = true,
+                "f" | "foreground" => arguments.foreground = true,
+                "h" | "help" => return Ok(None),
+                _ => {
+                    return Err(Error::new(
+                        &format!("Unknown option `{}`\n\n{}", name, USAGE),
+                        libc::EINVAL,
+                    ))
+                }
+            },
+        }
+    }
+
+    if !arguments.memory && (arguments.size.is_some() || arguments.save.is_some()) {
+        return Err(Error::new(
+            &format!("--size and --save require --memory\n\n{}", USAGE),
+            libc::EINVAL,
+        ));
+    }
+    match (positional.len(), arguments.size) {
+        (2, None) => arguments.image = Some(positional.remove(0)),
+        (1, Some(_)) if arguments.memory => {}
+        (1, None) if arguments.memory => {
+            return Err(Error::new(
+                &format!("Expected an image or --size\n\n{}", USAGE),
+                libc::EINVAL,
+            ))
+        }
+        (2, Some(_)) => {
+            return Err(Error::new(
+                &format!("--size cannot be used with an image\n\n{}", USAGE),
+                libc::EINVAL,
+            ))
+        }
+        _ => {
+            return Err(Error::new(
+                &format!("Expected an image and a mount point\n\n{}", USAGE),
+                libc::EINVAL,
+            ))
+        }
+    }
+    arguments.mount_point = positional.remove(0);
+    Ok(Some(arguments))
+}
+
+fn block_signals() -> libc::sigset_t {
+    unsafe {
+        let mut signals: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGINT);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+        libc::sigaddset(&mut signals, libc::SIGHUP);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &signals, ptr::null_mut());
+        signals
+    }
+}
+
+// Returns once one of `signals` arrives or the session ends on its own, which is what
+// happens after `fusermount -u`.
+fn wait_for_exit(signals: &libc::sigset_t, session: &BackgroundSession) {
+    let timeout = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: POLL_INTERVAL.as_nanos() as libc::c_long,
+    };
+    while !session.guard.is_finished() {
+        if unsafe { libc::sigtimedwait(signals, ptr::null_mut(), &timeout) } > 0 {
+            return;
+        }
+    }
+}
+
+// Forks into the background. The parent stays around only until the child reports a
+// successful mount through a pipe, so that errors still end up in its exit code. The
+// child gets the write end of that pipe.
+fn daemonize() -> Result<c_int, Error> {
+    let mut pipe = [0 as c_int; 2];
+    if unsafe { libc::pipe(pipe.as_mut_ptr()) } != 0 {
+        return Err(Error::io("Failed to daemonize", io::Error::last_os_error()));
+    }
+
+    match unsafe { libc::fork() } {
+        -1 => Err(Error::io("Failed to daemonize", io::Error::last_os_error())),
+        0 => unsafe {
+            libc::close(pipe[0]);
+            libc::setsid();
+            Ok(pipe[1])
+        },
+        _ => {
+            unsafe { libc::close(pipe[1]) };
+            let mut status = 0u8;
+            // the pipe closes without a status if the child fails to mount
+            let read = unsafe { libc::read(pipe[0], ptr::addr_of_mut!(status).cast(), 1) };
+            process::exit(if read == 1 && status == 1 { 0 } else { 1 });
+        }
+    }
+}
+
+// Tells the waiting parent that mounting worked and lets go of the terminal.
+fn detach(ready: c_int) {
+    unsafe {
+        let status = 1u8;
+        libc::write(ready, ptr::addr_of!(status).cast(), 1);
+        libc::close(ready);
+        libc::chdir(c"/".as_ptr());
+        let null = libc::open(c"/dev/null".as_ptr(), libc::O_RDWR);
+        for fd in 0..3 {
+            libc::dup2(null, fd);
+        }
+        if null > 2 {
+            libc::close(null);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Option<MountArguments>, Error> {
+        parse(Arguments::new(args.iter().map(|arg| arg.to_string())))
+    }
+
+    #[test]
+    fn test_parse_mount_arguments() {
+        let arguments = parse_args(&["-r", "disk.img", "--auto-unmount", "/mnt", "-f"])
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            arguments,
+            MountArguments {
+                image: Some(PathBuf::from("disk.img")),
+                mount_point: PathBuf::from("/mnt"),
+                memory: false,
+                size: None,
+                save: None,
+                read_only: true,
+                data_mode: DataMode::Ordered,
+                backup: None,
+                scrub: false,
+                allow_other: false,
+                auto_unmount: true,
+                foreground: true,
+            }
+        );
+
+        let arguments = parse_args(&[
+            "-m",
+            "--size",
+            "64M",
+            "--save",
+            "out.img",
+            "--data=journal",
+            "--scrub",
+            "-b",
+            "4095",
+            "/mnt",
+        ])
+        .unwrap()
+        .unwrap();
+        assert!(arguments.memory);
+        assert_eq!(arguments.image, None);
+        assert_eq!(arguments.size, Some(64 * 1024 * 1024));
+        assert_eq!(arguments.save, Some(PathBuf::from("out.img")));
+        assert_eq!(arguments.data_mode, DataMode::Journal);
+        assert!(arguments.scrub);
+        assert_eq!(arguments.backup, Some(4095));
+        assert_eq!(arguments.mount_point, PathBuf::from("/mnt"));
+
+        assert!(parse_args(&["--help"]).unwrap().is_none());
+        assert!(parse_args(&["--memory", "/mnt"]).is_err());
+        assert!(parse_args(&["--memory", "-s", "1M", "disk.img", "/mnt"]).is_err());
+        assert!(parse_args(&["--save", "out.img", "disk.img", "/mnt"]).is_err());
+        assert!(parse_args(&["disk.img"]).is_err());
+        assert!(parse_args(&["--bogus", "disk.img", "/mnt"]).is_err());
+        assert!(parse_args(&["--data", "sometimes", "disk.img", "/mnt"]).is_err());
+    }
+}
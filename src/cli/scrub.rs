@@ -0,0 +1,162 @@
+use crate::cli::args::{Argument, Arguments};
+use crate::driver::file_drive::FileDrive;
+use crate::driver::mirror_drive::MirrorDrive;
+use crate::driver::DeviceDriver;
+use crate::ops::JourneyFS;
+use crate::structure::layout::MountOptions;
+use crate::util::error::Error;
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+
+pub const USAGE: &str = "\
+Usage: jfs scrub [options] <image> [<mirror>...]
+
+Reads every data block of an unmounted filesystem created with `jfs mkfs
+--data-checksums` and reports the blocks that no longer match their checksums. Images
+given as mirrors hold identical copies of the filesystem.
+
+Options:
+  -r, --repair               rewrite bad blocks from an intact copy on a mirror
+      --sector-size <bytes>  sector size of the images (default: 512)
+  -h, --help                 print this help";
+
+const DEFAULT_SECTOR_SIZE: usize = 512;
+
+#[derive(Debug, PartialEq)]
+struct ScrubArguments {
+    image: PathBuf,
+    mirrors: Vec<PathBuf>,
+    repair: bool,
+    sector_size: usize,
+}
+
+pub fn run(args: Arguments) -> Result<(), Error> {
+    let arguments = match parse(args)? {
+        Some(arguments) => arguments,
+        None => {
+            println!("{}", USAGE);
+            return Ok(());
+        }
+    };
+
+    let mut drives = vec![open_drive(&arguments.image, &arguments)?];
+    for mirror in &arguments.mirrors {
+        drives.push(open_drive(mirror, &arguments)?);
+    }
+    let options = MountOptions {
+        read_only: !arguments.repair,
+        ..MountOptions::default()
+    };
+    let mut journey_fs = JourneyFS::mount_with(MirrorDrive::new(drives)?, &options)?;
+
+    let bad_blocks = journey_fs.scrub(arguments.repair)?;
+    for bad_block in &bad_blocks {
+        println!("{}", bad_block);
+    }
+
+    let repaired = bad_blocks
+        .iter()
+        .filter(|bad_block| bad_block.repaired)
+        .count();
+    if repaired != 0 {
+        journey_fs.sync()?;
+    }
+    match (bad_blocks.len(), repaired) {
+        (0, _) => {
+            println!("{}: clean", arguments.image.display());
+            Ok(())
+        }
+        (count, repaired) if count == repaired => {
+            println!("{}: repaired {} blocks", arguments.image.display(), count);
+            Ok(())
+        }
+        (count, repaired) => Err(Error::new(
+            &format!(
+                "Found {} bad blocks, {} of them could not be repaired",
+                count,
+                count - repaired
+            ),
+            libc::EIO,
+        )),
+    }
+}
+
+fn open_drive(path: &Path, arguments: &ScrubArguments) -> Result<Box<dyn DeviceDriver>, Error> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(arguments.repair)
+        .open(path)
+        .map_err(|error| Error::io("Cannot open image", error))?;
+    Ok(Box::new(FileDrive::open(file, arguments.sector_size)?))
+}
+
+fn parse(mut args: Arguments) -> Result<Option<ScrubArguments>, Error> {
+    let mut positional = Vec::new();
+    let mut arguments = ScrubArguments {
+        image: PathBuf::new(),
+        mirrors: Vec::new(),
+        repair: false,
+        sector_size: DEFAULT_SECTOR_SIZE,
+    };
+
+    while let Some(argument) = args.next()? {
+        let name = match argument {
+            Argument::Positional(value) => {
+                positional.push(PathBuf::from(value));
+                continue;
+            }
+            Argument::Option(name) => name,
+        };
+
+        match name.as_str() {
+            "r" | "repair" => arguments.repair = true,
+            "sector-size" => arguments.sector_size = args.parsed_value(&name)?,
+            "h" | "help" => return Ok(None),
+            _ => {
+                return Err(Error::new(
+                    &format!("Unknown option `{}`\n\n{}", name, USAGE),
+                    libc::EINVAL,
+                ))
+            }
+        }
+    }
+
+    if positional.is_empty() {
+        return Err(Error::new(
+            &format!("Expected an image\n\n{}", USAGE),
+            libc::EINVAL,
+        ));
+    }
+    arguments.image = positional.remove(0);
+    arguments.mirrors = positional;
+    Ok(Some(arguments))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Option<ScrubArguments>, Error> {
+        parse(Arguments::new(args.iter().map(|arg| arg.to_string())))
+    }
+
+    #[test]
+    fn test_parse_scrub_arguments() {
+        assert_eq!(
+            parse_args(&["-r", "disk.img", "copy.img"]).unwrap(),
+            Some(ScrubArguments {
+                image: PathBuf::from("disk.img"),
+                mirrors: vec![PathBuf::from("copy.img")],
+                repair: true,
+                sector_size: 512,
+            })
+        );
+        assert_eq!(
+            parse_args(&["disk.img"]).unwrap().unwrap().mirrors,
+            Vec::<PathBuf>::new()
+        );
+        assert_eq!(parse_args(&["--help"]).unwrap(), None);
+        assert!(parse_args(&[]).is_err());
+        assert!(parse_args(&["--mirror", "disk.img"]).is_err());
+    }
+}
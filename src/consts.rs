@@ -1,8 +1,13 @@
 pub(crate) const SUPERBLOCK_SIZE: usize = 1024;
-pub(crate) const BLOCKS_PER_INODE_MAP: usize = 10240;
+pub(crate) const DEFAULT_BYTES_PER_INODE: u64 = 16384;
 pub(crate) const DIRECT_POINTERS: usize = 12;
+pub(crate) const INDIRECT_POINTERS: usize = 3;
 pub(crate) const FILE_NAME_LENGTH: usize = 255;
+// journal sizes picked when formatting, unless one is given
+pub(crate) const MIN_JOURNAL_BLOCKS: u64 = 16;
+pub(crate) const MAX_JOURNAL_BLOCKS: u64 = 8192;
 
 pub type BlockPointer = u64;
 pub type InodePointer = u64;
 pub type DirectPointers = [BlockPointer; DIRECT_POINTERS];
+pub type IndirectPointers = [BlockPointer; INDIRECT_POINTERS];
@@ -0,0 +1,336 @@
+use crate::driver::DeviceDriver;
+use crate::util::error::Error;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+#[derive(Default)]
+struct FaultState {
+    failing_reads: HashSet<u64>,
+    failing_writes: HashSet<u64>,
+    // (sector, bit) pairs that read back flipped
+    flipped_bits: Vec<(u64, usize)>,
+    // sector writes still reaching the device before power is lost
+    writes_left: Option<u64>,
+    writes: u64,
+}
+
+/// Scripts the faults of a `FaultyDrive`, also after the drive was handed to a
+/// filesystem.
+#[derive(Clone, Default)]
+pub struct Faults(Arc<Mutex<FaultState>>);
+
+impl Faults {
+    /// Reads of `sector` fail with `EIO`.
+    pub fn fail_reads(&self, sector: u64) {
+        self.state().failing_reads.insert(sector);
+    }
+
+    /// Writes to `sector` fail with `EIO` and leave it unchanged.
+    pub fn fail_writes(&self, sector: u64) {
+        self.state().failing_writes.insert(sector);
+    }
+
+    /// `bit` of `sector` reads back inverted, as if it rotted on the medium.
+    pub fn flip_bit(&self, sector: u64, bit: usize) {
+        self.state().flipped_bits.push((sector, bit));
+    }
+
+    /// Lets `writes` more sector writes through and silently drops all later ones. A
+    /// cut in the middle of `IO::write_block` leaves a torn block behind.
+    pub fn power_loss_after(&self, writes: u64) {
+        self.state().writes_left = Some(writes);
+    }
+
+    /// The number of sector writes that reached the device.
+    pub fn writes(&self) -> u64 {
+        self.state().writes
+    }
+
+    pub fn clear(&self) {
+        *self.state() = FaultState::default();
+    }
+
+    fn state(&self) -> MutexGuard<'_, FaultState> {
+        self.0.lock().unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+/// Wraps another device and misbehaves as scripted through its `Faults`.
+pub struct FaultyDrive<D: DeviceDriver> {
+    drive: D,
+    faults: Faults,
+}
+
+impl<D: DeviceDriver> FaultyDrive<D> {
+    pub fn new(drive: D) -> FaultyDrive<D> {
+        FaultyDrive {
+            drive,
+            faults: Faults::default(),
+        }
+    }
+
+    pub fn faults(&self) -> Faults {
+        self.faults.clone()
+    }
+}
+
+impl<D: DeviceDriver> DeviceDriver for FaultyDrive<D> {
+    fn get_sector_count(&self) -> u64 {
+        self.drive.get_sector_count()
+    }
+
+    fn get_sector_size(&self) -> usize {
+        self.drive.get_sector_size()
+    }
+
+    fn read_sector(&self, index: u64) -> Result<Vec<u8>, Error> {
+        let state = self.faults.state();
+        if state.failing_reads.contains(&index) {
+            return Err(Error::new(
+                &format!("Injected read error in sector {}", index),
+                libc::EIO,
+            ));
+        }
+        let mut sector = self.drive.read_sector(index)?;
+        for (_, bit) in state
+            .flipped_bits
+            .iter()
+            .filter(|(sector, _)| *sector == index)
+        {
+            sector[bit / 8] ^= 1 << (bit % 8);
+        }
+        Ok(sector)
+    }
+
+    fn write_sector(&mut self, index: u64, data: &[u8]) -> Result<(), Error> {
+        let mut state = self.faults.state();
+        if state.failing_writes.contains(&index) {
+            return Err(Error::new(
+                &format!("Injected write error in sector {}", index),
+                libc::EIO,
+            ));
+        }
+        match state.writes_left {
+            Some(0) => return Ok(()),
+            Some(ref mut left) => *left -= 1,
+            None => {}
+        }
+        state.writes += 1;
+        self.drive.write_sector(index, data)
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        self.drive.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::consts::SUPERBLOCK_SIZE;
+    use crate::driver::faulty_drive::FaultyDrive;
+    use crate::driver::memory_drive::MemoryDrive;
+    use crate::driver::DeviceDriver;
+    use crate::io::IO;
+    use crate::ops::JourneyFS;
+    use crate::structure::superblock::FEATURE_DATA_CHECKSUMS;
+    use crate::testing::{create_fs, BLOCK_SIZE};
+    use crate::util::error::Error;
+    use std::ffi::OsString;
+
+    fn format() -> MemoryDrive {
+        let drive = MemoryDrive::new(4096 * 1024, 512);
+        create_fs(drive.clone(), 0);
+        drive
+    }
+
+    fn populate(fs: &mut JourneyFS) -> Result<(), Error> {
+        let root = fs.structure().super_block.root_inode;
+        let directory = fs.mkdir(root, &OsString::from("dir"), 0, 0, 0o755)?;
+        let (_, handle) = fs.create(
+            directory.inode.id.unwrap(),
+            &OsString::from("file"),
+            0,
+            0,
+            0o644,
+            libc::O_RDWR,
+        )?;
+        fs.write(handle, 0, &vec![7u8; 20 * 1024])?;
+        fs.release(handle)
+    }
+
+    fn read_back(fs: &mut JourneyFS) -> Result<Vec<u8>, Error> {
+        let root = fs.structure().super_block.root_inode;
+        let directory = fs.lookup(root, &OsString::from("dir"))?;
+        let file = fs.lookup(directory.id.unwrap(), &OsString::from("file"))?;
+        let handle = fs.open(file.id.unwrap(), libc::O_RDONLY)?;
+        fs.read(handle, 0, 20 * 1024)
+    }
+
+    #[test]
+    fn test_injected_errors() {
+        let drive = FaultyDrive::new(MemoryDrive::new(64 * 512, 512));
+        let faults = drive.faults();
+        let mut io = IO::new(drive, 512);
+        faults.fail_reads(3);
+        faults.fail_writes(4);
+        assert_eq!(io.read_block(3).unwrap_err().errno(), libc::EIO);
+        assert_eq!(
+            io.write_block(4, &vec![1; 512]).unwrap_err().errno(),
+            libc::EIO
+        );
+        faults.clear();
+        io.write_block(4, &vec![1; 512]).unwrap();
+        assert_eq!(io.read_block(4).unwrap(), vec![1; 512]);
+    }
+
+    #[test]
+    fn test_torn_write() {
+        let memory = MemoryDrive::new(64 * 512, 512);
+        let drive = FaultyDrive::new(memory.clone());
+        let faults = drive.faults();
+        let mut io = IO::new(drive, 1024);
+        faults.power_loss_after(1);
+        io.write_block(2, &vec![0xaa; 1024]).unwrap();
+        assert_eq!(faults.writes(), 1);
+        assert_eq!(memory.read_sector(4).unwrap(), vec![0xaa; 512]);
+        assert_eq!(memory.read_sector(5).unwrap(), vec![0; 512]);
+    }
+
+    #[test]
+    fn test_mount_detects_damage() {
+        let memory = format();
+
+        let drive = FaultyDrive::new(memory.clone());
+        drive.faults().fail_reads(0);
+        assert_eq!(JourneyFS::mount(drive).err().unwrap().errno(), libc::EIO);
+
+        // 1024 becomes 1025, caught by the superblock's checksum
+        let drive = FaultyDrive::new(memory.clone());
+        drive.faults().flip_bit(0, 4 * 8);
+        let error = JourneyFS::mount(drive).err().unwrap();
+        assert_eq!(error.errno(), libc::EUCLEAN);
+    }
+
+    #[test]
+    fn test_checksums_catch_bit_rot() {
+        let memory = format();
+        let drive = FaultyDrive::new(memory.clone());
+        let block_count = drive.get_sector_count() * 512 / BLOCK_SIZE as u64;
+        let last = block_count - 1;
+        let map_sector = (SUPERBLOCK_SIZE.div_ceil(BLOCK_SIZE) * BLOCK_SIZE / 512) as u64;
+        drive.faults().flip_bit(map_sector, last as usize);
+        assert_eq!(
+            JourneyFS::mount(drive).err().unwrap().errno(),
+            libc::EUCLEAN
+        );
+
+        // damage to a directory block only shows once the directory is read
+        let mut fs = JourneyFS::mount(memory.clone()).unwrap();
+        populate(&mut fs).unwrap();
+        let root = fs.structure().super_block.root_inode;
+        let inode = fs.structure().read_inode(root).unwrap();
+        let block = inode.block_pointer(fs.structure(), 0).unwrap();
+        let drive = FaultyDrive::new(memory);
+        drive
+            .faults()
+            .flip_bit(block * (BLOCK_SIZE / 512) as u64, 9);
+        let fs = JourneyFS::mount(drive).unwrap();
+        assert_eq!(fs.checksum_errors(), 0);
+        let error = fs.lookup(root, &OsString::from("dir")).err().unwrap();
+        assert_eq!(error.errno(), libc::EUCLEAN);
+        assert_eq!(fs.checksum_errors(), 1);
+    }
+
+    #[test]
+    fn test_data_checksums() {
+        let memory = MemoryDrive::new(4096 * 1024, 512);
+        create_fs(memory.clone(), FEATURE_DATA_CHECKSUMS);
+        let mut fs = JourneyFS::mount(memory.clone()).unwrap();
+        populate(&mut fs).unwrap();
+        let root = fs.structure().super_block.root_inode;
+        let directory = fs.lookup(root, &OsString::from("dir")).unwrap();
+        let file = fs
+            .lookup(directory.id.unwrap(), &OsString::from("file"))
+            .unwrap();
+        let block = file.block_pointer(fs.structure(), 3).unwrap();
+        drop(fs);
+
+        // without data checksums the damage would go unnoticed
+        let drive = FaultyDrive::new(memory);
+        drive
+            .faults()
+            .flip_bit(block * (BLOCK_SIZE / 512) as u64, 100);
+        let mut fs = JourneyFS::mount(drive).unwrap();
+        assert_eq!(read_back(&mut fs).unwrap_err().errno(), libc::EIO);
+        assert_eq!(fs.checksum_errors(), 1);
+    }
+
+    #[test]
+    fn test_data_checksums_survive_power_loss() {
+        let memory = MemoryDrive::new(4096 * 1024, 512);
+        let mut fs = create_fs(memory.clone(), FEATURE_DATA_CHECKSUMS);
+        populate(&mut fs).unwrap();
+        fs.sync().unwrap();
+        let image = memory.snapshot();
+        let overwrite = |fs: &mut JourneyFS| -> Result<(), Error> {
+            let root = fs.structure().super_block.root_inode;
+            let directory = fs.lookup(root, &OsString::from("dir"))?;
+            let file = fs.lookup(directory.id.unwrap(), &OsString::from("file"))?;
+            let handle = fs.open(file.id.unwrap(), libc::O_RDWR)?;
+            fs.write(handle, 0, &vec![8u8; 20 * 1024])?;
+            fs.release(handle)
+        };
+
+        let drive = FaultyDrive::new(MemoryDrive::from_bytes(image.clone(), 512));
+        let faults = drive.faults();
+        overwrite(&mut JourneyFS::mount(drive).unwrap()).unwrap();
+        let total = faults.writes();
+
+        for cut in 0..total {
+            let memory = MemoryDrive::from_bytes(image.clone(), 512);
+            let drive = FaultyDrive::new(memory.clone());
+            drive.faults().power_loss_after(cut);
+            let _ = overwrite(&mut JourneyFS::mount(drive).unwrap());
+
+            // every block holds either the old or the new contents, and matches the
+            // checksum recorded for it
+            let mut fs = JourneyFS::mount(memory).unwrap();
+            let contents = read_back(&mut fs).unwrap();
+            for block in contents.chunks(BLOCK_SIZE) {
+                let value = block[0];
+                assert!(value == 7 || value == 8, "cut after {}", cut);
+                assert!(block.iter().all(|byte| *byte == value), "cut after {}", cut);
+            }
+            assert_eq!(fs.checksum_errors(), 0, "cut after {}", cut);
+        }
+    }
+
+    #[test]
+    fn test_survive_power_loss() {
+        let memory = format();
+        let drive = FaultyDrive::new(memory.clone());
+        let faults = drive.faults();
+        let mut fs = JourneyFS::mount(drive).unwrap();
+        populate(&mut fs).unwrap();
+        let total = faults.writes();
+
+        for cut in 0..total {
+            let memory = format();
+            let drive = FaultyDrive::new(memory.clone());
+            drive.faults().power_loss_after(cut);
+            let mut fs = JourneyFS::mount(drive).unwrap();
+            // later operations may read back what was dropped and fail, only the
+            // state left on the device matters
+            let _ = populate(&mut fs);
+            drop(fs);
+
+            // the journal replays or drops whatever was cut short
+            let mut fs = JourneyFS::mount(memory).unwrap();
+            assert_eq!(fs.check().unwrap(), vec![], "cut after {}", cut);
+            // ordered data is on the device before the file grows to cover it
+            if let Ok(contents) = read_back(&mut fs) {
+                assert!(contents.iter().all(|byte| *byte == 7), "cut after {}", cut);
+            }
+        }
+    }
+}
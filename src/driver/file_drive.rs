@@ -1,4 +1,5 @@
 use crate::driver::DeviceDriver;
+use crate::util::error::Error;
 use std::fs::File;
 use std::os::unix::fs::FileExt;
 
@@ -9,33 +10,25 @@ pub struct FileDrive {
 }
 
 impl FileDrive {
-    pub fn new(name: &str, bytes: u64, sector_size: usize) -> FileDrive {
-        let file = File::create_new(name).unwrap();
-        file.set_len(bytes).unwrap();
-        FileDrive {
+    /// Creates the image file `name` with a size of `bytes`, failing if it exists.
+    #[cfg(test)]
+    pub fn new(name: &str, bytes: u64, sector_size: usize) -> Result<FileDrive, Error> {
+        let file = File::create_new(name)?;
+        file.set_len(bytes)?;
+        Ok(FileDrive {
             file,
             bytes,
             sector_size,
-        }
-    }
-
-    pub fn open(file: File, sector_size: usize) -> FileDrive {
-        let bytes = file.metadata().unwrap().len();
-        FileDrive {
-            file,
-            bytes,
-            sector_size,
-        }
+        })
     }
 
-    pub fn open_path(path: &str, sector_size: usize) -> FileDrive {
-        let file = File::open(path).unwrap();
-        let bytes = file.metadata().unwrap().len();
-        FileDrive {
+    pub fn open(file: File, sector_size: usize) -> Result<FileDrive, Error> {
+        let bytes = file.metadata()?.len();
+        Ok(FileDrive {
             file,
             bytes,
             sector_size,
-        }
+        })
     }
 }
 
@@ -48,25 +41,32 @@ impl DeviceDriver for FileDrive {
         self.sector_size
     }
 
-    fn read_sector(&self, index: u64) -> Vec<u8> {
+    fn read_sector(&self, index: u64) -> Result<Vec<u8>, Error> {
         let mut buffer = vec![0; self.sector_size];
         self.file
-            .read_at(&mut buffer, index * self.sector_size as u64)
-            .unwrap();
-        buffer
+            .read_exact_at(&mut buffer, index * self.sector_size as u64)?;
+        Ok(buffer)
     }
 
-    fn write_sector(&mut self, index: u64, sector: &Vec<u8>) {
+    fn write_sector(&mut self, index: u64, sector: &[u8]) -> Result<(), Error> {
         if sector.len() != self.sector_size {
-            panic!(
-                "Sector size mismatch - expected {}, got {}",
-                self.sector_size,
-                sector.len()
-            );
+            return Err(Error::new(
+                &format!(
+                    "Sector size mismatch - expected {}, got {}",
+                    self.sector_size,
+                    sector.len()
+                ),
+                libc::EINVAL,
+            ));
         }
         self.file
-            .write_at(&sector, index * self.sector_size as u64)
-            .unwrap();
+            .write_all_at(sector, index * self.sector_size as u64)?;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        self.file.sync_all()?;
+        Ok(())
     }
 }
 
@@ -77,29 +77,31 @@ mod tests {
 
     #[test]
     fn test_hard_drive() {
-        let mut drive = FileDrive::new("./test-images/test_drive.img", 1024 * 512, 512);
+        let path = std::env::temp_dir().join(format!("jfs_test_drive_{}.img", std::process::id()));
+        let mut drive = FileDrive::new(path.to_str().unwrap(), 1024 * 512, 512).unwrap();
+        std::fs::remove_file(&path).unwrap();
 
         let sector0 = vec![0x42; 512];
         let sector1 = vec![0x1; 512];
         let sector512 = vec![0x8; 512];
         let sector1023 = vec![0x52; 512];
 
-        drive.write_sector(0, &sector0);
-        drive.write_sector(1, &sector1);
-        drive.write_sector(512, &sector512);
-        drive.write_sector(1023, &sector1023);
+        drive.write_sector(0, &sector0).unwrap();
+        drive.write_sector(1, &sector1).unwrap();
+        drive.write_sector(512, &sector512).unwrap();
+        drive.write_sector(1023, &sector1023).unwrap();
 
-        assert_eq!(drive.read_sector(0), sector0);
-        assert_eq!(drive.read_sector(1), sector1);
-        assert_eq!(drive.read_sector(512), sector512);
-        assert_eq!(drive.read_sector(1023), sector1023);
-        assert_eq!(drive.read_sector(2), vec![0; 512]);
-        assert_eq!(drive.read_sector(511), vec![0; 512]);
+        assert_eq!(drive.read_sector(0).unwrap(), sector0);
+        assert_eq!(drive.read_sector(1).unwrap(), sector1);
+        assert_eq!(drive.read_sector(512).unwrap(), sector512);
+        assert_eq!(drive.read_sector(1023).unwrap(), sector1023);
+        assert_eq!(drive.read_sector(2).unwrap(), vec![0; 512]);
+        assert_eq!(drive.read_sector(511).unwrap(), vec![0; 512]);
 
         let mut buffer = vec![0x42, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49];
         buffer.append(&mut vec![0; 504]);
-        drive.write_sector(0, &buffer);
+        drive.write_sector(0, &buffer).unwrap();
 
-        assert_eq!(drive.read_sector(0), buffer);
+        assert_eq!(drive.read_sector(0).unwrap(), buffer);
     }
 }
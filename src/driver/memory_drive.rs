@@ -0,0 +1,141 @@
+use crate::driver::DeviceDriver;
+use crate::util::error::Error;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// A device that only lives in memory. Clones share the same storage, so a clone
+/// kept around after handing the drive to a filesystem still sees every write.
+#[derive(Clone)]
+pub struct MemoryDrive {
+    data: Arc<Mutex<Vec<u8>>>,
+    pub sector_size: usize,
+}
+
+impl MemoryDrive {
+    /// Creates a zeroed device of `bytes` bytes.
+    pub fn new(bytes: u64, sector_size: usize) -> MemoryDrive {
+        MemoryDrive::from_bytes(vec![0; bytes as usize], sector_size)
+    }
+
+    pub fn from_bytes(data: Vec<u8>, sector_size: usize) -> MemoryDrive {
+        MemoryDrive {
+            data: Arc::new(Mutex::new(data)),
+            sector_size,
+        }
+    }
+
+    /// Reads a whole image file into memory, later writes never reach the file.
+    pub fn load(mut file: File, sector_size: usize) -> Result<MemoryDrive, Error> {
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        Ok(MemoryDrive::from_bytes(data, sector_size))
+    }
+
+    /// A copy of the current contents.
+    pub fn snapshot(&self) -> Vec<u8> {
+        self.data().clone()
+    }
+
+    /// Writes the current contents to `path` as an image that `FileDrive` can open.
+    pub fn export(&self, path: &Path) -> Result<(), Error> {
+        // copied first so that writers are not held up by the file system
+        std::fs::write(path, self.snapshot())?;
+        Ok(())
+    }
+
+    fn data(&self) -> MutexGuard<'_, Vec<u8>> {
+        // the data stays consistent sector by sector even if a writer panicked
+        self.data.lock().unwrap_or_else(|error| error.into_inner())
+    }
+
+    fn check_sector(&self, index: u64) -> Result<usize, Error> {
+        if index >= self.get_sector_count() {
+            return Err(Error::new(
+                &format!("Sector {} out of range", index),
+                libc::EIO,
+            ));
+        }
+        Ok(index as usize * self.sector_size)
+    }
+}
+
+impl DeviceDriver for MemoryDrive {
+    fn get_sector_count(&self) -> u64 {
+        (self.data().len() / self.sector_size) as u64
+    }
+
+    fn get_sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn read_sector(&self, index: u64) -> Result<Vec<u8>, Error> {
+        let offset = self.check_sector(index)?;
+        Ok(self.data()[offset..offset + self.sector_size].to_vec())
+    }
+
+    fn write_sector(&mut self, index: u64, sector: &[u8]) -> Result<(), Error> {
+        if sector.len() != self.sector_size {
+            return Err(Error::new(
+                &format!(
+                    "Sector size mismatch - expected {}, got {}",
+                    self.sector_size,
+                    sector.len()
+                ),
+                libc::EINVAL,
+            ));
+        }
+        let offset = self.check_sector(index)?;
+        self.data()[offset..offset + self.sector_size].copy_from_slice(sector);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::driver::file_drive::FileDrive;
+    use crate::driver::memory_drive::MemoryDrive;
+    use crate::driver::DeviceDriver;
+    use std::fs::File;
+
+    #[test]
+    fn test_memory_drive() {
+        let mut drive = MemoryDrive::new(1024 * 512, 512);
+        let shared = drive.clone();
+        assert_eq!(drive.get_sector_count(), 1024);
+
+        let sector = vec![0x42; 512];
+        drive.write_sector(3, &sector).unwrap();
+        assert_eq!(drive.read_sector(3).unwrap(), sector);
+        assert_eq!(shared.read_sector(3).unwrap(), sector);
+        assert_eq!(drive.read_sector(2).unwrap(), vec![0; 512]);
+
+        let snapshot = drive.snapshot();
+        drive.write_sector(3, &vec![0; 512]).unwrap();
+        assert_eq!(snapshot[3 * 512..4 * 512], sector[..]);
+        let restored = MemoryDrive::from_bytes(snapshot, 512);
+        assert_eq!(restored.read_sector(3).unwrap(), sector);
+
+        assert_eq!(drive.read_sector(1024).unwrap_err().errno(), libc::EIO);
+        assert_eq!(
+            drive.write_sector(0, &[0; 16]).unwrap_err().errno(),
+            libc::EINVAL
+        );
+    }
+
+    #[test]
+    fn test_export_and_load() {
+        let path = std::env::temp_dir().join(format!("jfs_test_export_{}.img", std::process::id()));
+        let mut drive = MemoryDrive::new(64 * 512, 512);
+        drive.write_sector(7, &vec![0x17; 512]).unwrap();
+        drive.export(&path).unwrap();
+
+        let file_drive = FileDrive::open(File::open(&path).unwrap(), 512).unwrap();
+        assert_eq!(file_drive.get_sector_count(), 64);
+        assert_eq!(file_drive.read_sector(7).unwrap(), vec![0x17; 512]);
+        let loaded = MemoryDrive::load(File::open(&path).unwrap(), 512).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.snapshot(), drive.snapshot());
+    }
+}
@@ -0,0 +1,103 @@
+use crate::driver::DeviceDriver;
+use crate::util::error::Error;
+
+/// Keeps identical copies of the data on several devices of the same size. Writes go to
+/// every device and reads are served by the first one, the others are only read when
+/// asked for a particular copy.
+pub struct MirrorDrive {
+    drives: Vec<Box<dyn DeviceDriver>>,
+}
+
+impl MirrorDrive {
+    pub fn new(drives: Vec<Box<dyn DeviceDriver>>) -> Result<MirrorDrive, Error> {
+        let first = drives
+            .first()
+            .ok_or_else(|| Error::new("A mirror needs at least one device", libc::EINVAL))?;
+        let geometry = (first.get_sector_size(), first.get_sector_count());
+        if drives
+            .iter()
+            .any(|drive| (drive.get_sector_size(), drive.get_sector_count()) != geometry)
+        {
+            return Err(Error::new(
+                "Mirrored devices must have the same size",
+                libc::EINVAL,
+            ));
+        }
+        Ok(MirrorDrive { drives })
+    }
+}
+
+impl DeviceDriver for MirrorDrive {
+    fn get_sector_count(&self) -> u64 {
+        self.drives[0].get_sector_count()
+    }
+
+    fn get_sector_size(&self) -> usize {
+        self.drives[0].get_sector_size()
+    }
+
+    fn read_sector(&self, index: u64) -> Result<Vec<u8>, Error> {
+        self.drives[0].read_sector(index)
+    }
+
+    fn write_sector(&mut self, index: u64, data: &[u8]) -> Result<(), Error> {
+        for drive in &mut self.drives {
+            drive.write_sector(index, data)?;
+        }
+        Ok(())
+    }
+
+    fn copies(&self) -> usize {
+        self.drives.len()
+    }
+
+    fn read_sector_copy(&self, index: u64, copy: usize) -> Result<Vec<u8>, Error> {
+        match self.drives.get(copy) {
+            Some(drive) => drive.read_sector(index),
+            None => Err(Error::new(
+                &format!("No copy {} of sector {}", copy, index),
+                libc::EINVAL,
+            )),
+        }
+    }
+
+    fn sync(&mut self) -> Result<(), Error> {
+        for drive in &mut self.drives {
+            drive.sync()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::driver::memory_drive::MemoryDrive;
+    use crate::driver::mirror_drive::MirrorDrive;
+    use crate::driver::DeviceDriver;
+
+    #[test]
+    fn test_mirror_drive() {
+        let first = MemoryDrive::new(64 * 512, 512);
+        let second = MemoryDrive::new(64 * 512, 512);
+        let mut drive =
+            MirrorDrive::new(vec![Box::new(first.clone()), Box::new(second.clone())]).unwrap();
+        assert_eq!(drive.copies(), 2);
+
+        drive.write_sector(5, &vec![0x55; 512]).unwrap();
+        assert_eq!(first.read_sector(5).unwrap(), vec![0x55; 512]);
+        assert_eq!(second.read_sector(5).unwrap(), vec![0x55; 512]);
+
+        let mut damaged = first.clone();
+        damaged.write_sector(5, &vec![0; 512]).unwrap();
+        assert_eq!(drive.read_sector(5).unwrap(), vec![0; 512]);
+        assert_eq!(drive.read_sector_copy(5, 1).unwrap(), vec![0x55; 512]);
+        assert_eq!(
+            drive.read_sector_copy(5, 2).unwrap_err().errno(),
+            libc::EINVAL
+        );
+
+        let small = MemoryDrive::new(32 * 512, 512);
+        assert!(MirrorDrive::new(vec![Box::new(first), Box::new(small)]).is_err());
+        assert!(MirrorDrive::new(Vec::new()).is_err());
+    }
+}
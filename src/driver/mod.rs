@@ -1,8 +1,35 @@
+use crate::util::error::Error;
+
+#[cfg(test)]
+pub(crate) mod faulty_drive;
 pub(crate) mod file_drive;
+pub(crate) mod memory_drive;
+pub(crate) mod mirror_drive;
 
-pub trait DeviceDriver {
+pub trait DeviceDriver: Send {
     fn get_sector_count(&self) -> u64;
     fn get_sector_size(&self) -> usize;
-    fn read_sector(&self, index: u64) -> Vec<u8>;
-    fn write_sector(&mut self, index: u64, data: &Vec<u8>);
+    fn read_sector(&self, index: u64) -> Result<Vec<u8>, Error>;
+    fn write_sector(&mut self, index: u64, data: &[u8]) -> Result<(), Error>;
+
+    /// The number of copies kept of every sector.
+    fn copies(&self) -> usize {
+        1
+    }
+
+    /// Reads one particular copy of a sector, copy 0 being what `read_sector` returns.
+    fn read_sector_copy(&self, index: u64, copy: usize) -> Result<Vec<u8>, Error> {
+        match copy {
+            0 => self.read_sector(index),
+            _ => Err(Error::new(
+                &format!("No copy {} of sector {}", copy, index),
+                libc::EINVAL,
+            )),
+        }
+    }
+
+    /// Makes sure everything written so far has reached the underlying storage.
+    fn sync(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
 }
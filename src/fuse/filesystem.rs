@@ -1,55 +1,61 @@
-use core::panic;
-use fuser::{FileAttr, FileType, Filesystem, ReplyAttr, ReplyEntry, Request, TimeOrNow};
-use libc::c_int;
+use fuser::{
+    FileAttr, FileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory,
+    ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, Request,
+    TimeOrNow,
+};
 use std::ffi::OsStr;
-use std::fs::File;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::time::{Duration, SystemTime};
 
-use crate::driver::file_drive::FileDrive;
+use crate::consts::FILE_NAME_LENGTH;
 use crate::ops::meta::{GroupId, InodeType, Metadata, UserId};
-use crate::ops::JourneyFS;
+use crate::ops::{FileHandle, JourneyFS};
 use crate::structure::inode::{Inode, InodeId};
-use crate::util::error::Error;
 use crate::util::mode::{ModeBits, ModeBitsHelper};
 
 const TTL: Duration = Duration::new(100, 0);
 
-struct FuseDriver {
-    size: u64,
-    journey_fs: Option<JourneyFS>,
-    mount_path: String,
-    sector_size: usize,
+pub(crate) struct FuseDriver {
+    // shared with the background scrub, see `jfs mount --scrub`
+    journey_fs: Arc<Mutex<JourneyFS>>,
     block_size: usize,
 }
 
 impl Filesystem for FuseDriver {
-    fn init(&mut self, _req: &Request<'_>, _config: &mut fuser::KernelConfig) -> Result<(), c_int> {
-        if self.journey_fs.is_some() {
-            panic!("init should only be called once")
+    fn destroy(&mut self) {
+        if let Err(error) = self.fs().forget_all() {
+            eprintln!("Failed to free unlinked inodes: {}", error);
         }
+        if let Err(error) = self.fs().sync() {
+            eprintln!("Failed to sync filesystem: {}", error);
+        }
+    }
 
-        let drive = match File::open(self.mount_path.as_str()) {
-            Ok(file) => FileDrive::open(file, self.sector_size),
-            Err(_) => FileDrive::new(self.mount_path.as_str(), self.size, self.sector_size),
-        };
-
-        // TODO: should error if an already existing file does not match our parameters
-
-        match JourneyFS::new(drive, _req.uid(), _req.gid(), self.block_size) {
-            Ok(fs) => {
-                self.journey_fs = Some(fs);
-                Ok(())
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let result = self.fs().lookup(parent as InodeId, name);
+        match result {
+            Err(error) => reply.error(error.errno()),
+            Ok(inode) => {
+                let attr = self.inode_to_fileattr(inode);
+                self.remember(attr.ino);
+                reply.entry(&TTL, &attr, 0);
             }
-            Err(error) => Err(error.error_num),
+        }
+    }
+
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+        if let Err(error) = self.fs().forget(ino as InodeId, nlookup) {
+            eprintln!("Failed to free inode {}: {}", ino, error);
         }
     }
 
     fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
         // TODO: really need error handling
-        let inode = self.get_fs_ref().get_inode(ino as InodeId);
+        let inode = self.fs().get_inode(ino as InodeId);
         match inode {
             Ok(inode) => reply.attr(&TTL, &self.inode_to_fileattr(inode)),
-            Err(error) => reply.error(error.error_num),
+            Err(error) => reply.error(error.errno()),
         }
     }
 
@@ -72,15 +78,16 @@ impl Filesystem for FuseDriver {
         flags: Option<u32>,
         reply: ReplyAttr,
     ) {
-        let result = self.get_fs_ref().get_inode(ino as InodeId);
+        let result = self.fs().get_inode(ino as InodeId);
 
         match result {
-            Err(error) => reply.error(error.error_num),
+            Err(error) => reply.error(error.errno()),
             Ok(mut inode) => {
-                // TODO: resize
-                if let Some(_) = size {
-                    reply.error(libc::EOPNOTSUPP);
-                    return;
+                if let Some(size) = size {
+                    match self.fs().truncate(ino as InodeId, size) {
+                        Ok(truncated) => inode = truncated,
+                        Err(error) => return reply.error(error.errno()),
+                    }
                 }
 
                 if let Some(mode) = mode {
@@ -106,9 +113,9 @@ impl Filesystem for FuseDriver {
                     inode.meta.changed_at = ctime;
                 }
 
-                match self.get_mut_fs_ref().write_inode(&mut inode) {
-                    Ok(_) => reply.attr(&TTL, &FuseDriver::inode_to_fileattr(&self, inode)),
-                    Err(error) => reply.error(error.error_num),
+                match self.fs().write_inode(&mut inode) {
+                    Ok(_) => reply.attr(&TTL, &self.inode_to_fileattr(inode)),
+                    Err(error) => reply.error(error.errno()),
                 }
             }
         }
@@ -125,97 +132,411 @@ impl Filesystem for FuseDriver {
     ) {
         // TODO: apply umask if necessary
         let permissions = mode.get_permissions();
-        let result = self.get_mut_fs_ref().mkdir(
+        let result = self.fs().mkdir(
             parent,
-            &name.try_into().unwrap(),
+            &name.to_os_string(),
             req.uid(),
             req.gid(),
             permissions,
         );
 
         match result {
-            Err(error) => reply.error(error.error_num),
+            Err(error) => reply.error(error.errno()),
             Ok(directory) => {
-                reply.entry(
-                    &Duration::new(100, 0),
-                    &self.inode_to_fileattr(directory.inode),
-                    0,
-                );
+                let attr = self.inode_to_fileattr(directory.inode);
+                self.remember(attr.ino);
+                reply.entry(&TTL, &attr, 0);
+            }
+        }
+    }
+
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.fs().unlink(parent as InodeId, name) {
+            Ok(_) => reply.ok(),
+            Err(error) => reply.error(error.errno()),
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.fs().rmdir(parent as InodeId, name) {
+            Ok(_) => reply.ok(),
+            Err(error) => reply.error(error.errno()),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        match self.fs().readlink(ino as InodeId) {
+            Ok(target) => reply.data(target.as_encoded_bytes()),
+            Err(error) => reply.error(error.errno()),
+        }
+    }
+
+    fn symlink(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        link_name: &OsStr,
+        target: &Path,
+        reply: ReplyEntry,
+    ) {
+        let result = self.fs().symlink(
+            parent as InodeId,
+            &link_name.to_os_string(),
+            target.as_os_str(),
+            req.uid(),
+            req.gid(),
+        );
+
+        match result {
+            Err(error) => reply.error(error.errno()),
+            Ok(symlink) => {
+                let attr = self.inode_to_fileattr(symlink.inode);
+                self.remember(attr.ino);
+                reply.entry(&TTL, &attr, 0);
+            }
+        }
+    }
+
+    fn link(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        let result = self
+            .fs()
+            .link(ino as InodeId, newparent as InodeId, newname);
+        match result {
+            Err(error) => reply.error(error.errno()),
+            Ok(inode) => {
+                let attr = self.inode_to_fileattr(inode);
+                self.remember(attr.ino);
+                reply.entry(&TTL, &attr, 0);
+            }
+        }
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let result = self.fs().rename(
+            parent as InodeId,
+            name,
+            newparent as InodeId,
+            newname,
+            flags,
+        );
+
+        match result {
+            Ok(_) => reply.ok(),
+            Err(error) => reply.error(error.errno()),
+        }
+    }
+
+    fn mknod(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: ModeBits,
+        _umask: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        let inode_type = match FuseDriver::mode_to_inode_type(mode) {
+            Some(inode_type) => inode_type,
+            None => return reply.error(libc::EINVAL),
+        };
+        let mut meta = Metadata::new(
+            inode_type,
+            req.uid(),
+            req.gid(),
+            mode.get_permissions(),
+            1,
+            0,
+        );
+        meta.rdev = rdev;
+        let result = self
+            .fs()
+            .mknod(parent as InodeId, &name.to_os_string(), meta);
+
+        match result {
+            Err(error) => reply.error(error.errno()),
+            Ok(inode) => {
+                let attr = self.inode_to_fileattr(inode);
+                self.remember(attr.ino);
+                reply.entry(&TTL, &attr, 0);
+            }
+        }
+    }
+
+    fn opendir(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        match self.fs().opendir(ino as InodeId) {
+            Ok(handle) => reply.opened(handle, 0),
+            Err(error) => reply.error(error.errno()),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let entries = match self.fs().readdir(fh as FileHandle, offset as usize) {
+            Ok(entries) => entries.to_vec(),
+            Err(error) => return reply.error(error.errno()),
+        };
+
+        for (i, entry) in entries.iter().enumerate() {
+            let kind = match self.fs().get_inode(entry.id) {
+                Ok(inode) => FuseDriver::inode_type_to_file_type(inode.meta.inode_type),
+                Err(error) => return reply.error(error.errno()),
+            };
+            // the offset handed to the kernel is the one to resume from after this entry
+            if reply.add(entry.id, offset + i as i64 + 1, kind, &entry.name) {
+                break;
             }
         }
+        reply.ok();
+    }
+
+    fn readdirplus(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
+    ) {
+        let entries = match self.fs().readdir(fh as FileHandle, offset as usize) {
+            Ok(entries) => entries.to_vec(),
+            Err(error) => return reply.error(error.errno()),
+        };
+
+        for (i, entry) in entries.iter().enumerate() {
+            let attr = match self.fs().get_inode(entry.id) {
+                Ok(inode) => self.inode_to_fileattr(inode),
+                Err(error) => return reply.error(error.errno()),
+            };
+            if reply.add(entry.id, offset + i as i64 + 1, &entry.name, &TTL, &attr, 0) {
+                break;
+            }
+            // the kernel takes a lookup reference for everything but `.` and `..`
+            if entry.name != "." && entry.name != ".." {
+                self.remember(attr.ino);
+            }
+        }
+        reply.ok();
+    }
+
+    fn create(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: ModeBits,
+        _umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        let result = self.fs().create(
+            parent as InodeId,
+            &name.to_os_string(),
+            req.uid(),
+            req.gid(),
+            mode.get_permissions(),
+            flags,
+        );
+
+        match result {
+            Err(error) => reply.error(error.errno()),
+            Ok((file, handle)) => {
+                let attr = self.inode_to_fileattr(file.inode);
+                self.remember(attr.ino);
+                reply.created(&TTL, &attr, 0, handle, 0);
+            }
+        }
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        match self.fs().open(ino as InodeId, flags) {
+            Ok(handle) => reply.opened(handle, 0),
+            Err(error) => reply.error(error.errno()),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        match self
+            .fs()
+            .read(fh as FileHandle, offset as u64, size as usize)
+        {
+            Ok(data) => reply.data(&data),
+            Err(error) => reply.error(error.errno()),
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        match self.fs().write(fh as FileHandle, offset as u64, data) {
+            Ok(written) => reply.written(written as u32),
+            Err(error) => reply.error(error.errno()),
+        }
+    }
+
+    fn flush(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _lock_owner: u64,
+        reply: ReplyEmpty,
+    ) {
+        match self.fs().flush(fh as FileHandle) {
+            Ok(_) => reply.ok(),
+            Err(error) => reply.error(error.errno()),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        match self.fs().release(fh as FileHandle) {
+            Ok(_) => reply.ok(),
+            Err(error) => reply.error(error.errno()),
+        }
+    }
+
+    fn releasedir(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        reply: ReplyEmpty,
+    ) {
+        match self.fs().releasedir(fh as FileHandle) {
+            Ok(_) => reply.ok(),
+            Err(error) => reply.error(error.errno()),
+        }
+    }
+
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+        let usage = self.fs().usage();
+        reply.statfs(
+            usage.block_count,
+            usage.free_blocks,
+            usage.free_blocks,
+            usage.inode_count,
+            usage.free_inodes,
+            usage.block_size as u32,
+            FILE_NAME_LENGTH as u32,
+            usage.block_size as u32,
+        );
     }
 }
 
 impl FuseDriver {
-    fn new(
-        mount_path: &str,
-        size: u64,
-        block_size: usize,
-        sector_size: usize,
-    ) -> Result<FuseDriver, Error> {
-        return Ok(FuseDriver {
-            mount_path: String::from(mount_path),
-            size,
-            journey_fs: None,
+    pub(crate) fn new(journey_fs: Arc<Mutex<JourneyFS>>) -> FuseDriver {
+        let block_size = journey_fs.lock().unwrap().get_block_size();
+        FuseDriver {
+            journey_fs,
             block_size,
-            sector_size,
-        });
+        }
     }
 
-    fn get_fs_ref(&self) -> &JourneyFS {
-        self.journey_fs
-            .as_ref()
-            .expect("init should have been called")
+    // The lock is held until the guard is dropped, results that are matched on have to
+    // be bound first so that the match arms are free to call back into the driver.
+    fn fs(&self) -> MutexGuard<'_, JourneyFS> {
+        JourneyFS::lock(&self.journey_fs)
     }
 
-    fn get_mut_fs_ref(&mut self) -> &mut JourneyFS {
-        self.journey_fs
-            .as_mut()
-            .expect("init should have been called")
+    // Every entry reply bumps the kernel's lookup count for that inode, which it
+    // will later hand back through `forget`.
+    fn remember(&self, ino: u64) {
+        self.fs().remember(ino as InodeId);
     }
 
     fn inode_to_fileattr(&self, inode: Inode<Metadata>) -> FileAttr {
         FileAttr {
-            ino: inode.id.unwrap() as u64,
+            ino: inode.id.unwrap(),
             size: inode.size,
             blocks: inode.used_pointers as u64,
             atime: inode.meta.accessed_at,
             mtime: inode.meta.modified_at,
             ctime: inode.meta.changed_at,
             crtime: inode.meta.created_at,
-            kind: match inode.meta.inode_type {
-                InodeType::File => FileType::RegularFile,
-                InodeType::Directory => FileType::Directory,
-            },
+            kind: FuseDriver::inode_type_to_file_type(inode.meta.inode_type),
             perm: inode.meta.permissions,
             nlink: inode.meta.nlinks,
             uid: inode.meta.user_id,
             gid: inode.meta.group_id,
             rdev: inode.meta.rdev,
             flags: inode.meta.flags,
-            // If the block size is not know something is seriously wrong and we
-            // should panic
-            blksize: self.get_fs_ref().get_block_size().unwrap() as u32,
-        }
-    }
-
-    fn fileattr_to_metadata(&self, attr: FileAttr) -> Metadata {
-        Metadata {
-            inode_type: match attr.kind {
-                FileType::RegularFile => InodeType::File,
-                FileType::Directory => InodeType::Directory,
-                _ => panic!("Unsupported file type"),
-            },
-            created_at: attr.crtime,
-            modified_at: attr.mtime,
-            changed_at: attr.ctime,
-            accessed_at: attr.atime,
-            permissions: attr.perm,
-            nlinks: attr.nlink,
-            user_id: attr.uid,
-            group_id: attr.gid,
-            rdev: attr.rdev,
-            flags: attr.flags,
+            blksize: self.block_size as u32,
+        }
+    }
+
+    fn inode_type_to_file_type(inode_type: InodeType) -> FileType {
+        match inode_type {
+            InodeType::File => FileType::RegularFile,
+            InodeType::Directory => FileType::Directory,
+            InodeType::Symlink => FileType::Symlink,
+            InodeType::CharDevice => FileType::CharDevice,
+            InodeType::BlockDevice => FileType::BlockDevice,
+            InodeType::Fifo => FileType::NamedPipe,
+            InodeType::Socket => FileType::Socket,
+        }
+    }
+
+    fn mode_to_inode_type(mode: ModeBits) -> Option<InodeType> {
+        match mode & libc::S_IFMT {
+            libc::S_IFREG => Some(InodeType::File),
+            libc::S_IFDIR => Some(InodeType::Directory),
+            libc::S_IFLNK => Some(InodeType::Symlink),
+            libc::S_IFCHR => Some(InodeType::CharDevice),
+            libc::S_IFBLK => Some(InodeType::BlockDevice),
+            libc::S_IFIFO => Some(InodeType::Fifo),
+            libc::S_IFSOCK => Some(InodeType::Socket),
+            _ => None,
         }
     }
 
@@ -1 +1,3 @@
 mod filesystem;
+
+pub(crate) use filesystem::FuseDriver;
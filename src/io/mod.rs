@@ -1,22 +1,38 @@
 use crate::consts::BlockPointer;
 use crate::driver::DeviceDriver;
-use std::ops::Range;
+use crate::structure::journal::Journal;
+use crate::structure::layout::DataMode;
+use crate::util::checksum::is_sealed;
+use crate::util::error::{Error, Location};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 pub(crate) struct IO {
     pub drive: Box<dyn DeviceDriver>,
     pub block_size: usize,
     pub block_count: u64,
+    // while a transaction is open, block writes are collected here
+    journal: Option<Journal>,
+    // checksum mismatches seen since the device was opened
+    checksum_errors: AtomicU64,
+    // writes are refused with `EROFS`
+    read_only: bool,
+    // whether anything was written to the device since the last sync
+    dirty: bool,
 }
 
 impl IO {
     pub(crate) fn new<D: DeviceDriver + 'static>(drive: D, block_size: usize) -> IO {
         let block_count =
-            (drive.get_sector_size() as u64 * drive.get_sector_count() as u64) / block_size as u64;
+            (drive.get_sector_size() as u64 * drive.get_sector_count()) / block_size as u64;
 
         IO {
             drive: Box::new(drive),
             block_size,
             block_count,
+            journal: None,
+            checksum_errors: AtomicU64::new(0),
+            read_only: false,
+            dirty: false,
         }
     }
 
@@ -30,8 +46,7 @@ impl IO {
 
     pub(crate) fn set_block_size(&mut self, block_size: usize) {
         self.block_size = block_size;
-        self.block_count = (self.drive.get_sector_size() as u64
-            * self.drive.get_sector_count() as u64)
+        self.block_count = (self.drive.get_sector_size() as u64 * self.drive.get_sector_count())
             / block_size as u64;
     }
 
@@ -43,87 +58,228 @@ impl IO {
         self.drive.get_sector_count()
     }
 
-    pub(crate) fn write_block(&mut self, index: BlockPointer, block: &Vec<u8>) {
-        if block.len() != self.block_size {
-            panic!("Block size mismatch");
+    /// Flushes what was written to the device since the last sync, and marks everything
+    /// committed to the journal as applied.
+    pub(crate) fn sync(&mut self) -> Result<(), Error> {
+        if let Some(mut journal) = self.journal.take() {
+            let result = journal.checkpoint(self);
+            self.journal = Some(journal);
+            result?;
         }
-
-        if index >= self.block_count {
-            panic!("Block index out of range");
+        if self.dirty {
+            self.drive.sync()?;
+            self.dirty = false;
         }
+        Ok(())
+    }
 
-        if self.block_size == self.drive.get_sector_size() {
-            self.drive.write_sector(index, block);
-        } else {
-            let ratio = (self.block_size / self.drive.get_sector_size()) as u64;
-            let start = index * ratio;
-            let end = start + ratio;
+    pub(crate) fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
 
-            for i in start..end {
-                let offset = (i - start) as usize * self.drive.get_sector_size();
-                let limit = offset + self.drive.get_sector_size();
-                self.drive
-                    .write_sector(i, &block[(offset..limit) as Range<usize>].to_vec())
-            }
+    pub(crate) fn set_journal(&mut self, journal: Journal) {
+        self.journal = Some(journal);
+    }
+
+    pub(crate) fn journal(&self) -> Option<&Journal> {
+        self.journal.as_ref()
+    }
+
+    /// Opens a transaction. Block writes are held back until the outermost transaction
+    /// is committed, without a journal they go straight to the device.
+    pub(crate) fn begin(&mut self) {
+        if let Some(journal) = &mut self.journal {
+            journal.begin();
         }
     }
 
-    pub(crate) fn read_block(&self, index: BlockPointer) -> Vec<u8> {
-        if index >= self.block_count {
-            panic!("Block index out of range");
+    pub(crate) fn commit(&mut self) -> Result<(), Error> {
+        match self.journal.as_mut().map(|journal| journal.end()) {
+            Some(true) => self.flush_journal(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Forgets the writes of the open transaction. Without a journal they already
+    /// reached the device and stay there.
+    pub(crate) fn abort(&mut self) {
+        if let Some(journal) = &mut self.journal {
+            journal.abort();
         }
+    }
 
-        if self.block_size == self.drive.get_sector_size() {
-            self.drive.read_sector(index)
-        } else {
-            let ratio = (self.block_size / self.drive.get_sector_size()) as u64;
-            let mut buffer = Vec::new();
+    // Writes the open transaction through the journal, with the journal taken out so
+    // that its own writes reach the device.
+    fn flush_journal(&mut self) -> Result<(), Error> {
+        let mut journal = self.journal.take().unwrap();
+        let result = journal.commit(self);
+        self.journal = Some(journal);
+        result
+    }
 
-            let start = index * ratio;
-            let end = start + (self.block_size / self.drive.get_sector_size()) as u64;
+    pub(crate) fn write_block(&mut self, index: BlockPointer, block: &[u8]) -> Result<(), Error> {
+        self.check_write(index, block)?;
+        if let Some(journal) = &mut self.journal {
+            if journal.is_open() {
+                return journal.stage(index, block, self.block_size);
+            }
+        }
+        self.write_to_device(index, block)
+    }
 
-            if self.block_size >= self.drive.get_sector_size() {
-                for i in start..end {
-                    buffer.append(&mut self.drive.read_sector(i));
+    /// Writes file contents, which only go through the journal in `DataMode::Journal`.
+    /// In ordered mode they reach the device right away, ahead of the commit.
+    pub(crate) fn write_data_block(
+        &mut self,
+        index: BlockPointer,
+        block: &[u8],
+    ) -> Result<(), Error> {
+        self.check_write(index, block)?;
+        if let Some(journal) = &mut self.journal {
+            if journal.is_open() {
+                journal.stage_data(index, block, self.block_size)?;
+                if journal.data_mode != DataMode::Ordered {
+                    return Ok(());
                 }
             }
+        }
+        self.write_to_device(index, block)
+    }
+
+    fn write_to_device(&mut self, index: BlockPointer, block: &[u8]) -> Result<(), Error> {
+        if self
+            .journal
+            .as_ref()
+            .is_some_and(|journal| journal.is_committed(index))
+        {
+            self.sync()?;
+        }
+        let sector_size = self.drive.get_sector_size();
+        let ratio = (self.block_size / sector_size) as u64;
+        self.dirty = true;
+        for (i, sector) in block.chunks(sector_size).enumerate() {
+            self.drive.write_sector(index * ratio + i as u64, sector)?;
+        }
+        Ok(())
+    }
 
-            buffer
+    pub(crate) fn read_block(&self, index: BlockPointer) -> Result<Vec<u8>, Error> {
+        self.check_index(index)?;
+        if let Some(block) = self
+            .journal
+            .as_ref()
+            .and_then(|journal| journal.staged(index))
+        {
+            return Ok(block.clone());
         }
+        self.read_block_copy(index, 0)
+    }
+
+    /// Reads one particular copy of a block from a device that keeps several, see
+    /// `DeviceDriver::copies`. Staged writes are not taken into account.
+    pub(crate) fn read_block_copy(
+        &self,
+        index: BlockPointer,
+        copy: usize,
+    ) -> Result<Vec<u8>, Error> {
+        self.check_index(index)?;
+        let ratio = (self.block_size / self.drive.get_sector_size()) as u64;
+        let mut buffer = Vec::with_capacity(self.block_size);
+        for i in index * ratio..(index + 1) * ratio {
+            buffer.append(&mut self.drive.read_sector_copy(i, copy)?);
+        }
+        Ok(buffer)
+    }
+
+    pub(crate) fn copies(&self) -> usize {
+        self.drive.copies()
+    }
+
+    /// Checks a structure sealed with `checksum::seal`, counting mismatches.
+    pub(crate) fn verify(&self, bytes: &[u8], seed: u64, location: Location) -> Result<(), Error> {
+        if is_sealed(bytes, seed) {
+            return Ok(());
+        }
+        self.count_checksum_error();
+        Err(Error::corrupted("Checksum mismatch", location))
+    }
+
+    pub(crate) fn count_checksum_error(&self) {
+        self.checksum_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn checksum_errors(&self) -> u64 {
+        self.checksum_errors.load(Ordering::Relaxed)
+    }
+
+    fn check_write(&self, index: BlockPointer, block: &[u8]) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::new("Read-only file system", libc::EROFS));
+        }
+        if block.len() != self.block_size {
+            return Err(Error::new("Block size mismatch", libc::EINVAL));
+        }
+        self.check_index(index)
+    }
+
+    fn check_index(&self, index: BlockPointer) -> Result<(), Error> {
+        if index >= self.block_count {
+            return Err(Error::new(
+                &format!("Block index {} out of range", index),
+                libc::EIO,
+            ));
+        }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::driver::file_drive::FileDrive;
+    use crate::driver::memory_drive::MemoryDrive;
 
     #[test]
     fn read_write() {
-        let drive = FileDrive::new("./test-images/fsio_read_write.img", 1024 * 512, 1024);
+        let drive = MemoryDrive::new(1024 * 512, 1024);
         let mut io = super::IO::new(drive, 1024);
 
         let block = vec![42; 1024];
-        io.write_block(0, &block);
-        let read = io.read_block(0);
+        io.write_block(0, &block).unwrap();
+        let read = io.read_block(0).unwrap();
 
         assert_eq!(block, read);
     }
 
     #[test]
     fn read_write_large_block() {
-        let drive = FileDrive::new("./test-images/fsio_large_block.img", 1024 * 512, 512);
+        let drive = MemoryDrive::new(1024 * 512, 512);
         let mut io = super::IO::new(drive, 1024);
 
         let block1 = vec![0x42; 1024];
-        io.write_block(3, &block1);
-        assert_eq!(io.read_block(3), block1);
+        io.write_block(3, &block1).unwrap();
+        assert_eq!(io.read_block(3).unwrap(), block1);
 
         let block2 = vec![0x1; 1024];
-        io.write_block(4, &block2);
-        assert_eq!(io.read_block(4), block2);
+        io.write_block(4, &block2).unwrap();
+        assert_eq!(io.read_block(4).unwrap(), block2);
 
         let block3 = vec![0x8; 1024];
-        io.write_block(3, &block3);
-        assert_eq!(io.read_block(3), block3);
+        io.write_block(3, &block3).unwrap();
+        assert_eq!(io.read_block(3).unwrap(), block3);
+    }
+
+    #[test]
+    fn out_of_range() {
+        let drive = MemoryDrive::new(1024 * 512, 512);
+        let mut io = super::IO::new(drive, 1024);
+
+        assert_eq!(io.read_block(512).err().unwrap().errno(), libc::EIO);
+        assert_eq!(
+            io.write_block(512, &vec![0; 1024]).err().unwrap().errno(),
+            libc::EIO
+        );
+        assert_eq!(
+            io.write_block(0, &vec![0; 512]).err().unwrap().errno(),
+            libc::EINVAL
+        );
     }
 }
@@ -1,11 +1,20 @@
 extern crate core;
 
+mod cli;
 mod consts;
 mod driver;
 mod fuse;
 mod io;
 mod ops;
 mod structure;
+#[cfg(test)]
+mod testing;
 mod util;
 
-fn main() {}
+fn main() {
+    let args = std::env::args().skip(1).collect();
+    if let Err(error) = cli::run(args) {
+        eprintln!("jfs: {}", error);
+        std::process::exit(1);
+    }
+}
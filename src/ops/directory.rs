@@ -1,17 +1,20 @@
 use crate::consts::FILE_NAME_LENGTH;
 use crate::ops::file::File;
 use crate::ops::meta::{GroupId, InodeType, Metadata, UserId};
+use crate::ops::symlink::Symlink;
 use crate::structure::inode::{Inode, InodeId};
 use crate::structure::Structure;
+use crate::util::checksum::{seal, CHECKSUM_SIZE};
+use crate::util::error::{Error, Location};
 use crate::util::serializable::ByteSerializable;
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
 use std::mem::size_of;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Entry {
     // TODO: use something better for this, or at least find a safe way to decode it
-    name: OsString,
-    id: InodeId,
+    pub(crate) name: OsString,
+    pub(crate) id: InodeId,
 }
 
 pub type EntryList = Vec<Entry>;
@@ -28,23 +31,28 @@ impl ByteSerializable for EntryList {
         bytes
     }
 
-    fn from_bytes(bytes: &[u8]) -> Self {
+    fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let corrupted = || Error::new("Corrupted directory entry", libc::EUCLEAN);
         let mut entries = Vec::<Entry>::new();
         let mut data = bytes;
-        unsafe {
-            while data.len() > 0 {
-                let (id_bytes, remainder) = data.split_at(size_of::<InodeId>());
-                let (name_length_bytes, remainder) = remainder.split_at(size_of::<u8>());
-                let (name_bytes, remainder) = remainder.split_at(name_length_bytes[0] as usize);
-
-                let id = InodeId::from_le_bytes(id_bytes.try_into().unwrap());
-                let name = OsString::from_encoded_bytes_unchecked(name_bytes.to_vec());
-                entries.push(Entry { name, id });
-
-                data = remainder;
-            }
+        while !data.is_empty() {
+            let (id_bytes, remainder) = data
+                .split_at_checked(size_of::<InodeId>())
+                .ok_or_else(corrupted)?;
+            let (name_length_bytes, remainder) = remainder
+                .split_at_checked(size_of::<u8>())
+                .ok_or_else(corrupted)?;
+            let (name_bytes, remainder) = remainder
+                .split_at_checked(name_length_bytes[0] as usize)
+                .ok_or_else(corrupted)?;
+
+            let id = InodeId::from_le_bytes(id_bytes.try_into().unwrap());
+            let name = unsafe { OsString::from_encoded_bytes_unchecked(name_bytes.to_vec()) };
+            entries.push(Entry { name, id });
+
+            data = remainder;
         }
-        entries
+        Ok(entries)
     }
 }
 
@@ -53,37 +61,127 @@ pub struct Directory {
 }
 
 impl Directory {
+    /// Creates an empty directory containing only `.` and `..`. Without a parent the
+    /// directory is its own parent, which is how the root is set up.
     pub fn new(
         structure: &mut Structure<Metadata>,
+        parent: Option<InodeId>,
         user_id: UserId,
         group_id: GroupId,
         permissions: u16,
-    ) -> Directory {
+    ) -> Result<Directory, Error> {
         let meta = Metadata::new(InodeType::Directory, user_id, group_id, permissions, 2, 0);
-        let inode = structure.create_inode(meta);
-        Directory { inode }
+        let inode = structure.create_inode(meta)?;
+        let id = inode.id.unwrap();
+        let mut directory = Directory { inode };
+        directory.add_entry(structure, &OsString::from("."), id)?;
+        directory.add_entry(structure, &OsString::from(".."), parent.unwrap_or(id))?;
+        Ok(directory)
     }
 
     pub fn from_inode(inode: Inode<Metadata>) -> Directory {
         Directory { inode }
     }
 
-    pub fn get_entries(&self, structure: &Structure<Metadata>) -> EntryList {
-        let data = self.inode.get_data(structure);
-        EntryList::from_bytes(&data)
+    pub fn get_entries(&self, structure: &Structure<Metadata>) -> Result<EntryList, Error> {
+        let id = self.inode.id.unwrap_or(0);
+        let data = self.inode.get_data(structure)?;
+        let mut bytes = Vec::with_capacity(data.len());
+        for block in data.chunks(structure.get_block_size()) {
+            structure.verify(block, id, Location::Inode(id))?;
+            bytes.extend_from_slice(&block[..block.len() - CHECKSUM_SIZE]);
+        }
+        EntryList::from_bytes(&bytes).map_err(|error| error.at(Location::Inode(id)))
+    }
+
+    // Every block of a directory ends in a checksum, the last one may be cut short.
+    fn set_entries(
+        &mut self,
+        structure: &mut Structure<Metadata>,
+        entries: &EntryList,
+    ) -> Result<(), Error> {
+        let id = self.inode.id.unwrap_or(0);
+        let bytes = entries.to_bytes();
+        let mut data = Vec::with_capacity(bytes.len());
+        for chunk in bytes.chunks(structure.get_block_size() - CHECKSUM_SIZE) {
+            let start = data.len();
+            data.extend_from_slice(chunk);
+            data.resize(data.len() + CHECKSUM_SIZE, 0);
+            seal(&mut data[start..], id);
+        }
+        self.inode.set_data(structure, data)?;
+        structure.write_inode(&mut self.inode)
+    }
+
+    pub fn find_entry(
+        &self,
+        structure: &Structure<Metadata>,
+        name: &OsStr,
+    ) -> Result<Option<InodeId>, Error> {
+        Ok(self
+            .get_entries(structure)?
+            .into_iter()
+            .find(|entry| entry.name == name)
+            .map(|entry| entry.id))
+    }
+
+    /// A directory is empty when it holds nothing but `.` and `..`.
+    pub fn is_empty(&self, structure: &Structure<Metadata>) -> Result<bool, Error> {
+        Ok(self
+            .get_entries(structure)?
+            .iter()
+            .all(|entry| entry.name == "." || entry.name == ".."))
     }
 
-    fn add_entry(&mut self, structure: &mut Structure<Metadata>, name: &OsString, id: InodeId) {
+    pub(crate) fn add_entry(
+        &mut self,
+        structure: &mut Structure<Metadata>,
+        name: &OsString,
+        id: InodeId,
+    ) -> Result<(), Error> {
         if name.len() > FILE_NAME_LENGTH {
-            panic!("Name too long");
+            return Err(Error::new("Name too long", libc::ENAMETOOLONG));
         }
 
-        let mut entries = self.get_entries(structure);
+        let mut entries = self.get_entries(structure)?;
         entries.push(Entry {
             name: name.clone(),
             id,
         });
-        self.inode.set_data(structure, entries.to_bytes());
+        self.set_entries(structure, &entries)
+    }
+
+    /// Removes the entry called `name` and returns the inode it pointed to.
+    pub fn remove_entry(
+        &mut self,
+        structure: &mut Structure<Metadata>,
+        name: &OsStr,
+    ) -> Result<Option<InodeId>, Error> {
+        let mut entries = self.get_entries(structure)?;
+        let Some(position) = entries.iter().position(|entry| entry.name == name) else {
+            return Ok(None);
+        };
+        let entry = entries.remove(position);
+        self.set_entries(structure, &entries)?;
+        Ok(Some(entry.id))
+    }
+
+    /// Points the existing entry called `name` at `id` and returns the inode it pointed
+    /// to before.
+    pub fn set_entry(
+        &mut self,
+        structure: &mut Structure<Metadata>,
+        name: &OsStr,
+        id: InodeId,
+    ) -> Result<Option<InodeId>, Error> {
+        let mut entries = self.get_entries(structure)?;
+        let Some(entry) = entries.iter_mut().find(|entry| entry.name == name) else {
+            return Ok(None);
+        };
+        let previous = entry.id;
+        entry.id = id;
+        self.set_entries(structure, &entries)?;
+        Ok(Some(previous))
     }
 
     pub fn add_directory(
@@ -93,10 +191,12 @@ impl Directory {
         user_id: UserId,
         group_id: GroupId,
         permissions: u16,
-    ) -> Directory {
-        let directory = Directory::new(structure, user_id, group_id, permissions);
-        self.add_entry(structure, name, directory.inode.id.unwrap());
-        directory
+    ) -> Result<Directory, Error> {
+        let directory = Directory::new(structure, self.inode.id, user_id, group_id, permissions)?;
+        // the new directory's `..` links back to this one
+        self.inode.meta.nlinks += 1;
+        self.add_entry(structure, name, directory.inode.id.unwrap())?;
+        Ok(directory)
     }
 
     pub fn add_file(
@@ -106,18 +206,42 @@ impl Directory {
         user_id: UserId,
         group_id: GroupId,
         permissions: u16,
-    ) -> File {
-        let file = File::new(structure, user_id, group_id, permissions);
-        self.add_entry(structure, name, file.inode.id.unwrap());
-        file
+    ) -> Result<File, Error> {
+        let file = File::new(structure, user_id, group_id, permissions)?;
+        self.add_entry(structure, name, file.inode.id.unwrap())?;
+        Ok(file)
+    }
+
+    /// Adds a special file described by `meta`: a device node, FIFO or socket.
+    pub fn add_node(
+        &mut self,
+        structure: &mut Structure<Metadata>,
+        name: &OsString,
+        meta: Metadata,
+    ) -> Result<Inode<Metadata>, Error> {
+        let inode = structure.create_inode(meta)?;
+        self.add_entry(structure, name, inode.id.unwrap())?;
+        Ok(inode)
+    }
+
+    pub fn add_symlink(
+        &mut self,
+        structure: &mut Structure<Metadata>,
+        name: &OsString,
+        target: &OsStr,
+        user_id: UserId,
+        group_id: GroupId,
+    ) -> Result<Symlink, Error> {
+        let symlink = Symlink::new(structure, target, user_id, group_id)?;
+        self.add_entry(structure, name, symlink.inode.id.unwrap())?;
+        Ok(symlink)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::driver::file_drive::FileDrive;
-    use crate::io::IO;
+    use crate::testing::{create_structure, memory_drive};
 
     #[test]
     fn test_entry_list_to_bytes() {
@@ -152,33 +276,100 @@ mod tests {
             },
         ];
         let bytes = entries.to_bytes();
-        assert_eq!(entries, EntryList::from_bytes(&bytes));
+        assert_eq!(entries, EntryList::from_bytes(&bytes).unwrap());
+    }
+
+    #[test]
+    fn test_entry_list_from_corrupted_bytes() {
+        let mut bytes = vec![Entry {
+            name: OsString::from("file1"),
+            id: 1,
+        }]
+        .to_bytes();
+        bytes.pop();
+        let error = EntryList::from_bytes(&bytes).err().unwrap();
+        assert_eq!(error.errno(), libc::EUCLEAN);
     }
 
     #[test]
     fn test_directory_new() {
-        let drive = FileDrive::new("./test-images/test_directory_new.img", 2048 * 1024 * 5, 512);
-        let io = IO::new(drive, 512);
-        let mut structure = Structure::<Metadata>::new(io, 512);
-        let directory = Directory::new(&mut structure, 0, 0, 0o755);
-        let entries = directory.get_entries(&structure);
-        assert_eq!(entries.len(), 0);
+        let mut structure = create_structure::<Metadata>(memory_drive(), 512);
+        let directory = Directory::new(&mut structure, None, 0, 0, 0o755).unwrap();
+        let entries = directory.get_entries(&structure).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].name, ".");
+        assert_eq!(entries[0].id, directory.inode.id.unwrap());
+        assert_eq!(entries[1].name, "..");
+        assert_eq!(entries[1].id, directory.inode.id.unwrap());
     }
 
     #[test]
     fn test_directory_add_entry() {
-        let drive = FileDrive::new(
-            "./test-images/test_directory_add_entry.img",
-            2048 * 1024 * 5,
-            512,
+        let mut structure = create_structure::<Metadata>(memory_drive(), 1024);
+        let mut directory = Directory::new(&mut structure, None, 0, 0, 0o755).unwrap();
+        directory
+            .add_entry(&mut structure, &OsString::from("file1"), 1)
+            .unwrap();
+        let entries = directory.get_entries(&structure).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[2].name, "file1");
+        assert_eq!(entries[2].id, 1);
+
+        let long = OsString::from("a".repeat(FILE_NAME_LENGTH + 1));
+        let error = directory.add_entry(&mut structure, &long, 1).unwrap_err();
+        assert_eq!(error.errno(), libc::ENAMETOOLONG);
+    }
+
+    #[test]
+    fn test_directory_find_entry() {
+        let mut structure = create_structure::<Metadata>(memory_drive(), 1024);
+        let mut directory = Directory::new(&mut structure, None, 0, 0, 0o755).unwrap();
+        let child = directory
+            .add_directory(&mut structure, &OsString::from("child"), 0, 0, 0o755)
+            .unwrap();
+        assert_eq!(
+            directory
+                .find_entry(&structure, OsStr::new("child"))
+                .unwrap(),
+            child.inode.id
+        );
+        assert_eq!(
+            child.find_entry(&structure, OsStr::new("..")).unwrap(),
+            directory.inode.id
+        );
+        assert_eq!(
+            directory
+                .find_entry(&structure, OsStr::new("missing"))
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_directory_remove_entry() {
+        let mut structure = create_structure::<Metadata>(memory_drive(), 1024);
+        let mut directory = Directory::new(&mut structure, None, 0, 0, 0o755).unwrap();
+        let file = directory
+            .add_file(&mut structure, &OsString::from("file"), 0, 0, 0o644)
+            .unwrap();
+        assert!(!directory.is_empty(&structure).unwrap());
+
+        let removed = directory
+            .remove_entry(&mut structure, OsStr::new("file"))
+            .unwrap();
+        assert_eq!(removed, file.inode.id);
+        assert_eq!(
+            directory
+                .find_entry(&structure, OsStr::new("file"))
+                .unwrap(),
+            None
+        );
+        assert!(directory.is_empty(&structure).unwrap());
+        assert_eq!(
+            directory
+                .remove_entry(&mut structure, OsStr::new("file"))
+                .unwrap(),
+            None
         );
-        let io = IO::new(drive, 1024);
-        let mut structure = Structure::<Metadata>::new(io, 1024);
-        let mut directory = Directory::new(&mut structure, 0, 0, 0o755);
-        directory.add_entry(&mut structure, &OsString::from("file1"), 1);
-        let entries = directory.get_entries(&structure);
-        assert_eq!(entries.len(), 1);
-        assert_eq!(entries[0].name, "file1");
-        assert_eq!(entries[0].id, 1);
     }
 }
@@ -1,18 +1,20 @@
 use crate::ops::meta::{GroupId, InodeType, Metadata, UserId};
 use crate::structure::inode::{Inode};
 use crate::structure::Structure;
+use crate::util::error::Error;
+use std::time::SystemTime;
 
 pub struct File {
     pub inode: Inode<Metadata>,
 }
 
 impl File {
-    pub fn new(structure: &mut Structure<Metadata>, user_id: UserId, group_id: GroupId, permissions: u16) -> File {
+    pub fn new(structure: &mut Structure<Metadata>, user_id: UserId, group_id: GroupId, permissions: u16) -> Result<File, Error> {
         let meta = Metadata::new(InodeType::File, user_id, group_id, permissions, 1, 0);
-        let inode = structure.create_inode(meta);
-        File {
+        let inode = structure.create_inode(meta)?;
+        Ok(File {
             inode,
-        }
+        })
     }
 
     pub fn from_inode(inode: Inode<Metadata>) -> File {
@@ -21,11 +23,68 @@ impl File {
         }
     }
 
-    pub fn set_data(&mut self, structure: &mut Structure<Metadata>, data: Vec<u8>) {
-        self.inode.set_data(structure, data);
+    /// Reads up to `length` bytes starting at `offset`. Reading past the end of the file
+    /// returns fewer bytes, or none at all.
+    pub fn read_at(&self, structure: &Structure<Metadata>, offset: u64, length: usize) -> Result<Vec<u8>, Error> {
+        self.inode.read_at(structure, offset, length)
+    }
+
+    /// Writes `bytes` at `offset`, growing the file if needed. A gap between the old end
+    /// of the file and `offset` is filled with zeros.
+    pub fn write_at(&mut self, structure: &mut Structure<Metadata>, offset: u64, bytes: &[u8]) -> Result<(), Error> {
+        self.inode.write_at(structure, offset, bytes)?;
+        self.touch(structure)
+    }
+
+    /// Writes `bytes` at the end of the file.
+    pub fn append(&mut self, structure: &mut Structure<Metadata>, bytes: &[u8]) -> Result<(), Error> {
+        self.inode.append_data(structure, bytes)?;
+        self.touch(structure)
+    }
+
+    pub fn truncate(&mut self, structure: &mut Structure<Metadata>, size: u64) -> Result<(), Error> {
+        self.inode.truncate(structure, size)?;
+        self.touch(structure)
+    }
+
+    fn touch(&mut self, structure: &mut Structure<Metadata>) -> Result<(), Error> {
+        let now = SystemTime::now();
+        self.inode.meta.modified_at = now;
+        self.inode.meta.changed_at = now;
+        structure.write_inode(&mut self.inode)
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{create_structure, memory_drive};
+
+    #[test]
+    fn test_file_write_at() {
+        let mut structure = create_structure::<Metadata>(memory_drive(), 1024);
+        let mut file = File::new(&mut structure, 0, 0, 0o644).unwrap();
+
+        file.write_at(&mut structure, 0, b"hello world").unwrap();
+        file.write_at(&mut structure, 6, b"there").unwrap();
+        assert_eq!(file.inode.get_data(&structure).unwrap(), b"hello there");
+
+        file.write_at(&mut structure, 13, b"!").unwrap();
+        assert_eq!(file.inode.get_data(&structure).unwrap(), b"hello there\0\0!");
+    }
+
+    #[test]
+    fn test_file_read_at() {
+        let mut structure = create_structure::<Metadata>(memory_drive(), 1024);
+        let mut file = File::new(&mut structure, 0, 0, 0o644).unwrap();
+        file.write_at(&mut structure, 0, b"hello world").unwrap();
+
+        assert_eq!(file.read_at(&structure, 6, 100).unwrap(), b"world");
+        assert_eq!(file.read_at(&structure, 0, 5).unwrap(), b"hello");
+        assert_eq!(file.read_at(&structure, 11, 5).unwrap(), b"");
+        assert_eq!(file.read_at(&structure, 50, 5).unwrap(), b"");
 
-    pub fn get_data(&self, structure: &Structure<Metadata>) -> Vec<u8> {
-        self.inode.get_data(structure)
+        file.truncate(&mut structure, 5).unwrap();
+        assert_eq!(file.read_at(&structure, 0, 100).unwrap(), b"hello");
     }
 }
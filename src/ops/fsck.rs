@@ -0,0 +1,671 @@
+use crate::consts::BlockPointer;
+use crate::ops::directory::Directory;
+use crate::ops::meta::InodeType;
+use crate::ops::JourneyFS;
+use crate::structure::inode::{BlockMapping, BlockUse, InodeId};
+use crate::util::error::Error;
+use std::collections::hash_map;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::fmt::{Display, Formatter};
+
+const LOST_FOUND: &str = "lost+found";
+// every pass fixes what the previous one uncovered, a handful is always enough
+const MAX_REPAIR_PASSES: usize = 8;
+
+/// An inconsistency between the block map, the inode table, the inodes' block maps and
+/// the directory tree.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Problem {
+    /// The inode refers to a block outside of the data area.
+    InvalidBlock { inode: InodeId, block: BlockPointer },
+    /// Two inodes refer to the same block, the one with the lower id keeps it.
+    DuplicateBlock {
+        block: BlockPointer,
+        owner: InodeId,
+        inode: InodeId,
+    },
+    /// The block is marked used but nothing refers to it.
+    LeakedBlock(BlockPointer),
+    /// The block is in use but marked free.
+    UnmarkedBlock(BlockPointer),
+    /// The inode's data ends before its size does.
+    SizeExceedsAllocation {
+        inode: InodeId,
+        size: u64,
+        allocated_size: u64,
+    },
+    /// The inode maps data blocks past its size.
+    ExcessBlocks { inode: InodeId, count: u64 },
+    /// A directory entry refers to an inode that is not in use.
+    DanglingEntry {
+        directory: InodeId,
+        name: OsString,
+        inode: InodeId,
+    },
+    /// The inode is in use but cannot be reached from the root directory.
+    OrphanedInode(InodeId),
+    /// The inode's link count does not match the entries referring to it.
+    WrongLinkCount {
+        inode: InodeId,
+        stored: u32,
+        actual: u32,
+    },
+    /// The superblock's count of free blocks does not match the block map.
+    WrongFreeBlockCount { stored: u64, actual: u64 },
+    /// The superblock's count of free inodes does not match the inode table.
+    WrongFreeInodeCount { stored: u64, actual: u64 },
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::InvalidBlock { inode, block } => {
+                write!(f, "Inode {} refers to invalid block {}", inode, block)
+            }
+            Problem::DuplicateBlock {
+                block,
+                owner,
+                inode,
+            } => write!(
+                f,
+                "Block {} is claimed by inode {} and inode {}",
+                block, owner, inode
+            ),
+            Problem::LeakedBlock(block) => {
+                write!(f, "Block {} is marked used but not referenced", block)
+            }
+            Problem::UnmarkedBlock(block) => {
+                write!(f, "Block {} is referenced but marked free", block)
+            }
+            Problem::SizeExceedsAllocation {
+                inode,
+                size,
+                allocated_size,
+            } => write!(
+                f,
+                "Inode {} has size {} but only {} bytes allocated",
+                inode, size, allocated_size
+            ),
+            Problem::ExcessBlocks { inode, count } => {
+                write!(f, "Inode {} maps {} blocks past its size", inode, count)
+            }
+            Problem::DanglingEntry {
+                directory,
+                name,
+                inode,
+            } => write!(
+                f,
+                "Entry {:?} in directory {} refers to unused inode {}",
+                name, directory, inode
+            ),
+            Problem::OrphanedInode(inode) => {
+                write!(f, "Inode {} is not referenced by any directory", inode)
+            }
+            Problem::WrongLinkCount {
+                inode,
+                stored,
+                actual,
+            } => write!(
+                f,
+                "Inode {} has a link count of {} but {} links were found",
+                inode, stored, actual
+            ),
+            Problem::WrongFreeBlockCount { stored, actual } => write!(
+                f,
+                "The superblock counts {} free blocks but {} are free",
+                stored, actual
+            ),
+            Problem::WrongFreeInodeCount { stored, actual } => write!(
+                f,
+                "The superblock counts {} free inodes but {} are free",
+                stored, actual
+            ),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Scan {
+    problems: Vec<Problem>,
+    // the data blocks damaged inodes keep once they are repaired
+    remaps: BTreeMap<InodeId, Vec<BlockPointer>>,
+}
+
+impl JourneyFS {
+    /// Checks the whole filesystem for inconsistencies without changing anything.
+    pub fn check(&self) -> Result<Vec<Problem>, Error> {
+        Ok(self.scan()?.problems)
+    }
+
+    /// Checks the filesystem and repairs everything that was found: bitmaps are
+    /// rebuilt, damaged block maps are cut short, dangling entries are removed and
+    /// orphaned inodes are moved to `lost+found`. Returns the problems found.
+    pub fn repair(&mut self) -> Result<Vec<Problem>, Error> {
+        let mut scan = self.scan()?;
+        let found = scan.problems.clone();
+        for _ in 0..MAX_REPAIR_PASSES {
+            if scan.problems.is_empty() {
+                return Ok(found);
+            }
+            self.transaction(|fs| fs.fix(&scan))?;
+            scan = self.scan()?;
+        }
+
+        if scan.problems.is_empty() {
+            Ok(found)
+        } else {
+            Err(Error::new(
+                "Filesystem could not be repaired",
+                libc::EUCLEAN,
+            ))
+        }
+    }
+
+    fn scan(&self) -> Result<Scan, Error> {
+        let mut scan = Scan::default();
+        let owners = self.scan_blocks(&mut scan)?;
+        self.scan_tree(&mut scan)?;
+
+        // the superblock, its backups, block map and inode table are always in use
+        for block in 0..self.structure.block_count() {
+            let used = self.structure.is_reserved_block(block) || owners.contains_key(&block);
+            match (used, self.structure.is_block_free(block)) {
+                (true, true) => scan.problems.push(Problem::UnmarkedBlock(block)),
+                (false, false) => scan.problems.push(Problem::LeakedBlock(block)),
+                _ => {}
+            }
+        }
+
+        let usage = self.structure.usage();
+        let (stored_blocks, stored_inodes) = self.structure.stored_free_counts();
+        if stored_blocks != usage.free_blocks {
+            scan.problems.push(Problem::WrongFreeBlockCount {
+                stored: stored_blocks,
+                actual: usage.free_blocks,
+            });
+        }
+        if stored_inodes != usage.free_inodes {
+            scan.problems.push(Problem::WrongFreeInodeCount {
+                stored: stored_inodes,
+                actual: usage.free_inodes,
+            });
+        }
+        Ok(scan)
+    }
+
+    // Walks the block map of every inode in use and returns the owner of each block.
+    fn scan_blocks(&self, scan: &mut Scan) -> Result<HashMap<BlockPointer, InodeId>, Error> {
+        let structure = &self.structure;
+        let block_size = structure.get_block_size() as u64;
+        let block_count = structure.block_count();
+        let mut owners = HashMap::<BlockPointer, InodeId>::new();
+
+        // inode 0 is reserved and never holds anything
+        for id in 1..structure.inode_count() {
+            if structure.is_inode_free(id) {
+                continue;
+            }
+
+            let inode = structure.read_inode(id)?;
+            let mut problems = Vec::new();
+            // data blocks by index, with `None` for those that cannot be used
+            let mut data = Vec::<(u64, Option<BlockPointer>)>::new();
+            inode.walk_blocks(structure, &mut |usage| {
+                let (index, block) = match usage {
+                    BlockUse::Data(index, block) => (Some(index), block),
+                    BlockUse::Node(block) => (None, block),
+                };
+                let valid = if structure.is_reserved_block(block) || block >= block_count {
+                    problems.push(Problem::InvalidBlock { inode: id, block });
+                    false
+                } else {
+                    match owners.entry(block) {
+                        hash_map::Entry::Vacant(entry) => {
+                            entry.insert(id);
+                            true
+                        }
+                        hash_map::Entry::Occupied(entry) => {
+                            problems.push(Problem::DuplicateBlock {
+                                block,
+                                owner: *entry.get(),
+                                inode: id,
+                            });
+                            false
+                        }
+                    }
+                };
+                if let Some(index) = index {
+                    data.push((index, valid.then_some(block)));
+                }
+                valid
+            })?;
+
+            // the data is only usable up to the first missing or unusable block
+            data.sort_by_key(|(index, _)| *index);
+            let usable: Vec<BlockPointer> = data
+                .iter()
+                .enumerate()
+                .map_while(|(i, (index, block))| block.filter(|_| *index == i as u64))
+                .collect();
+            let needed = match inode.mapping {
+                BlockMapping::Inline(_) => 0,
+                _ => inode.size.div_ceil(block_size),
+            };
+            if (usable.len() as u64) < needed {
+                problems.push(Problem::SizeExceedsAllocation {
+                    inode: id,
+                    size: inode.size,
+                    allocated_size: usable.len() as u64 * block_size,
+                });
+            } else if data.len() as u64 > needed {
+                problems.push(Problem::ExcessBlocks {
+                    inode: id,
+                    count: data.len() as u64 - needed,
+                });
+            }
+
+            if !problems.is_empty() {
+                let keep = usize::min(usable.len(), needed as usize);
+                scan.remaps.insert(id, usable[..keep].to_vec());
+                scan.problems.append(&mut problems);
+            }
+        }
+        Ok(owners)
+    }
+
+    // Walks the directory tree from the root, looking for dangling entries, orphaned
+    // inodes and wrong link counts.
+    fn scan_tree(&self, scan: &mut Scan) -> Result<(), Error> {
+        let structure = &self.structure;
+        let root = structure.get_root_inode()?;
+        if root.meta.inode_type != InodeType::Directory
+            || scan.remaps.contains_key(&root.id.unwrap())
+        {
+            return Err(Error::new("Root directory is damaged", libc::EUCLEAN));
+        }
+
+        let root = root.id.unwrap();
+        let mut links = HashMap::<InodeId, u32>::new();
+        let mut reachable = HashSet::from([root]);
+        let mut queue = VecDeque::from([root]);
+        let mut complete = true;
+        while let Some(id) = queue.pop_front() {
+            // directories with damaged block maps cannot be read until they are repaired
+            if scan.remaps.contains_key(&id) {
+                complete = false;
+                continue;
+            }
+
+            let directory = self.read_directory(id)?;
+            for entry in directory.get_entries(structure)? {
+                if entry.id == 0
+                    || entry.id >= structure.inode_count()
+                    || structure.is_inode_free(entry.id)
+                {
+                    scan.problems.push(Problem::DanglingEntry {
+                        directory: id,
+                        name: entry.name,
+                        inode: entry.id,
+                    });
+                    continue;
+                }
+
+                *links.entry(entry.id).or_default() += 1;
+                if entry.name == "." || entry.name == ".." {
+                    continue;
+                }
+                if reachable.insert(entry.id)
+                    && structure.read_inode(entry.id)?.meta.inode_type == InodeType::Directory
+                {
+                    queue.push_back(entry.id);
+                }
+            }
+        }
+
+        // without all directories, orphans and link counts cannot be told apart from
+        // the entries that were skipped
+        if !complete {
+            return Ok(());
+        }
+
+        for id in 1..structure.inode_count() {
+            if structure.is_inode_free(id) {
+                continue;
+            }
+            if !reachable.contains(&id) {
+                scan.problems.push(Problem::OrphanedInode(id));
+                continue;
+            }
+
+            let stored = structure.read_inode(id)?.meta.nlinks;
+            let actual = links.get(&id).copied().unwrap_or(0);
+            if stored != actual {
+                scan.problems.push(Problem::WrongLinkCount {
+                    inode: id,
+                    stored,
+                    actual,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn fix(&mut self, scan: &Scan) -> Result<(), Error> {
+        let bitmap_damaged = scan
+            .problems
+            .iter()
+            .any(|problem| matches!(problem, Problem::LeakedBlock(_) | Problem::UnmarkedBlock(_)));
+        if bitmap_damaged || !scan.remaps.is_empty() {
+            // the block map has to be right before remapping allocates from it
+            for problem in &scan.problems {
+                match problem {
+                    Problem::LeakedBlock(block) => self.structure.free_block(*block)?,
+                    Problem::UnmarkedBlock(block) => self.structure.mark_block_used(*block)?,
+                    _ => {}
+                }
+            }
+            for (id, blocks) in &scan.remaps {
+                let mut inode = self.structure.read_inode(*id)?;
+                inode.remap(&mut self.structure, blocks)?;
+                self.structure.write_inode(&mut inode)?;
+            }
+            // the directory tree may not have been fully readable, so it is checked
+            // again on the next pass
+            return Ok(());
+        }
+
+        let mut orphans = Vec::new();
+        for problem in &scan.problems {
+            match problem {
+                Problem::DanglingEntry {
+                    directory, name, ..
+                } => {
+                    self.read_directory(*directory)?
+                        .remove_entry(&mut self.structure, name)?;
+                }
+                Problem::WrongLinkCount { inode, actual, .. } => {
+                    let mut inode = self.structure.read_inode(*inode)?;
+                    inode.meta.nlinks = *actual;
+                    self.structure.write_inode(&mut inode)?;
+                }
+                Problem::OrphanedInode(id) => orphans.push(*id),
+                // the counts in memory follow the bitmaps and only need to be written
+                Problem::WrongFreeBlockCount { .. } | Problem::WrongFreeInodeCount { .. } => {
+                    self.structure.write_super_block()?
+                }
+                _ => {}
+            }
+        }
+        self.reconnect(&orphans)
+    }
+
+    // Moves the orphans that are not held by another orphaned directory to lost+found.
+    // Orphans without any links left were deleted while still open and are freed.
+    fn reconnect(&mut self, orphans: &[InodeId]) -> Result<(), Error> {
+        let mut claimed = HashSet::new();
+        for id in orphans {
+            let inode = self.structure.read_inode(*id)?;
+            if inode.meta.inode_type != InodeType::Directory {
+                continue;
+            }
+            for entry in Directory::from_inode(inode).get_entries(&self.structure)? {
+                if entry.name != "." && entry.name != ".." {
+                    claimed.insert(entry.id);
+                }
+            }
+        }
+
+        let mut roots: Vec<InodeId> = orphans
+            .iter()
+            .copied()
+            .filter(|id| !claimed.contains(id))
+            .collect();
+        if roots.is_empty() {
+            // orphaned directories holding each other, break the cycle anywhere
+            roots.extend(orphans.first());
+        }
+
+        for id in roots {
+            let mut inode = self.structure.read_inode(id)?;
+            if inode.meta.nlinks == 0 {
+                self.structure.free_inode(&mut inode)?;
+                continue;
+            }
+
+            let lost_found = self.lost_found()?;
+            let mut directory = self.read_directory(lost_found)?;
+            let mut name = OsString::from(format!("#{}", id));
+            let mut suffix = 1;
+            while directory.find_entry(&self.structure, &name)?.is_some() {
+                name = OsString::from(format!("#{}.{}", id, suffix));
+                suffix += 1;
+            }
+            directory.add_entry(&mut self.structure, &name, id)?;
+
+            if inode.meta.inode_type == InodeType::Directory {
+                let mut orphan = Directory::from_inode(inode);
+                let parent = OsStr::new("..");
+                if orphan
+                    .set_entry(&mut self.structure, parent, lost_found)?
+                    .is_none()
+                {
+                    orphan.add_entry(&mut self.structure, &parent.to_os_string(), lost_found)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn lost_found(&mut self) -> Result<InodeId, Error> {
+        let root = Directory::from_inode(self.structure.get_root_inode()?);
+        let root_id = root.inode.id.unwrap();
+        match root.find_entry(&self.structure, OsStr::new(LOST_FOUND))? {
+            Some(id) => {
+                self.read_directory(id)?;
+                Ok(id)
+            }
+            None => {
+                let (user_id, group_id) = (root.inode.meta.user_id, root.inode.meta.group_id);
+                let directory = self.mkdir(
+                    root_id,
+                    &OsString::from(LOST_FOUND),
+                    user_id,
+                    group_id,
+                    0o700,
+                )?;
+                Ok(directory.inode.id.unwrap())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structure::superblock::{FEATURE_DATA_CHECKSUMS, FEATURE_EXTENTS};
+    use crate::testing::{create_fs, memory_drive};
+
+    fn populate(fs: &mut JourneyFS) -> (InodeId, InodeId) {
+        let root = fs.structure.get_root_inode().unwrap().id.unwrap();
+        let directory = fs.mkdir(root, &OsString::from("dir"), 0, 0, 0o755).unwrap();
+        let directory = directory.inode.id.unwrap();
+        let (file, handle) = fs
+            .create(
+                directory,
+                &OsString::from("file"),
+                0,
+                0,
+                0o644,
+                libc::O_RDWR,
+            )
+            .unwrap();
+        fs.write(handle, 0, &vec![7u8; 20 * 1024]).unwrap();
+        fs.release(handle).unwrap();
+        (directory, file.inode.id.unwrap())
+    }
+
+    #[test]
+    fn test_check_clean_filesystem() {
+        for features in [0, FEATURE_EXTENTS, FEATURE_DATA_CHECKSUMS] {
+            let mut fs = create_fs(memory_drive(), features);
+            populate(&mut fs);
+            assert_eq!(fs.check().unwrap(), vec![]);
+            assert_eq!(fs.repair().unwrap(), vec![]);
+        }
+    }
+
+    #[test]
+    fn test_repair_block_map() {
+        let mut fs = create_fs(memory_drive(), 0);
+        let (_, file) = populate(&mut fs);
+        let inode = fs.structure.read_inode(file).unwrap();
+        let used = inode.block_pointer(&fs.structure, 3).unwrap();
+        // the last block holds a backup of the superblock
+        let leaked = fs.structure.block_count() - 2;
+        fs.structure.free_block(used).unwrap();
+        fs.structure.mark_block_used(leaked).unwrap();
+
+        let problems = fs.check().unwrap();
+        assert_eq!(
+            problems,
+            vec![Problem::UnmarkedBlock(used), Problem::LeakedBlock(leaked)]
+        );
+        assert_eq!(fs.repair().unwrap(), problems);
+        assert_eq!(fs.check().unwrap(), vec![]);
+        assert!(!fs.structure.is_block_free(used));
+        assert!(fs.structure.is_block_free(leaked));
+    }
+
+    #[test]
+    fn test_repair_free_counts() {
+        let drive = memory_drive();
+        let mut fs = create_fs(drive.clone(), 0);
+        populate(&mut fs);
+        let usage = fs.usage();
+        fs.structure.super_block.free_blocks = 5;
+        fs.structure.super_block.free_inodes = 6;
+        fs.structure.write_super_block().unwrap();
+        drop(fs);
+
+        // mounting goes by the bitmaps but the stored counts are reported
+        let mut fs = JourneyFS::mount(drive.clone()).unwrap();
+        assert_eq!(fs.usage().free_blocks, usage.free_blocks);
+        assert_eq!(fs.usage().free_inodes, usage.free_inodes);
+        let problems = fs.check().unwrap();
+        assert_eq!(
+            problems,
+            vec![
+                Problem::WrongFreeBlockCount {
+                    stored: 5,
+                    actual: usage.free_blocks
+                },
+                Problem::WrongFreeInodeCount {
+                    stored: 6,
+                    actual: usage.free_inodes
+                },
+            ]
+        );
+        assert_eq!(fs.repair().unwrap(), problems);
+        drop(fs);
+
+        let fs = JourneyFS::mount(drive).unwrap();
+        assert_eq!(fs.check().unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_repair_damaged_block_map() {
+        let mut fs = create_fs(memory_drive(), 0);
+        let (directory, file) = populate(&mut fs);
+        let mut inode = fs.structure.read_inode(file).unwrap();
+        let shared = fs
+            .structure
+            .read_inode(directory)
+            .unwrap()
+            .block_pointer(&fs.structure, 0)
+            .unwrap();
+        if let BlockMapping::Pointers(pointers) = &mut inode.mapping {
+            pointers.direct[2] = shared;
+            pointers.direct[5] = fs.structure.block_count() + 10;
+        }
+        fs.structure.write_inode(&mut inode).unwrap();
+
+        let problems = fs.check().unwrap();
+        assert!(problems.contains(&Problem::DuplicateBlock {
+            block: shared,
+            owner: directory,
+            inode: file,
+        }));
+        assert!(problems.contains(&Problem::InvalidBlock {
+            inode: file,
+            block: fs.structure.block_count() + 10,
+        }));
+        assert!(problems.contains(&Problem::SizeExceedsAllocation {
+            inode: file,
+            size: 20 * 1024,
+            allocated_size: 2 * 1024,
+        }));
+
+        fs.repair().unwrap();
+        assert_eq!(fs.check().unwrap(), vec![]);
+        let inode = fs.structure.read_inode(file).unwrap();
+        assert_eq!(inode.size, 2 * 1024);
+        assert_eq!(fs.structure.read_inode(directory).unwrap().meta.nlinks, 2);
+    }
+
+    #[test]
+    fn test_repair_directory_tree() {
+        let mut fs = create_fs(memory_drive(), FEATURE_EXTENTS);
+        let (directory, file) = populate(&mut fs);
+        let root = fs.structure.get_root_inode().unwrap().id.unwrap();
+
+        // cut `dir` loose from the root and leave a dangling entry behind
+        let mut root_directory = fs.read_directory(root).unwrap();
+        root_directory
+            .set_entry(&mut fs.structure, OsStr::new("dir"), 4000)
+            .unwrap();
+        let mut inode = fs.structure.read_inode(file).unwrap();
+        inode.meta.nlinks = 5;
+        fs.structure.write_inode(&mut inode).unwrap();
+
+        let problems = fs.check().unwrap();
+        assert!(problems.contains(&Problem::DanglingEntry {
+            directory: root,
+            name: OsString::from("dir"),
+            inode: 4000,
+        }));
+        assert!(problems.contains(&Problem::OrphanedInode(directory)));
+        assert!(problems.contains(&Problem::OrphanedInode(file)));
+
+        fs.repair().unwrap();
+        assert_eq!(fs.check().unwrap(), vec![]);
+        let lost_found = fs.lookup(root, OsStr::new(LOST_FOUND)).unwrap();
+        let lost_found = lost_found.id.unwrap();
+        let moved = fs.lookup(lost_found, OsStr::new(&format!("#{}", directory)));
+        assert_eq!(moved.unwrap().id, Some(directory));
+        assert_eq!(
+            fs.lookup(directory, OsStr::new("..")).unwrap().id,
+            Some(lost_found)
+        );
+        assert_eq!(fs.structure.read_inode(file).unwrap().meta.nlinks, 1);
+        assert_eq!(
+            fs.lookup(root, OsStr::new("dir")).err().unwrap().errno(),
+            libc::ENOENT
+        );
+    }
+
+    #[test]
+    fn test_repair_frees_unlinked_orphans() {
+        let mut fs = create_fs(memory_drive(), 0);
+        let (directory, file) = populate(&mut fs);
+        let handle = fs.open(file, libc::O_RDONLY).unwrap();
+        fs.unlink(directory, OsStr::new("file")).unwrap();
+
+        // the handle is gone with the process, as after a crash
+        fs.open_files.remove(&handle);
+        assert_eq!(fs.check().unwrap(), vec![Problem::OrphanedInode(file)]);
+        fs.repair().unwrap();
+        assert_eq!(fs.check().unwrap(), vec![]);
+        assert!(fs.structure.is_inode_free(file));
+    }
+}
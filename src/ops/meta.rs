@@ -1,9 +1,16 @@
 use std::time::{Duration, SystemTime};
 use crate::util::serializable::{ByteSerializable, KnownSize};
+use crate::util::error::Error;
 
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum InodeType {
     File,
     Directory,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+    Fifo,
+    Socket,
 }
 
 pub type UserId = u32;
@@ -16,10 +23,13 @@ pub struct Metadata {
     pub changed_at: SystemTime,
     pub accessed_at: SystemTime,
     pub permissions: u16,
-    pub nlinks: u32, // what is this?
+    // number of directory entries referring to this inode; directories also count
+    // their own `.` and the `..` of every subdirectory
+    pub nlinks: u32,
     pub user_id: UserId,
     pub group_id: GroupId,
-    pub rdev: u32, // what is this?
+    // major and minor number of device nodes, in the kernel's encoding
+    pub rdev: u32,
     pub flags: u32,
 }
 
@@ -52,20 +62,23 @@ impl Metadata {
 
 impl ByteSerializable for SystemTime {
     fn to_bytes(&self) -> Vec<u8> {
-        let since_unix = self.duration_since(SystemTime::UNIX_EPOCH).unwrap();
+        // times before the epoch are clamped to it
+        let since_unix = self
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO);
         let mut result = Vec::<u8>::new();
         result.extend_from_slice(&since_unix.as_secs().to_le_bytes());
         result.extend_from_slice(&since_unix.subsec_nanos().to_le_bytes());
         result
     }
 
-    fn from_bytes(bytes: &[u8]) -> Self {
+    fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
         let (seconds, sub_nanos) = bytes.split_at(8);
 
-        SystemTime::UNIX_EPOCH + Duration::new(
+        SystemTime::UNIX_EPOCH.checked_add(Duration::new(
             u64::from_le_bytes(seconds.try_into().unwrap()),
             u32::from_le_bytes(sub_nanos.try_into().unwrap())
-        )
+        )).ok_or_else(|| Error::new("Invalid timestamp", libc::EUCLEAN))
     }
 }
 
@@ -74,14 +87,24 @@ impl ByteSerializable for InodeType {
         match self {
             InodeType::File => vec![0],
             InodeType::Directory => vec![1],
+            InodeType::Symlink => vec![2],
+            InodeType::CharDevice => vec![3],
+            InodeType::BlockDevice => vec![4],
+            InodeType::Fifo => vec![5],
+            InodeType::Socket => vec![6],
         }
     }
 
-    fn from_bytes(bytes: &[u8]) -> Self {
+    fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
         match bytes[0] {
-            0 => InodeType::File,
-            1 => InodeType::Directory,
-            _ => panic!("Invalid inode type"),
+            0 => Ok(InodeType::File),
+            1 => Ok(InodeType::Directory),
+            2 => Ok(InodeType::Symlink),
+            3 => Ok(InodeType::CharDevice),
+            4 => Ok(InodeType::BlockDevice),
+            5 => Ok(InodeType::Fifo),
+            6 => Ok(InodeType::Socket),
+            other => Err(Error::new(&format!("Invalid inode type {}", other), libc::EUCLEAN)),
         }
     }
 }
@@ -110,20 +133,20 @@ impl ByteSerializable for Metadata {
         result
     }
 
-    fn from_bytes(bytes: &[u8]) -> Self {
-        let inode_type = InodeType::from_bytes(&bytes[0..1]);
-        let created_at = SystemTime::from_bytes(&bytes[1..17]);
-        let modified_at = SystemTime::from_bytes(&bytes[17..33]);
-        let accessed_at = SystemTime::from_bytes(&bytes[33..49]);
-        let changed_at = SystemTime::from_bytes(&bytes[49..65]);
-        let permission = u16::from_le_bytes([bytes[65], bytes[66]]);
-        let nlinks = u32::from_le_bytes([bytes[67], bytes[68], bytes[69], bytes[70]]);
-        let user_id = u32::from_le_bytes([bytes[71], bytes[72], bytes[73], bytes[74]]);
-        let group_id = u32::from_le_bytes([bytes[75], bytes[76], bytes[77], bytes[78]]);
-        let rdev = u32::from_le_bytes([bytes[79], bytes[80], bytes[81], bytes[82]]);
-        let flags = u32::from_le_bytes([bytes[83], bytes[84], bytes[85], bytes[86]]);
+    fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let inode_type = InodeType::from_bytes(&bytes[0..1])?;
+        let created_at = SystemTime::from_bytes(&bytes[1..13])?;
+        let modified_at = SystemTime::from_bytes(&bytes[13..25])?;
+        let accessed_at = SystemTime::from_bytes(&bytes[25..37])?;
+        let changed_at = SystemTime::from_bytes(&bytes[37..49])?;
+        let permission = u16::from_le_bytes([bytes[49], bytes[50]]);
+        let nlinks = u32::from_le_bytes([bytes[51], bytes[52], bytes[53], bytes[54]]);
+        let user_id = u32::from_le_bytes([bytes[55], bytes[56], bytes[57], bytes[58]]);
+        let group_id = u32::from_le_bytes([bytes[59], bytes[60], bytes[61], bytes[62]]);
+        let rdev = u32::from_le_bytes([bytes[63], bytes[64], bytes[65], bytes[66]]);
+        let flags = u32::from_le_bytes([bytes[67], bytes[68], bytes[69], bytes[70]]);
 
-        Metadata {
+        Ok(Metadata {
             inode_type,
             created_at,
             modified_at,
@@ -135,6 +158,64 @@ impl ByteSerializable for Metadata {
             group_id,
             rdev,
             flags,
-        }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metadata_round_trip() {
+        let meta = Metadata::new(InodeType::Directory, 1000, 100, 0o755, 2, 0);
+        let bytes = meta.to_bytes();
+        assert_eq!(bytes.len(), Metadata::size_on_disk());
+
+        let decoded = Metadata::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.inode_type, InodeType::Directory);
+        assert_eq!(decoded.created_at, meta.created_at);
+        assert_eq!(decoded.changed_at, meta.changed_at);
+        assert_eq!(decoded.permissions, 0o755);
+        assert_eq!(decoded.nlinks, 2);
+        assert_eq!(decoded.user_id, 1000);
+        assert_eq!(decoded.group_id, 100);
+    }
+
+    #[test]
+    fn test_metadata_field_offsets() {
+        // timestamps take 12 bytes each, the seconds followed by the nanoseconds
+        let mut meta = Metadata::new(InodeType::File, 1000, 100, 0o640, 3, 4);
+        meta.created_at = SystemTime::UNIX_EPOCH + Duration::new(10, 1);
+        meta.modified_at = SystemTime::UNIX_EPOCH + Duration::new(20, 2);
+        meta.accessed_at = SystemTime::UNIX_EPOCH + Duration::new(30, 3);
+        meta.changed_at = SystemTime::UNIX_EPOCH + Duration::new(40, 4);
+        meta.rdev = 5;
+        let bytes = meta.to_bytes();
+        assert_eq!(bytes[1..13], [10u64.to_le_bytes().as_slice(), &1u32.to_le_bytes()].concat());
+        assert_eq!(bytes[37..49], [40u64.to_le_bytes().as_slice(), &4u32.to_le_bytes()].concat());
+        assert_eq!(bytes[49..51], 0o640u16.to_le_bytes());
+        assert_eq!(bytes[67..71], 4u32.to_le_bytes());
+
+        let decoded = Metadata::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.inode_type, InodeType::File);
+        assert_eq!(decoded.created_at, meta.created_at);
+        assert_eq!(decoded.modified_at, meta.modified_at);
+        assert_eq!(decoded.accessed_at, meta.accessed_at);
+        assert_eq!(decoded.changed_at, meta.changed_at);
+        assert_eq!(decoded.permissions, 0o640);
+        assert_eq!(decoded.nlinks, 3);
+        assert_eq!(decoded.user_id, 1000);
+        assert_eq!(decoded.group_id, 100);
+        assert_eq!(decoded.rdev, 5);
+        assert_eq!(decoded.flags, 4);
+    }
+
+    #[test]
+    fn test_metadata_invalid_type() {
+        let mut bytes = Metadata::new(InodeType::File, 0, 0, 0o644, 1, 0).to_bytes();
+        bytes[0] = 42;
+        let error = Metadata::from_bytes(&bytes).err().unwrap();
+        assert_eq!(error.errno(), libc::EUCLEAN);
     }
 }
@@ -1,44 +1,301 @@
+use crate::consts::FILE_NAME_LENGTH;
 use crate::driver::DeviceDriver;
 use crate::io::IO;
-use crate::ops::directory::Directory;
-use crate::ops::meta::{GroupId, Metadata, UserId};
+use crate::ops::directory::{Directory, Entry, EntryList};
+use crate::ops::file::File;
+use crate::ops::meta::{GroupId, InodeType, Metadata, UserId};
+use crate::ops::symlink::Symlink;
 use crate::structure::inode::{Inode, InodeId};
+use crate::structure::layout::{FormatOptions, Layout, MountOptions, Usage};
+use crate::structure::superblock::LABEL_LENGTH;
 use crate::structure::Structure;
 use crate::util::error::Error;
-use std::ffi::OsString;
+use std::collections::{hash_map, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::sync::{Mutex, MutexGuard};
+use std::time::SystemTime;
 
 mod directory;
 mod file;
+pub mod fsck;
 pub mod meta;
+pub mod scrub;
+mod symlink;
+
+pub type FileHandle = u64;
+
+struct OpenFile {
+    id: InodeId,
+    flags: i32,
+}
+
+// listings are snapshotted on opendir so offsets stay valid until releasedir
+struct OpenDirectory {
+    id: InodeId,
+    entries: EntryList,
+}
 
 pub struct JourneyFS {
     structure: Structure<Metadata>,
-    root: Directory,
+    next_handle: FileHandle,
+    open_directories: HashMap<FileHandle, OpenDirectory>,
+    open_files: HashMap<FileHandle, OpenFile>,
+    // references the kernel took by being handed an inode's attributes, see `remember`
+    lookups: HashMap<InodeId, u64>,
 }
 
 impl JourneyFS {
-    // TODO: "new" should probably not mount existing filesystems
-    pub fn new<D: DeviceDriver + 'static>(
+    /// Creates a new filesystem on `device`, with an empty root directory owned by
+    /// `user_id` and `group_id`. Devices that already hold a JourneyFS are only
+    /// overwritten if `force` is set.
+    pub fn format<D: DeviceDriver + 'static>(
         device: D,
+        options: &FormatOptions,
         user_id: UserId,
         group_id: GroupId,
-        block_size: usize,
+        force: bool,
     ) -> Result<JourneyFS, Error> {
-        let mut io = IO::new(device, block_size);
+        let sector_size = device.get_sector_size();
+        if !options.block_size.is_power_of_two() || options.block_size < sector_size {
+            return Err(Error::new(
+                "Block size must be a power of two and at least the sector size",
+                libc::EINVAL,
+            ));
+        }
+        if options.label.len() > LABEL_LENGTH {
+            return Err(Error::new(
+                &format!("Label cannot be longer than {} bytes", LABEL_LENGTH),
+                libc::EINVAL,
+            ));
+        }
+        if options.bytes_per_inode == 0 {
+            return Err(Error::new("Inode ratio must not be zero", libc::EINVAL));
+        }
+
+        let mut io = IO::new(device, sector_size);
+        if !force && Structure::<Metadata>::is_initialized(&mut io)? {
+            return Err(Error::new(
+                "Device already contains a filesystem",
+                libc::EEXIST,
+            ));
+        }
+        // the root directory needs one block on top of the filesystem structures
+        let block_count = io.get_block_count() * sector_size as u64 / options.block_size as u64;
+        if Structure::<Metadata>::reserved_blocks(block_count, options) >= block_count {
+            return Err(Error::new("Device is too small", libc::ENOSPC));
+        }
+
+        let mut structure = Structure::format(io, options)?;
+        let mut root = Directory::new(&mut structure, None, user_id, group_id, 0o755)?;
+        structure.set_root_inode(&mut root.inode)?;
+        Ok(JourneyFS::from_structure(structure))
+    }
+
+    /// Opens the filesystem on `device`, failing if it was never formatted.
+    #[cfg(test)]
+    pub fn mount<D: DeviceDriver + 'static>(device: D) -> Result<JourneyFS, Error> {
+        JourneyFS::mount_with(device, &MountOptions::default())
+    }
+
+    pub fn mount_with<D: DeviceDriver + 'static>(
+        device: D,
+        options: &MountOptions,
+    ) -> Result<JourneyFS, Error> {
+        // the superblock sits at the very start, so any block size finds it
+        let sector_size = device.get_sector_size();
+        let mut io = IO::new(device, sector_size);
+        if !Structure::<Metadata>::is_initialized(&mut io)? {
+            return Err(Error::new("No filesystem found", libc::EINVAL));
+        }
+        let structure = Structure::mount(io, options)?;
+        // fail early on a root directory that cannot be read
+        structure.get_root_inode()?;
+        Ok(JourneyFS::from_structure(structure))
+    }
+
+    /// Locks a filesystem shared between threads. If an operation panicked while
+    /// holding the lock, its open transaction is dropped before anyone continues.
+    pub fn lock(shared: &Mutex<JourneyFS>) -> MutexGuard<'_, JourneyFS> {
+        let mut journey_fs = match shared.lock() {
+            Ok(journey_fs) => return journey_fs,
+            Err(error) => error.into_inner(),
+        };
+        match journey_fs.structure.abort() {
+            Ok(()) => shared.clear_poison(),
+            // stays poisoned, so the next lock tries again
+            Err(error) => eprintln!("Failed to recover the filesystem: {}", error),
+        }
+        journey_fs
+    }
+
+    fn from_structure(structure: Structure<Metadata>) -> JourneyFS {
+        JourneyFS {
+            structure,
+            next_handle: 1,
+            open_directories: HashMap::new(),
+            open_files: HashMap::new(),
+            lookups: HashMap::new(),
+        }
+    }
+
+    fn allocate_handle(&mut self) -> FileHandle {
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        handle
+    }
+
+    fn read_directory(&self, id: InodeId) -> Result<Directory, Error> {
+        let inode = self.structure.read_inode(id)?;
+        if inode.meta.inode_type != InodeType::Directory {
+            return Err(Error::new("Not a directory", libc::ENOTDIR));
+        }
+        Ok(Directory::from_inode(inode))
+    }
+
+    fn read_file(&self, id: InodeId) -> Result<File, Error> {
+        let inode = self.structure.read_inode(id)?;
+        match inode.meta.inode_type {
+            InodeType::File => Ok(File::from_inode(inode)),
+            InodeType::Directory => Err(Error::new("Is a directory", libc::EISDIR)),
+            _ => Err(Error::new("Not a regular file", libc::EINVAL)),
+        }
+    }
+
+    fn get_open_file(&self, handle: FileHandle) -> Result<&OpenFile, Error> {
+        self.open_files
+            .get(&handle)
+            .ok_or(Error::new("Bad file handle", libc::EBADF))
+    }
+
+    fn ensure_absent(&self, directory: &Directory, name: &OsStr) -> Result<(), Error> {
+        match directory.find_entry(&self.structure, name)? {
+            Some(_) => Err(Error::new("File exists", libc::EEXIST)),
+            None => Ok(()),
+        }
+    }
+
+    fn find_entry(&self, directory: &Directory, name: &OsStr) -> Result<InodeId, Error> {
+        directory
+            .find_entry(&self.structure, name)?
+            .ok_or(Error::new("No such file or directory", libc::ENOENT))
+    }
+
+    fn is_in_use(&self, id: InodeId) -> bool {
+        self.lookups.contains_key(&id)
+            || self.open_files.values().any(|open_file| open_file.id == id)
+            || self
+                .open_directories
+                .values()
+                .any(|directory| directory.id == id)
+    }
+
+    // Unlinked inodes stay around until the last handle and the kernel's last reference
+    // to them are gone. Their blocks are freed a transaction at a time before the inode
+    // itself goes.
+    fn reclaim_if_unused(&mut self, id: InodeId) -> Result<(), Error> {
+        if self.structure.is_inode_free(id) {
+            return Ok(());
+        }
+        let inode = self.structure.read_inode(id)?;
+        if inode.meta.nlinks != 0 || self.is_in_use(id) {
+            return Ok(());
+        }
+        self.resize(id, 0)?;
+        self.transaction(|fs| fs.structure.free_inode(&mut fs.structure.read_inode(id)?))
+    }
+
+    // Truncates or extends `id` in steps that each fit into one transaction. A crash
+    // leaves the file at one of the sizes in between.
+    fn resize(&mut self, id: InodeId, size: u64) -> Result<Inode<Metadata>, Error> {
+        let step = self.bytes_per_transaction();
+        loop {
+            let inode = self.transaction(|fs| {
+                let mut file = File::from_inode(fs.structure.read_inode(id)?);
+                let current = file.inode.size;
+                let next = match current < size {
+                    true => u64::min(size, current.saturating_add(step)),
+                    false => u64::max(size, current.saturating_sub(step)),
+                };
+                file.truncate(&mut fs.structure, next)?;
+                Ok(file.inode)
+            })?;
+            if inode.size == size {
+                return Ok(inode);
+            }
+        }
+    }
+
+    // The bytes of file contents one transaction can write or free, see
+    // `Structure::blocks_per_transaction`.
+    fn bytes_per_transaction(&self) -> u64 {
+        self.structure
+            .blocks_per_transaction()
+            .saturating_mul(self.get_block_size() as u64)
+    }
+
+    fn adjust_links(&mut self, id: InodeId, delta: i32) -> Result<(), Error> {
+        let mut inode = self.structure.read_inode(id)?;
+        inode.meta.nlinks = inode.meta.nlinks.saturating_add_signed(delta);
+        inode.meta.changed_at = SystemTime::now();
+        self.structure.write_inode(&mut inode)
+    }
+
+    // Walks up the `..` entries from `id` to check whether `ancestor` is on the way.
+    fn is_ancestor(&self, ancestor: InodeId, id: InodeId) -> Result<bool, Error> {
+        let mut current = id;
+        loop {
+            if current == ancestor {
+                return Ok(true);
+            }
+            let parent = self.find_entry(&self.read_directory(current)?, OsStr::new(".."))?;
+            if parent == current {
+                return Ok(false);
+            }
+            current = parent;
+        }
+    }
+
+    // Fixes up `..` and the parents' link counts after `id` moved between directories.
+    fn reparent(
+        &mut self,
+        id: InodeId,
+        old_parent: InodeId,
+        new_parent: InodeId,
+    ) -> Result<(), Error> {
+        let mut inode = self.structure.read_inode(id)?;
+        inode.meta.changed_at = SystemTime::now();
+        self.structure.write_inode(&mut inode)?;
+        if inode.meta.inode_type != InodeType::Directory || old_parent == new_parent {
+            return Ok(());
+        }
+
+        let mut directory = Directory::from_inode(inode);
+        directory.set_entry(&mut self.structure, OsStr::new(".."), new_parent)?;
+        self.adjust_links(old_parent, -1)?;
+        self.adjust_links(new_parent, 1)
+    }
 
-        if Structure::<Metadata>::is_initialized(&mut io) {
-            let structure = Structure::mount(io);
-            let root = Directory::from_inode(structure.get_root_inode());
-            Ok(JourneyFS { structure, root })
-        } else {
-            let mut structure = Structure::new(io, block_size);
-            let root = Directory::new(&mut structure, user_id, group_id, 0o755);
-            Ok(JourneyFS { structure, root })
+    // Runs `operation` as one journal transaction. A failed operation is rolled back
+    // as a whole, nothing it wrote reaches the device.
+    fn transaction<T>(
+        &mut self,
+        operation: impl FnOnce(&mut JourneyFS) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        self.structure.begin();
+        match operation(self) {
+            Ok(value) => {
+                self.structure.commit()?;
+                Ok(value)
+            }
+            Err(error) => {
+                self.structure.abort()?;
+                Err(error)
+            }
         }
     }
 
-    pub fn get_block_size(&self) -> Result<usize, Error> {
-        Ok(self.structure.get_block_size())
+    pub fn get_block_size(&self) -> usize {
+        self.structure.get_block_size()
     }
 
     pub fn mkdir(
@@ -49,22 +306,1037 @@ impl JourneyFS {
         group_id: GroupId,
         permissions: u16,
     ) -> Result<Directory, Error> {
-        let parent_inode = self.structure.read_inode(parent);
-        let mut parent_directory = Directory::from_inode(parent_inode);
-        Ok(parent_directory.add_directory(
-            &mut self.structure,
-            name,
-            user_id,
-            group_id,
-            permissions,
-        ))
+        self.transaction(|fs| {
+            let mut parent_directory = fs.read_directory(parent)?;
+            fs.ensure_absent(&parent_directory, name)?;
+            parent_directory.add_directory(&mut fs.structure, name, user_id, group_id, permissions)
+        })
+    }
+
+    pub fn lookup(&self, parent: InodeId, name: &OsStr) -> Result<Inode<Metadata>, Error> {
+        let directory = self.read_directory(parent)?;
+        let id = self.find_entry(&directory, name)?;
+        self.structure.read_inode(id)
+    }
+
+    /// Removes the entry `name` from `parent`. The inode and its blocks are freed once
+    /// no links and no open handles are left.
+    pub fn unlink(&mut self, parent: InodeId, name: &OsStr) -> Result<(), Error> {
+        let id = self.transaction(|fs| {
+            let mut parent_directory = fs.read_directory(parent)?;
+            let id = fs.find_entry(&parent_directory, name)?;
+            let mut inode = fs.structure.read_inode(id)?;
+            if inode.meta.inode_type == InodeType::Directory {
+                return Err(Error::new("Is a directory", libc::EISDIR));
+            }
+
+            parent_directory.remove_entry(&mut fs.structure, name)?;
+            inode.meta.nlinks = inode.meta.nlinks.saturating_sub(1);
+            inode.meta.changed_at = SystemTime::now();
+            fs.structure.write_inode(&mut inode)?;
+            Ok(id)
+        })?;
+        self.reclaim_if_unused(id)
+    }
+
+    /// Removes the empty directory `name` from `parent`. Like with `unlink`, the
+    /// directory is freed once no open handles are left.
+    pub fn rmdir(&mut self, parent: InodeId, name: &OsStr) -> Result<(), Error> {
+        let id = self.transaction(|fs| {
+            if name == "." {
+                return Err(Error::new("Invalid argument", libc::EINVAL));
+            }
+            if name == ".." {
+                return Err(Error::new("Directory not empty", libc::ENOTEMPTY));
+            }
+
+            let mut parent_directory = fs.read_directory(parent)?;
+            let id = fs.find_entry(&parent_directory, name)?;
+            let directory = fs.read_directory(id)?;
+            if !directory.is_empty(&fs.structure)? {
+                return Err(Error::new("Directory not empty", libc::ENOTEMPTY));
+            }
+
+            parent_directory.inode.meta.nlinks =
+                parent_directory.inode.meta.nlinks.saturating_sub(1);
+            parent_directory.remove_entry(&mut fs.structure, name)?;
+            let mut inode = directory.inode;
+            inode.meta.nlinks = 0;
+            inode.meta.changed_at = SystemTime::now();
+            fs.structure.write_inode(&mut inode)?;
+            Ok(id)
+        })?;
+        self.reclaim_if_unused(id)
+    }
+
+    /// Creates a regular file, device node, FIFO or socket without opening it. The
+    /// type, owner, permissions and device number are taken from `meta`.
+    pub fn mknod(
+        &mut self,
+        parent: InodeId,
+        name: &OsString,
+        meta: Metadata,
+    ) -> Result<Inode<Metadata>, Error> {
+        self.transaction(|fs| {
+            let mut parent_directory = fs.read_directory(parent)?;
+            fs.ensure_absent(&parent_directory, name)?;
+            match meta.inode_type {
+                InodeType::File => Ok(parent_directory
+                    .add_file(
+                        &mut fs.structure,
+                        name,
+                        meta.user_id,
+                        meta.group_id,
+                        meta.permissions,
+                    )?
+                    .inode),
+                InodeType::Directory | InodeType::Symlink => {
+                    Err(Error::new("Use mkdir or symlink instead", libc::EINVAL))
+                }
+                _ => parent_directory.add_node(&mut fs.structure, name, meta),
+            }
+        })
+    }
+
+    pub fn symlink(
+        &mut self,
+        parent: InodeId,
+        name: &OsString,
+        target: &OsStr,
+        user_id: UserId,
+        group_id: GroupId,
+    ) -> Result<Symlink, Error> {
+        self.transaction(|fs| {
+            if target.is_empty() {
+                return Err(Error::new("No such file or directory", libc::ENOENT));
+            }
+            // PATH_MAX includes the terminating NUL
+            if target.len() >= libc::PATH_MAX as usize {
+                return Err(Error::new("File name too long", libc::ENAMETOOLONG));
+            }
+
+            let mut parent_directory = fs.read_directory(parent)?;
+            fs.ensure_absent(&parent_directory, name)?;
+            parent_directory.add_symlink(&mut fs.structure, name, target, user_id, group_id)
+        })
+    }
+
+    pub fn readlink(&self, id: InodeId) -> Result<OsString, Error> {
+        let inode = self.structure.read_inode(id)?;
+        if inode.meta.inode_type != InodeType::Symlink {
+            return Err(Error::new("Not a symlink", libc::EINVAL));
+        }
+        Symlink::from_inode(inode).get_target(&self.structure)
+    }
+
+    /// Adds `new_name` in `new_parent` as another name for the inode `id`. Directories
+    /// cannot be hard linked.
+    pub fn link(
+        &mut self,
+        id: InodeId,
+        new_parent: InodeId,
+        new_name: &OsStr,
+    ) -> Result<Inode<Metadata>, Error> {
+        self.transaction(|fs| {
+            let mut inode = fs.structure.read_inode(id)?;
+            if inode.meta.inode_type == InodeType::Directory {
+                return Err(Error::new("Cannot hard link a directory", libc::EPERM));
+            }
+            if new_name.len() > FILE_NAME_LENGTH {
+                return Err(Error::new("File name too long", libc::ENAMETOOLONG));
+            }
+
+            let mut parent_directory = fs.read_directory(new_parent)?;
+            fs.ensure_absent(&parent_directory, new_name)?;
+            parent_directory.add_entry(&mut fs.structure, &new_name.to_os_string(), id)?;
+            inode.meta.nlinks = inode.meta.nlinks.saturating_add(1);
+            inode.meta.changed_at = SystemTime::now();
+            fs.structure.write_inode(&mut inode)?;
+            Ok(inode)
+        })
+    }
+
+    /// Moves the entry `name` in `parent` to `new_name` in `new_parent`, replacing an
+    /// existing target unless `RENAME_NOREPLACE` is given. With `RENAME_EXCHANGE` both
+    /// entries must exist and trade places.
+    pub fn rename(
+        &mut self,
+        parent: InodeId,
+        name: &OsStr,
+        new_parent: InodeId,
+        new_name: &OsStr,
+        flags: u32,
+    ) -> Result<(), Error> {
+        // a replaced file is reclaimed once the rename is committed
+        let replaced = self.transaction(|fs| {
+            let exchange = flags & libc::RENAME_EXCHANGE != 0;
+            let no_replace = flags & libc::RENAME_NOREPLACE != 0;
+            if (exchange && no_replace)
+                || flags & !(libc::RENAME_EXCHANGE | libc::RENAME_NOREPLACE) != 0
+            {
+                return Err(Error::new("Invalid rename flags", libc::EINVAL));
+            }
+            if [name, new_name]
+                .iter()
+                .any(|name| *name == "." || *name == "..")
+            {
+                return Err(Error::new("Invalid argument", libc::EINVAL));
+            }
+            if new_name.len() > FILE_NAME_LENGTH {
+                return Err(Error::new("File name too long", libc::ENAMETOOLONG));
+            }
+
+            let id = fs.find_entry(&fs.read_directory(parent)?, name)?;
+            let target = fs
+                .read_directory(new_parent)?
+                .find_entry(&fs.structure, new_name)?;
+            if target == Some(id) {
+                // both names already refer to the same inode
+                return Ok(None);
+            }
+
+            let inode = fs.structure.read_inode(id)?;
+            let is_directory = inode.meta.inode_type == InodeType::Directory;
+            if is_directory && parent != new_parent && fs.is_ancestor(id, new_parent)? {
+                return Err(Error::new(
+                    "Cannot move a directory into itself",
+                    libc::EINVAL,
+                ));
+            }
+
+            let target = match target {
+                None if exchange => {
+                    return Err(Error::new("No such file or directory", libc::ENOENT))
+                }
+                None => None,
+                Some(_) if no_replace => return Err(Error::new("File exists", libc::EEXIST)),
+                Some(target) => Some(fs.structure.read_inode(target)?),
+            };
+
+            let mut replaced = None;
+            match target {
+                Some(target) if exchange => {
+                    let target_id = target.id.unwrap();
+                    let target_is_directory = target.meta.inode_type == InodeType::Directory;
+                    if target_is_directory
+                        && parent != new_parent
+                        && fs.is_ancestor(target_id, parent)?
+                    {
+                        return Err(Error::new(
+                            "Cannot move a directory into itself",
+                            libc::EINVAL,
+                        ));
+                    }
+
+                    fs.read_directory(new_parent)?
+                        .set_entry(&mut fs.structure, new_name, id)?;
+                    fs.read_directory(parent)?
+                        .set_entry(&mut fs.structure, name, target_id)?;
+                    fs.reparent(id, parent, new_parent)?;
+                    fs.reparent(target_id, new_parent, parent)?;
+                    return Ok(None);
+                }
+                Some(mut target) => {
+                    let target_is_directory = target.meta.inode_type == InodeType::Directory;
+                    if is_directory && !target_is_directory {
+                        return Err(Error::new("Not a directory", libc::ENOTDIR));
+                    }
+                    if !is_directory && target_is_directory {
+                        return Err(Error::new("Is a directory", libc::EISDIR));
+                    }
+                    if target_is_directory
+                        && !fs
+                            .read_directory(target.id.unwrap())?
+                            .is_empty(&fs.structure)?
+                    {
+                        return Err(Error::new("Directory not empty", libc::ENOTEMPTY));
+                    }
+
+                    // the target is swapped out in place, so the new name never goes missing
+                    fs.read_directory(new_parent)?
+                        .set_entry(&mut fs.structure, new_name, id)?;
+                    if target_is_directory {
+                        fs.adjust_links(new_parent, -1)?;
+                        target.meta.nlinks = 0;
+                        fs.structure.free_inode(&mut target)?;
+                    } else {
+                        target.meta.nlinks = target.meta.nlinks.saturating_sub(1);
+                        target.meta.changed_at = SystemTime::now();
+                        fs.structure.write_inode(&mut target)?;
+                        replaced = target.id;
+                    }
+                }
+                None => {
+                    fs.read_directory(new_parent)?.add_entry(
+                        &mut fs.structure,
+                        &new_name.to_os_string(),
+                        id,
+                    )?;
+                }
+            }
+
+            fs.read_directory(parent)?
+                .remove_entry(&mut fs.structure, name)?;
+            fs.reparent(id, parent, new_parent)?;
+            Ok(replaced)
+        })?;
+        match replaced {
+            Some(target) => self.reclaim_if_unused(target),
+            None => Ok(()),
+        }
+    }
+
+    pub fn opendir(&mut self, id: InodeId) -> Result<FileHandle, Error> {
+        let entries = self.read_directory(id)?.get_entries(&self.structure)?;
+        let handle = self.allocate_handle();
+        self.open_directories
+            .insert(handle, OpenDirectory { id, entries });
+        Ok(handle)
+    }
+
+    /// Returns the entries of an open directory starting at `offset`, which is the
+    /// number of entries the caller has already consumed.
+    pub fn readdir(&self, handle: FileHandle, offset: usize) -> Result<&[Entry], Error> {
+        match self.open_directories.get(&handle) {
+            Some(directory) => Ok(directory.entries.get(offset..).unwrap_or(&[])),
+            None => Err(Error::new("Bad directory handle", libc::EBADF)),
+        }
+    }
+
+    pub fn releasedir(&mut self, handle: FileHandle) -> Result<(), Error> {
+        match self.open_directories.remove(&handle) {
+            Some(directory) => self.reclaim_if_unused(directory.id),
+            None => Err(Error::new("Bad directory handle", libc::EBADF)),
+        }
+    }
+
+    pub fn create(
+        &mut self,
+        parent: InodeId,
+        name: &OsString,
+        user_id: UserId,
+        group_id: GroupId,
+        permissions: u16,
+        flags: i32,
+    ) -> Result<(File, FileHandle), Error> {
+        self.transaction(|fs| {
+            let mut parent_directory = fs.read_directory(parent)?;
+            fs.ensure_absent(&parent_directory, name)?;
+            let file = parent_directory.add_file(
+                &mut fs.structure,
+                name,
+                user_id,
+                group_id,
+                permissions,
+            )?;
+            let handle = fs.allocate_handle();
+            fs.open_files.insert(
+                handle,
+                OpenFile {
+                    id: file.inode.id.unwrap(),
+                    flags,
+                },
+            );
+            Ok((file, handle))
+        })
+    }
+
+    pub fn open(&mut self, id: InodeId, flags: i32) -> Result<FileHandle, Error> {
+        self.read_file(id)?;
+        if flags & libc::O_TRUNC != 0 && flags & libc::O_ACCMODE != libc::O_RDONLY {
+            self.resize(id, 0)?;
+        }
+        let handle = self.allocate_handle();
+        self.open_files.insert(handle, OpenFile { id, flags });
+        Ok(handle)
+    }
+
+    pub fn read(&self, handle: FileHandle, offset: u64, length: usize) -> Result<Vec<u8>, Error> {
+        let open_file = self.get_open_file(handle)?;
+        if open_file.flags & libc::O_ACCMODE == libc::O_WRONLY {
+            return Err(Error::new("File not open for reading", libc::EBADF));
+        }
+        let file = self.read_file(open_file.id)?;
+        file.read_at(&self.structure, offset, length)
+    }
+
+    /// Writes `data` at `offset`, or at the end of the file if it was opened with
+    /// `O_APPEND`. Returns the number of bytes written. Large writes are committed in
+    /// parts that each fit into the journal, so a failure part way through leaves the
+    /// parts before it written and reports only those.
+    pub fn write(&mut self, handle: FileHandle, offset: u64, data: &[u8]) -> Result<usize, Error> {
+        let open_file = self.get_open_file(handle)?;
+        if open_file.flags & libc::O_ACCMODE == libc::O_RDONLY {
+            return Err(Error::new("File not open for writing", libc::EBADF));
+        }
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let (id, append) = (open_file.id, open_file.flags & libc::O_APPEND != 0);
+        if !append && offset > self.read_file(id)?.inode.size {
+            self.resize(id, offset)?;
+        }
+
+        let step = usize::try_from(self.bytes_per_transaction()).unwrap_or(usize::MAX);
+        let mut written = 0;
+        for part in data.chunks(step) {
+            let position = offset + written as u64;
+            let result = self.transaction(|fs| {
+                let mut file = fs.read_file(id)?;
+                match append {
+                    true => file.append(&mut fs.structure, part),
+                    false => file.write_at(&mut fs.structure, position, part),
+                }
+            });
+            match result {
+                Ok(()) => written += part.len(),
+                Err(_) if written > 0 => break,
+                Err(error) => return Err(error),
+            }
+        }
+        Ok(written)
+    }
+
+    pub fn truncate(&mut self, id: InodeId, size: u64) -> Result<Inode<Metadata>, Error> {
+        self.read_file(id)?;
+        self.resize(id, size)
+    }
+
+    pub fn flush(&self, handle: FileHandle) -> Result<(), Error> {
+        // writes go straight to the device, so there is nothing buffered to flush
+        self.get_open_file(handle).map(|_| ())
+    }
+
+    pub fn release(&mut self, handle: FileHandle) -> Result<(), Error> {
+        match self.open_files.remove(&handle) {
+            Some(open_file) => self.reclaim_if_unused(open_file.id),
+            None => Err(Error::new("Bad file handle", libc::EBADF)),
+        }
+    }
+
+    /// Counts a reference the kernel took to `id` when it was handed its attributes.
+    /// The inode is not freed while the kernel refers to it, even once unlinked.
+    pub fn remember(&mut self, id: InodeId) {
+        *self.lookups.entry(id).or_default() += 1;
+    }
+
+    /// Drops `count` references the kernel held to `id`, freeing the inode if it was
+    /// unlinked and nothing else refers to it.
+    pub fn forget(&mut self, id: InodeId, count: u64) -> Result<(), Error> {
+        let hash_map::Entry::Occupied(mut entry) = self.lookups.entry(id) else {
+            return Ok(());
+        };
+        *entry.get_mut() = entry.get().saturating_sub(count);
+        if *entry.get() != 0 {
+            return Ok(());
+        }
+        entry.remove();
+        self.reclaim_if_unused(id)
+    }
+
+    /// Drops every reference the kernel held, which it does not always do before
+    /// unmounting.
+    pub fn forget_all(&mut self) -> Result<(), Error> {
+        for id in std::mem::take(&mut self.lookups).into_keys() {
+            self.reclaim_if_unused(id)?;
+        }
+        Ok(())
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.structure.layout()
+    }
+
+    pub fn usage(&self) -> Usage {
+        self.structure.usage()
+    }
+
+    /// The checksum mismatches found since mounting.
+    pub fn checksum_errors(&self) -> u64 {
+        self.structure.checksum_errors()
+    }
+
+    /// Writes the superblock back to block 0 and to all backups, which repairs a
+    /// damaged superblock after mounting with `MountOptions::backup`.
+    pub fn restore_superblock(&mut self) -> Result<(), Error> {
+        self.transaction(|fs| fs.structure.write_super_block())
+    }
+
+    /// Whether file contents are checksummed, which `scrub` needs.
+    pub fn has_data_checksums(&self) -> bool {
+        self.structure.has_data_checksums()
+    }
+
+    /// The on-disk structures underneath, for tools that inspect them directly.
+    pub(crate) fn structure(&self) -> &Structure<Metadata> {
+        &self.structure
+    }
+
+    /// Flushes everything written so far to the device.
+    pub fn sync(&mut self) -> Result<(), Error> {
+        self.structure.sync()
     }
 
     pub fn get_inode(&self, id: InodeId) -> Result<Inode<Metadata>, Error> {
-        Ok(self.structure.read_inode(id))
+        self.structure.read_inode(id)
     }
 
     pub fn write_inode(&mut self, inode: &mut Inode<Metadata>) -> Result<(), Error> {
-        Ok(self.structure.write_inode(inode))
+        self.transaction(|fs| fs.structure.write_inode(inode))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::memory_drive::MemoryDrive;
+    use crate::structure::layout::DataMode;
+    use crate::structure::superblock::SuperBlock;
+    use crate::testing::{create_fs, memory_drive};
+
+    #[test]
+    fn test_usage() {
+        let drive = memory_drive();
+        let mut fs = create_fs(drive.clone(), 0);
+        let root = fs.structure.super_block.root_inode;
+        let matches_bitmaps = |fs: &JourneyFS| {
+            let usage = fs.usage();
+            usage.free_blocks == fs.structure.block_map.count_free()
+                && usage.free_inodes == fs.structure.inode_table.count_free()
+        };
+        let before = fs.usage();
+        assert_eq!(before.block_size, 1024);
+        assert!(matches_bitmaps(&fs));
+
+        let name = OsString::from("file");
+        let (_, handle) = fs.create(root, &name, 0, 0, 0o644, libc::O_RDWR).unwrap();
+        fs.write(handle, 0, &[1u8; 4096]).unwrap();
+        assert_eq!(fs.usage().free_inodes, before.free_inodes - 1);
+        assert!(fs.usage().free_blocks <= before.free_blocks - 4);
+        assert!(matches_bitmaps(&fs));
+
+        // block 0 is written with every transaction, the backups only on sync
+        let backup = fs.structure.super_block.backups[0];
+        let stored = |drive: &MemoryDrive, location| {
+            let mut io = IO::new(drive.clone(), 512);
+            let super_block = match location {
+                0 => SuperBlock::read(&io).unwrap().unwrap(),
+                _ => SuperBlock::read_backup(&mut io, location).unwrap(),
+            };
+            (super_block.free_blocks, super_block.free_inodes)
+        };
+        let counts = (fs.usage().free_blocks, fs.usage().free_inodes);
+        assert_eq!(stored(&drive, 0), counts);
+        assert_eq!(
+            stored(&drive, backup),
+            (before.free_blocks, before.free_inodes)
+        );
+        fs.sync().unwrap();
+        assert_eq!(stored(&drive, backup), counts);
+
+        fs.unlink(root, &name).unwrap();
+        fs.release(handle).unwrap();
+        assert_eq!(fs.usage().free_inodes, before.free_inodes);
+        assert!(matches_bitmaps(&fs));
+
+        // stale counters are recomputed from the bitmaps on mount
+        fs.structure.super_block.free_blocks = 0;
+        fs.structure.super_block.free_inodes = 0;
+        fs.mkdir(root, &OsString::from("dir"), 0, 0, 0o755).unwrap();
+        assert_eq!(fs.usage().free_inodes, 0);
+        drop(fs);
+        let fs = JourneyFS::mount(drive).unwrap();
+        assert!(matches_bitmaps(&fs));
+        assert_eq!(fs.usage().free_inodes, before.free_inodes - 1);
+    }
+
+    #[test]
+    fn test_unlink_frees_inode_and_blocks() {
+        let mut fs = create_fs(memory_drive(), 0);
+        let root = fs.structure.super_block.root_inode;
+        let name = OsString::from("file");
+        let (file, handle) = fs.create(root, &name, 0, 0, 0o644, libc::O_RDWR).unwrap();
+        let id = file.inode.id.unwrap();
+        fs.write(handle, 0, &[1u8; 4096]).unwrap();
+        let block = fs
+            .structure
+            .read_inode(id)
+            .unwrap()
+            .block_pointer(&fs.structure, 0)
+            .unwrap();
+
+        // still open, so nothing is reclaimed yet
+        fs.unlink(root, &name).unwrap();
+        assert_eq!(fs.lookup(root, &name).err().unwrap().errno(), libc::ENOENT);
+        assert_eq!(fs.read(handle, 0, 4).unwrap(), vec![1u8; 4]);
+        assert!(!fs.structure.block_map.is_free(block));
+
+        fs.release(handle).unwrap();
+        assert!(fs.structure.block_map.is_free(block));
+        assert!(fs.structure.inode_table.is_free(id));
+    }
+
+    #[test]
+    fn test_unlinked_inode_outlives_kernel_references() {
+        let mut fs = create_fs(memory_drive(), 0);
+        let root = fs.structure.super_block.root_inode;
+        let mut ids = Vec::new();
+        for name in ["a", "b"] {
+            let (file, handle) = fs
+                .create(root, &OsString::from(name), 0, 0, 0o644, libc::O_RDWR)
+                .unwrap();
+            let id = file.inode.id.unwrap();
+            fs.remember(id);
+            fs.remember(id);
+            fs.release(handle).unwrap();
+            fs.unlink(root, &OsString::from(name)).unwrap();
+            ids.push(id);
+        }
+
+        // the kernel may still ask for the attributes until it forgets the inode
+        assert!(!fs.structure.inode_table.is_free(ids[0]));
+        fs.forget(ids[0], 1).unwrap();
+        assert_eq!(fs.get_inode(ids[0]).unwrap().meta.nlinks, 0);
+        fs.forget(ids[0], 1).unwrap();
+        assert!(fs.structure.inode_table.is_free(ids[0]));
+        // forgetting an inode that is gone already changes nothing
+        fs.forget(ids[0], 1).unwrap();
+
+        assert!(!fs.structure.inode_table.is_free(ids[1]));
+        fs.forget_all().unwrap();
+        assert!(fs.structure.inode_table.is_free(ids[1]));
+    }
+
+    #[test]
+    fn test_rmdir() {
+        let mut fs = create_fs(memory_drive(), 0);
+        let root = fs.structure.super_block.root_inode;
+        let name = OsString::from("dir");
+        let directory = fs.mkdir(root, &name, 0, 0, 0o755).unwrap();
+        let id = directory.inode.id.unwrap();
+        let child = OsString::from("child");
+        fs.mkdir(id, &child, 0, 0, 0o755).unwrap();
+
+        let error = fs.rmdir(root, &name).unwrap_err();
+        assert_eq!(error.errno(), libc::ENOTEMPTY);
+        assert_eq!(fs.unlink(root, &name).unwrap_err().errno(), libc::EISDIR);
+
+        fs.rmdir(id, &child).unwrap();
+        fs.rmdir(root, &name).unwrap();
+        assert_eq!(fs.lookup(root, &name).err().unwrap().errno(), libc::ENOENT);
+        assert!(fs.structure.inode_table.is_free(id));
+    }
+
+    #[test]
+    fn test_removed_directory_outlives_handles() {
+        let mut fs = create_fs(memory_drive(), 0);
+        let root = fs.structure.super_block.root_inode;
+        let name = OsString::from("dir");
+        let id = fs
+            .mkdir(root, &name, 0, 0, 0o755)
+            .unwrap()
+            .inode
+            .id
+            .unwrap();
+        let handle = fs.opendir(id).unwrap();
+        fs.remember(id);
+
+        fs.rmdir(root, &name).unwrap();
+        assert_eq!(fs.lookup(root, &name).err().unwrap().errno(), libc::ENOENT);
+        assert_eq!(fs.get_inode(id).unwrap().meta.nlinks, 0);
+        assert_eq!(fs.readdir(handle, 0).unwrap().len(), 2);
+
+        fs.releasedir(handle).unwrap();
+        assert!(!fs.structure.inode_table.is_free(id));
+        fs.forget(id, 1).unwrap();
+        assert!(fs.structure.inode_table.is_free(id));
+    }
+
+    #[test]
+    fn test_rename() {
+        let mut fs = create_fs(memory_drive(), 0);
+        let root = fs.structure.super_block.root_inode;
+        let (a, b) = (OsString::from("a"), OsString::from("b"));
+        let directory = fs.mkdir(root, &a, 0, 0, 0o755).unwrap();
+        let id = directory.inode.id.unwrap();
+        let (file, handle) = fs.create(root, &b, 0, 0, 0o644, libc::O_RDWR).unwrap();
+        let file_id = file.inode.id.unwrap();
+        fs.release(handle).unwrap();
+
+        // a plain move into another directory
+        fs.rename(root, &b, id, &b, 0).unwrap();
+        assert!(fs.lookup(root, &b).is_err());
+        assert_eq!(fs.lookup(id, &b).unwrap().id, Some(file_id));
+
+        // a directory cannot end up inside itself
+        let child = fs.mkdir(id, &a, 0, 0, 0o755).unwrap().inode.id.unwrap();
+        let error = fs.rename(root, &a, child, &b, 0).err().unwrap();
+        assert_eq!(error.errno(), libc::EINVAL);
+
+        // moving a directory rewrites its `..`
+        fs.rename(id, &a, root, &b, 0).unwrap();
+        assert_eq!(fs.lookup(child, OsStr::new("..")).unwrap().id, Some(root));
+
+        let error = fs.rename(id, &b, root, &b, libc::RENAME_NOREPLACE);
+        assert_eq!(error.err().unwrap().errno(), libc::EEXIST);
+        let error = fs.rename(id, &b, root, &b, 0);
+        assert_eq!(error.err().unwrap().errno(), libc::EISDIR);
+
+        fs.rename(id, &b, root, &b, libc::RENAME_EXCHANGE).unwrap();
+        assert_eq!(fs.lookup(root, &b).unwrap().id, Some(file_id));
+        assert_eq!(fs.lookup(id, &b).unwrap().id, Some(child));
+        assert_eq!(fs.lookup(child, OsStr::new("..")).unwrap().id, Some(id));
+    }
+
+    #[test]
+    fn test_failed_operation_is_rolled_back() {
+        let drive = memory_drive();
+        let mut fs = create_fs(drive.clone(), 0);
+        let root = fs.structure.super_block.root_inode;
+        fs.sync().unwrap();
+        let image = drive.snapshot();
+        let usage = fs.usage();
+
+        // the inode, and for the directory its first block, are allocated before the
+        // name turns out to be too long
+        let name = OsString::from("x".repeat(FILE_NAME_LENGTH + 1));
+        let error = fs.create(root, &name, 0, 0, 0o644, libc::O_RDWR);
+        assert_eq!(error.err().unwrap().errno(), libc::ENAMETOOLONG);
+        let error = fs.mkdir(root, &name, 0, 0, 0o755);
+        assert_eq!(error.err().unwrap().errno(), libc::ENAMETOOLONG);
+        assert_eq!(fs.usage().free_blocks, usage.free_blocks);
+        assert_eq!(fs.usage().free_inodes, usage.free_inodes);
+        fs.sync().unwrap();
+        assert!(drive.snapshot() == image);
+
+        // the allocations were undone in memory as well
+        let (file, handle) = fs
+            .create(root, &OsString::from("a"), 0, 0, 0o644, libc::O_RDWR)
+            .unwrap();
+        fs.release(handle).unwrap();
+        assert_eq!(file.inode.id, Some(root + 1));
+    }
+
+    #[test]
+    fn test_lock_drops_transaction_of_panicked_operation() {
+        let fs = create_fs(memory_drive(), 0);
+        let root = fs.structure.super_block.root_inode;
+        let usage = fs.usage();
+        let shared = std::sync::Arc::new(Mutex::new(fs));
+
+        let name = OsString::from("dir");
+        let result = std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    let mut fs = shared.lock().unwrap();
+                    fs.structure.begin();
+                    fs.mkdir(root, &name, 0, 0, 0o755).unwrap();
+                    panic!("operation failed halfway");
+                })
+                .join()
+        });
+        assert!(result.is_err());
+        assert!(shared.is_poisoned());
+
+        let mut fs = JourneyFS::lock(&shared);
+        assert_eq!(fs.lookup(root, &name).err().unwrap().errno(), libc::ENOENT);
+        assert_eq!(fs.usage().free_inodes, usage.free_inodes);
+        fs.mkdir(root, &name, 0, 0, 0o755).unwrap();
+        drop(fs);
+        assert!(!shared.is_poisoned());
+    }
+
+    #[test]
+    fn test_read_only_mount_of_dirty_image() {
+        let drive = memory_drive();
+        let mut fs = create_fs(drive.clone(), 0);
+        let root = fs.structure.super_block.root_inode;
+        let name = OsString::from("file");
+        let (_, handle) = fs.create(root, &name, 0, 0, 0o644, libc::O_RDWR).unwrap();
+        fs.write(handle, 0, b"hello").unwrap();
+        fs.release(handle).unwrap();
+        // the last transaction is still waiting in the journal for a checkpoint
+        let image = drive.snapshot();
+
+        let options = MountOptions {
+            read_only: true,
+            ..MountOptions::default()
+        };
+        let mut fs = JourneyFS::mount_with(drive.clone(), &options).unwrap();
+        let id = fs.lookup(root, &name).unwrap().id.unwrap();
+        let handle = fs.open(id, libc::O_RDONLY).unwrap();
+        assert_eq!(fs.read(handle, 0, 100).unwrap(), b"hello");
+        fs.release(handle).unwrap();
+        assert_eq!(fs.unlink(root, &name).unwrap_err().errno(), libc::EROFS);
+        assert_eq!(fs.lookup(root, &name).unwrap().id, Some(id));
+        fs.sync().unwrap();
+        assert!(drive.snapshot() == image);
+    }
+
+    #[test]
+    fn test_operations_larger_than_journal() {
+        let drive = MemoryDrive::new(4096 * 1024, 512);
+        let options = FormatOptions {
+            block_size: 512,
+            journal_blocks: Some(16),
+            ..FormatOptions::default()
+        };
+        JourneyFS::format(drive.clone(), &options, 0, 0, false).unwrap();
+        let options = MountOptions {
+            data_mode: DataMode::Journal,
+            ..MountOptions::default()
+        };
+        let mut fs = JourneyFS::mount_with(drive.clone(), &options).unwrap();
+        let root = fs.structure.super_block.root_inode;
+        let before = fs.usage();
+
+        // 13 blocks fit into the journal, far fewer than the write and truncate take
+        let name = OsString::from("file");
+        let data: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        let (file, handle) = fs.create(root, &name, 0, 0, 0o644, libc::O_RDWR).unwrap();
+        assert_eq!(fs.write(handle, 0, &data).unwrap(), data.len());
+        fs.truncate(file.inode.id.unwrap(), 128 * 1024).unwrap();
+        fs.release(handle).unwrap();
+        fs.sync().unwrap();
+
+        let mut fs = JourneyFS::mount_with(drive, &options).unwrap();
+        let handle = fs.open(file.inode.id.unwrap(), libc::O_RDONLY).unwrap();
+        assert_eq!(fs.read(handle, 0, data.len()).unwrap(), data);
+        assert_eq!(fs.read(handle, 96 * 1024, 16).unwrap(), vec![0; 16]);
+        fs.release(handle).unwrap();
+        fs.unlink(root, &name).unwrap();
+        assert_eq!(fs.usage().free_blocks, before.free_blocks);
+        assert_eq!(fs.usage().free_inodes, before.free_inodes);
+    }
+
+    #[test]
+    fn test_rename_replaces_target() {
+        let mut fs = create_fs(memory_drive(), 0);
+        let root = fs.structure.super_block.root_inode;
+        let (a, b) = (OsString::from("a"), OsString::from("b"));
+        let (source, handle) = fs.create(root, &a, 0, 0, 0o644, libc::O_RDWR).unwrap();
+        fs.release(handle).unwrap();
+        let (target, handle) = fs.create(root, &b, 0, 0, 0o644, libc::O_RDWR).unwrap();
+        fs.release(handle).unwrap();
+
+        fs.rename(root, &a, root, &b, 0).unwrap();
+        assert!(fs.lookup(root, &a).is_err());
+        assert_eq!(fs.lookup(root, &b).unwrap().id, source.inode.id);
+        assert!(fs.structure.inode_table.is_free(target.inode.id.unwrap()));
+    }
+
+    #[test]
+    fn test_link() {
+        let mut fs = create_fs(memory_drive(), 0);
+        let root = fs.structure.super_block.root_inode;
+        let (a, b) = (OsString::from("a"), OsString::from("b"));
+        let (file, handle) = fs.create(root, &a, 0, 0, 0o644, libc::O_RDWR).unwrap();
+        fs.release(handle).unwrap();
+        let id = file.inode.id.unwrap();
+
+        assert_eq!(fs.link(id, root, &b).unwrap().meta.nlinks, 2);
+        assert_eq!(fs.lookup(root, &b).unwrap().id, Some(id));
+        let error = fs.link(id, root, &a).err().unwrap();
+        assert_eq!(error.errno(), libc::EEXIST);
+        let error = fs.link(root, root, &OsString::from("c")).err().unwrap();
+        assert_eq!(error.errno(), libc::EPERM);
+
+        // the data survives until the last name is gone
+        fs.unlink(root, &a).unwrap();
+        assert_eq!(fs.get_inode(id).unwrap().meta.nlinks, 1);
+        assert!(!fs.structure.inode_table.is_free(id));
+        fs.unlink(root, &b).unwrap();
+        assert!(fs.structure.inode_table.is_free(id));
+    }
+
+    #[test]
+    fn test_directory_nlinks() {
+        let mut fs = create_fs(memory_drive(), 0);
+        let root = fs.structure.super_block.root_inode;
+        let nlinks = |fs: &JourneyFS, id| fs.get_inode(id).unwrap().meta.nlinks;
+        assert_eq!(nlinks(&fs, root), 2);
+
+        let (a, b) = (OsString::from("a"), OsString::from("b"));
+        let a_id = fs.mkdir(root, &a, 0, 0, 0o755).unwrap().inode.id.unwrap();
+        let b_id = fs.mkdir(root, &b, 0, 0, 0o755).unwrap().inode.id.unwrap();
+        assert_eq!(nlinks(&fs, root), 4);
+        assert_eq!(nlinks(&fs, a_id), 2);
+
+        fs.rename(root, &b, a_id, &b, 0).unwrap();
+        assert_eq!(nlinks(&fs, root), 3);
+        assert_eq!(nlinks(&fs, a_id), 3);
+
+        fs.rmdir(a_id, &b).unwrap();
+        assert_eq!(nlinks(&fs, a_id), 2);
+        assert!(fs.structure.inode_table.is_free(b_id));
+    }
+
+    #[test]
+    fn test_symlink() {
+        let mut fs = create_fs(memory_drive(), 0);
+        let root = fs.structure.super_block.root_inode;
+        let name = OsString::from("link");
+        let target = OsStr::new("some/where");
+        let symlink = fs.symlink(root, &name, target, 0, 0).unwrap();
+        let id = symlink.inode.id.unwrap();
+
+        assert_eq!(
+            fs.lookup(root, &name).unwrap().meta.inode_type,
+            InodeType::Symlink
+        );
+        assert_eq!(fs.readlink(id).unwrap(), target);
+        assert_eq!(fs.readlink(root).err().unwrap().errno(), libc::EINVAL);
+        assert_eq!(
+            fs.open(id, libc::O_RDONLY).err().unwrap().errno(),
+            libc::EINVAL
+        );
+
+        let too_long = OsString::from("a".repeat(libc::PATH_MAX as usize));
+        let error = fs.symlink(root, &OsString::from("long"), &too_long, 0, 0);
+        assert_eq!(error.err().unwrap().errno(), libc::ENAMETOOLONG);
+
+        fs.unlink(root, &name).unwrap();
+        assert!(fs.structure.inode_table.is_free(id));
+    }
+
+    #[test]
+    fn test_mknod() {
+        let mut fs = create_fs(memory_drive(), 0);
+        let root = fs.structure.super_block.root_inode;
+        let node_meta = |inode_type, rdev| {
+            let mut meta = Metadata::new(inode_type, 0, 0, 0o666, 1, 0);
+            meta.rdev = rdev;
+            meta
+        };
+
+        let name = OsString::from("null");
+        let rdev = (1 << 8) | 3;
+        let node = fs
+            .mknod(root, &name, node_meta(InodeType::CharDevice, rdev))
+            .unwrap();
+        let inode = fs.lookup(root, &name).unwrap();
+        assert_eq!(inode.id, node.id);
+        assert_eq!(inode.meta.inode_type, InodeType::CharDevice);
+        assert_eq!(inode.meta.rdev, rdev);
+        let error = fs.open(inode.id.unwrap(), libc::O_RDONLY).err().unwrap();
+        assert_eq!(error.errno(), libc::EINVAL);
+
+        let fifo = OsString::from("fifo");
+        fs.mknod(root, &fifo, node_meta(InodeType::Fifo, 0))
+            .unwrap();
+        assert_eq!(
+            fs.lookup(root, &fifo).unwrap().meta.inode_type,
+            InodeType::Fifo
+        );
+        let error = fs.mknod(root, &fifo, node_meta(InodeType::Socket, 0));
+        assert_eq!(error.err().unwrap().errno(), libc::EEXIST);
+        let error = fs.mknod(root, &name, node_meta(InodeType::Directory, 0));
+        assert_eq!(error.err().unwrap().errno(), libc::EEXIST);
+        let dir = OsString::from("dir");
+        let error = fs.mknod(root, &dir, node_meta(InodeType::Directory, 0));
+        assert_eq!(error.err().unwrap().errno(), libc::EINVAL);
+    }
+
+    #[test]
+    fn test_format() {
+        let drive = MemoryDrive::new(4096 * 1024, 512);
+        let options = FormatOptions {
+            block_size: 1024,
+            label: String::from("data"),
+            uuid: [1; 16],
+            ..FormatOptions::default()
+        };
+        let fs = JourneyFS::format(drive.clone(), &options, 0, 0, false);
+        let layout = fs.unwrap().layout();
+        assert_eq!(layout.block_count, 4096);
+        // one map block of inodes, less its checksum
+        assert_eq!(layout.inode_count, 1020 * 8);
+        assert_eq!(layout.label, "data");
+        // the middle of the device is taken by the inode table
+        assert_eq!(layout.backups, vec![4095]);
+
+        let reopen = || drive.clone();
+        let error = JourneyFS::format(reopen(), &options, 0, 0, false)
+            .err()
+            .unwrap();
+        assert_eq!(error.errno(), libc::EEXIST);
+        let layout = JourneyFS::mount(reopen()).unwrap().layout();
+        assert_eq!(layout.uuid, [1; 16]);
+        assert!(JourneyFS::format(reopen(), &options, 0, 0, true).is_ok());
+
+        let tiny = MemoryDrive::new(8 * 1024, 512);
+        let error = JourneyFS::format(tiny, &options, 0, 0, false)
+            .err()
+            .unwrap();
+        assert_eq!(error.errno(), libc::ENOSPC);
+    }
+
+    #[test]
+    fn test_append() {
+        let mut fs = create_fs(memory_drive(), 0);
+        let root = fs.structure.super_block.root_inode;
+        let flags = libc::O_WRONLY | libc::O_APPEND;
+        let (file, handle) = fs
+            .create(root, &OsString::from("log"), 0, 0, 0o644, flags)
+            .unwrap();
+        fs.write(handle, 0, b"one ").unwrap();
+        // the offset is ignored, appends always go to the end
+        fs.write(handle, 0, b"two").unwrap();
+        fs.release(handle).unwrap();
+
+        let handle = fs.open(file.inode.id.unwrap(), libc::O_RDONLY).unwrap();
+        assert_eq!(fs.read(handle, 0, 100).unwrap(), b"one two");
+    }
+
+    #[test]
+    fn test_recover_from_backup() {
+        let mut drive = memory_drive();
+        let mut fs = create_fs(drive.clone(), 0);
+        let root = fs.structure.super_block.root_inode;
+        fs.mkdir(root, &OsString::from("dir"), 0, 0, 0o755).unwrap();
+        let free_blocks = fs.usage().free_blocks;
+        drop(fs);
+
+        drive.write_sector(0, &[0; 512]).unwrap();
+        let error = JourneyFS::mount(drive.clone()).err().unwrap();
+        assert_eq!(error.errno(), libc::EUCLEAN);
+        assert!(error.to_string().contains("block 10239"));
+        // the device is not mistaken for an empty one
+        let options = FormatOptions {
+            block_size: 1024,
+            ..FormatOptions::default()
+        };
+        let error = JourneyFS::format(drive.clone(), &options, 0, 0, false)
+            .err()
+            .unwrap();
+        assert_eq!(error.errno(), libc::EEXIST);
+
+        for backup in [5120, 10239] {
+            let options = MountOptions {
+                backup: Some(backup),
+                ..MountOptions::default()
+            };
+            let fs = JourneyFS::mount_with(drive.clone(), &options).unwrap();
+            assert_eq!(fs.usage().free_blocks, free_blocks);
+            assert!(fs.lookup(root, &OsString::from("dir")).is_ok());
+        }
+        let options = MountOptions {
+            backup: Some(1000),
+            ..MountOptions::default()
+        };
+        let error = JourneyFS::mount_with(drive.clone(), &options)
+            .err()
+            .unwrap();
+        assert_eq!(error.errno(), libc::EINVAL);
+
+        let options = MountOptions {
+            backup: Some(10239),
+            ..MountOptions::default()
+        };
+        let mut fs = JourneyFS::mount_with(drive.clone(), &options).unwrap();
+        fs.restore_superblock().unwrap();
+        drop(fs);
+        let fs = JourneyFS::mount(drive).unwrap();
+        assert_eq!(fs.check().unwrap(), vec![]);
     }
 }
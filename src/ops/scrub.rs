@@ -0,0 +1,230 @@
+use crate::consts::BlockPointer;
+use crate::ops::JourneyFS;
+use crate::structure::inode::{BlockUse, InodeId};
+use crate::util::checksum::checksum;
+use crate::util::error::Error;
+use std::fmt::{Display, Formatter};
+
+/// A data block whose contents do not match the checksum recorded for it.
+#[derive(Debug, PartialEq, Clone)]
+pub struct BadBlock {
+    pub inode: InodeId,
+    pub block: BlockPointer,
+    // rewritten from an intact copy on a mirrored device
+    pub repaired: bool,
+}
+
+impl Display for BadBlock {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Block {} of inode {} does not match its checksum",
+            self.block, self.inode
+        )?;
+        if self.repaired {
+            write!(f, ", repaired from a mirror")?;
+        }
+        Ok(())
+    }
+}
+
+/// The progress of a scrub that runs a few inodes at a time, so that it can be
+/// interleaved with other work on a mounted filesystem.
+pub struct Scrub {
+    // inode 0 is reserved and never holds anything
+    next_inode: InodeId,
+    repair: bool,
+    /// The bad blocks found so far, callers may take them out as they go.
+    pub bad_blocks: Vec<BadBlock>,
+}
+
+impl Scrub {
+    /// Starts a scrub that rewrites bad blocks from intact copies if `repair` is set.
+    pub fn new(repair: bool) -> Scrub {
+        Scrub {
+            next_inode: 1,
+            repair,
+            bad_blocks: Vec::new(),
+        }
+    }
+}
+
+impl JourneyFS {
+    /// Verifies every data block against its checksum and returns the bad ones, see
+    /// `scrub_step`.
+    pub fn scrub(&mut self, repair: bool) -> Result<Vec<BadBlock>, Error> {
+        let mut scrub = Scrub::new(repair);
+        while !self.scrub_step(&mut scrub, u64::MAX)? {}
+        Ok(scrub.bad_blocks)
+    }
+
+    /// Verifies the data blocks of up to `inodes` more inodes in use and returns whether
+    /// the scrub is done. When repairing, a bad block is rewritten from the first copy
+    /// on a mirrored device that matches its checksum.
+    pub fn scrub_step(&mut self, scrub: &mut Scrub, inodes: u64) -> Result<bool, Error> {
+        if !self.structure.has_data_checksums() {
+            return Err(Error::new(
+                "The filesystem keeps no data checksums",
+                libc::EINVAL,
+            ));
+        }
+
+        let mut visited = 0;
+        while visited < inodes && scrub.next_inode < self.structure.inode_count() {
+            let id = scrub.next_inode;
+            scrub.next_inode += 1;
+            if self.structure.is_inode_free(id) {
+                continue;
+            }
+            visited += 1;
+            // damaged metadata is for fsck to deal with, the rest is still worth checking
+            let Ok(inode) = self.structure.read_inode(id) else {
+                continue;
+            };
+            let block_count = self.structure.block_count();
+            let mut blocks = Vec::new();
+            let _ = inode.walk_blocks(&self.structure, &mut |usage| match usage {
+                BlockUse::Data(_, block) => {
+                    blocks.push(block);
+                    true
+                }
+                BlockUse::Node(block) => block < block_count,
+            });
+            for block in blocks {
+                if let Some(bad_block) = self.scrub_block(id, block, scrub.repair)? {
+                    scrub.bad_blocks.push(bad_block);
+                }
+            }
+        }
+        Ok(scrub.next_inode >= self.structure.inode_count())
+    }
+
+    fn scrub_block(
+        &mut self,
+        inode: InodeId,
+        block: BlockPointer,
+        repair: bool,
+    ) -> Result<Option<BadBlock>, Error> {
+        let expected = match self.structure.data_checksum(block) {
+            Ok(Some(expected)) => expected,
+            // out of range or not written through an inode yet
+            _ => return Ok(None),
+        };
+        let is_intact = |copy: &Vec<u8>| checksum(copy, block) == expected;
+        if self
+            .structure
+            .read_block_copy(block, 0)
+            .is_ok_and(|copy| is_intact(&copy))
+        {
+            return Ok(None);
+        }
+
+        let intact = match repair {
+            true => (1..self.structure.copies()).find_map(|copy| {
+                self.structure
+                    .read_block_copy(block, copy)
+                    .ok()
+                    .filter(is_intact)
+            }),
+            false => None,
+        };
+        if let Some(copy) = &intact {
+            self.structure.write_block(block, copy)?;
+        }
+        Ok(Some(BadBlock {
+            inode,
+            block,
+            repaired: intact.is_some(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::driver::memory_drive::MemoryDrive;
+    use crate::driver::mirror_drive::MirrorDrive;
+    use crate::driver::DeviceDriver;
+    use crate::structure::superblock::FEATURE_DATA_CHECKSUMS;
+    use crate::testing::{self, BLOCK_SIZE};
+    use std::ffi::OsString;
+
+    fn create_fs<D: DeviceDriver + 'static>(drive: D, features: u32) -> (JourneyFS, InodeId) {
+        let mut fs = testing::create_fs(drive, features);
+        let root = fs.structure.get_root_inode().unwrap().id.unwrap();
+        let (file, handle) = fs
+            .create(root, &OsString::from("file"), 0, 0, 0o644, libc::O_RDWR)
+            .unwrap();
+        fs.write(handle, 0, &vec![7u8; 8 * BLOCK_SIZE]).unwrap();
+        fs.release(handle).unwrap();
+        (fs, file.inode.id.unwrap())
+    }
+
+    // Overwrites the first sector of a block behind the filesystem's back.
+    fn damage(drive: &mut MemoryDrive, block: BlockPointer) {
+        let sector = block * (BLOCK_SIZE / 512) as u64;
+        drive.write_sector(sector, &vec![0xff; 512]).unwrap();
+    }
+
+    #[test]
+    fn test_scrub_reports_bad_blocks() {
+        let mut memory = MemoryDrive::new(4096 * 1024, 512);
+        let (mut fs, file) = create_fs(memory.clone(), FEATURE_DATA_CHECKSUMS);
+        assert_eq!(fs.scrub(false).unwrap(), vec![]);
+
+        let inode = fs.structure.read_inode(file).unwrap();
+        let block = inode.block_pointer(&fs.structure, 2).unwrap();
+        damage(&mut memory, block);
+        let bad_block = BadBlock {
+            inode: file,
+            block,
+            repaired: false,
+        };
+        assert_eq!(fs.scrub(false).unwrap(), vec![bad_block.clone()]);
+        // there is no other copy to repair it from
+        assert_eq!(fs.scrub(true).unwrap(), vec![bad_block]);
+
+        // the root directory comes first, then the file
+        let mut scrub = Scrub::new(false);
+        assert!(!fs.scrub_step(&mut scrub, 1).unwrap());
+        assert_eq!(scrub.bad_blocks, vec![]);
+        assert!(fs.scrub_step(&mut scrub, u64::MAX).unwrap());
+        assert_eq!(scrub.bad_blocks.len(), 1);
+
+        let (mut fs, _) = create_fs(MemoryDrive::new(4096 * 1024, 512), 0);
+        assert_eq!(fs.scrub(false).unwrap_err().errno(), libc::EINVAL);
+    }
+
+    #[test]
+    fn test_scrub_repairs_from_mirror() {
+        let mut first = MemoryDrive::new(4096 * 1024, 512);
+        let second = MemoryDrive::new(4096 * 1024, 512);
+        let mirror =
+            MirrorDrive::new(vec![Box::new(first.clone()), Box::new(second.clone())]).unwrap();
+        let (mut fs, file) = create_fs(mirror, FEATURE_DATA_CHECKSUMS);
+        let handle = fs.open(file, libc::O_RDONLY).unwrap();
+
+        let inode = fs.structure.read_inode(file).unwrap();
+        let block = inode.block_pointer(&fs.structure, 5).unwrap();
+        damage(&mut first, block);
+        assert_eq!(
+            fs.read(handle, 0, 8 * BLOCK_SIZE).unwrap_err().errno(),
+            libc::EIO
+        );
+
+        assert_eq!(
+            fs.scrub(true).unwrap(),
+            vec![BadBlock {
+                inode: file,
+                block,
+                repaired: true,
+            }]
+        );
+        assert_eq!(fs.scrub(false).unwrap(), vec![]);
+        assert_eq!(
+            fs.read(handle, 0, 8 * BLOCK_SIZE).unwrap(),
+            vec![7u8; 8 * BLOCK_SIZE]
+        );
+        assert_eq!(first.snapshot(), second.snapshot());
+    }
+}
@@ -0,0 +1,68 @@
+use crate::ops::meta::{GroupId, InodeType, Metadata, UserId};
+use crate::structure::inode::Inode;
+use crate::structure::Structure;
+use crate::util::error::Error;
+use std::ffi::{OsStr, OsString};
+
+pub struct Symlink {
+    pub inode: Inode<Metadata>,
+}
+
+impl Symlink {
+    /// Creates a symlink pointing at `target`. Short targets are stored inside the inode
+    /// itself, longer ones in data blocks like file contents.
+    pub fn new(
+        structure: &mut Structure<Metadata>,
+        target: &OsStr,
+        user_id: UserId,
+        group_id: GroupId,
+    ) -> Result<Symlink, Error> {
+        // permissions of symlinks are never checked, they are always rwxrwxrwx
+        let meta = Metadata::new(InodeType::Symlink, user_id, group_id, 0o777, 1, 0);
+        let target = target.as_encoded_bytes();
+        if target.len() <= Inode::<Metadata>::inline_capacity() {
+            let inode = structure.create_inline_inode(meta, target)?;
+            return Ok(Symlink { inode });
+        }
+
+        let mut inode = structure.create_inode(meta)?;
+        inode.set_data(structure, target.to_vec())?;
+        structure.write_inode(&mut inode)?;
+        Ok(Symlink { inode })
+    }
+
+    pub fn from_inode(inode: Inode<Metadata>) -> Symlink {
+        Symlink { inode }
+    }
+
+    pub fn get_target(&self, structure: &Structure<Metadata>) -> Result<OsString, Error> {
+        let data = self.inode.get_data(structure)?;
+        // TODO: same as directory entries, find a safe way to decode this
+        Ok(unsafe { OsString::from_encoded_bytes_unchecked(data) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structure::inode::BlockMapping;
+    use crate::testing::{create_structure, memory_drive};
+
+    #[test]
+    fn test_symlink_targets() {
+        let mut structure = create_structure::<Metadata>(memory_drive(), 512);
+
+        let short = OsString::from("../short/target");
+        let symlink = Symlink::new(&mut structure, &short, 0, 0).unwrap();
+        assert!(matches!(symlink.inode.mapping, BlockMapping::Inline(_)));
+        assert_eq!(symlink.inode.used_pointers, 0);
+        let symlink = Symlink::from_inode(structure.read_inode(symlink.inode.id.unwrap()).unwrap());
+        assert_eq!(symlink.get_target(&structure).unwrap(), short);
+
+        let long = OsString::from("long/".repeat(200));
+        let symlink = Symlink::new(&mut structure, &long, 0, 0).unwrap();
+        assert_eq!(symlink.inode.used_pointers, 2);
+        let symlink = Symlink::from_inode(structure.read_inode(symlink.inode.id.unwrap()).unwrap());
+        assert_eq!(symlink.get_target(&structure).unwrap(), long);
+    }
+}
@@ -1,6 +1,10 @@
 use crate::consts::BlockPointer;
 use crate::io::IO;
+use crate::util::checksum::{seal, CHECKSUM_SIZE};
+use crate::util::error::{Error, Location};
 
+/// One bit per block, set for blocks in use. Every block of the map ends in a checksum,
+/// the bits fill the rest of it.
 pub struct BlockMap {
     pub(crate) first_block: BlockPointer,
     pub(crate) last_block: BlockPointer,
@@ -10,7 +14,7 @@ pub struct BlockMap {
 impl BlockMap {
     pub fn new(first_block: BlockPointer, block_count: u64, block_size: usize) -> BlockMap {
         let data = BlockMap::create_data(block_count, block_size);
-        let last_block = first_block + data.len() as u64 / block_size as u64;
+        let last_block = first_block + data.len() as u64 / bytes_per_block(block_size) as u64;
         let mut map = BlockMap {
             first_block,
             last_block,
@@ -19,120 +23,212 @@ impl BlockMap {
         for i in 0..last_block + 1 {
             map.mark_used_mem(i)
         }
+        // the bitmap is padded to whole blocks, the bits past the end of the device must
+        // never be handed out
+        for i in block_count..map.data.len() as u64 * 8 {
+            map.data[(i / 8) as usize] |= 1 << (i % 8);
+        }
         map
     }
 
-    pub fn read(io: &IO, index: BlockPointer) -> BlockMap {
+    pub fn read(io: &IO, index: BlockPointer) -> Result<BlockMap, Error> {
+        let bytes_per_block = bytes_per_block(io.get_block_size());
         let mut data = BlockMap::create_data(io.get_block_count(), io.get_block_size());
-        let last_block = index + data.len() as u64 / io.get_block_size() as u64;
+        let last_block = index + (data.len() / bytes_per_block) as u64;
         for i in index..last_block {
-            let offset = (i as usize - index as usize) * io.get_block_size();
-            let limit = (i as usize - index as usize + 1) * io.get_block_size();
-            let block = io.read_block(i);
-            data[offset..limit].copy_from_slice(&block);
+            let offset = (i - index) as usize * bytes_per_block;
+            let block = io.read_block(i)?;
+            io.verify(&block, i, Location::Block(i))?;
+            data[offset..offset + bytes_per_block].copy_from_slice(&block[..bytes_per_block]);
         }
-        BlockMap {
+        Ok(BlockMap {
             first_block: index,
             last_block,
             data,
-        }
+        })
+    }
+
+    /// The number of blocks taken up by the map of a device with `block_count` blocks.
+    pub fn size_in_blocks(block_count: u64, block_size: usize) -> u64 {
+        block_count
+            .div_ceil(8)
+            .div_ceil(bytes_per_block(block_size) as u64)
     }
 
     fn create_data(block_count: u64, block_size: usize) -> Vec<u8> {
         let mut data = vec![0; block_count as usize / 8];
-        if (block_count as usize) % 8 != 0 {
+        if !(block_count as usize).is_multiple_of(8) {
             data.push(0);
         }
-        if data.len() % block_size != 0 {
-            data.append(&mut vec![0; block_size - (data.len() % block_size)]);
+        let bytes_per_block = bytes_per_block(block_size);
+        if !data.len().is_multiple_of(bytes_per_block) {
+            data.append(&mut vec![
+                0;
+                bytes_per_block - (data.len() % bytes_per_block)
+            ]);
         }
         data
     }
 
-    pub fn write_part(&self, io: &mut IO, including_index: BlockPointer) {
-        let block = (including_index / io.get_block_size() as u64 / 8) as usize;
-        let data = &self.data[block * io.get_block_size()..(block + 1) * io.get_block_size()];
-        io.write_block(self.first_block + block as u64, &data.to_vec());
+    pub fn write_part(&self, io: &mut IO, including_index: BlockPointer) -> Result<(), Error> {
+        let bits_per_block = bytes_per_block(io.get_block_size()) as u64 * 8;
+        self.write_block(io, including_index / bits_per_block)
     }
 
-    pub fn write_full(&self, io: &mut IO) {
-        for i in self.first_block..self.last_block {
-            let offset = (i as usize - self.first_block as usize) * io.get_block_size();
-            let limit = (i as usize - self.first_block as usize + 1) * io.get_block_size();
-            io.write_block(i, &self.data[offset..limit].to_vec());
+    pub fn write_full(&self, io: &mut IO) -> Result<(), Error> {
+        for i in 0..self.last_block - self.first_block {
+            self.write_block(io, i)?;
         }
+        Ok(())
+    }
+
+    // Writes the `n`th block of the map.
+    fn write_block(&self, io: &mut IO, n: u64) -> Result<(), Error> {
+        let bytes_per_block = bytes_per_block(io.get_block_size());
+        let offset = n as usize * bytes_per_block;
+        let mut block = self.data[offset..offset + bytes_per_block].to_vec();
+        block.resize(io.get_block_size(), 0);
+        seal(&mut block, self.first_block + n);
+        io.write_block(self.first_block + n, &block)
     }
 
-    pub fn allocate(&mut self, io: &mut IO) -> Option<u64> {
+    pub fn allocate(&mut self, io: &mut IO) -> Result<BlockPointer, Error> {
         for byte_index in 0..self.data.len() {
             let byte = self.data[byte_index];
             for j in 0..8 {
                 if byte & (1 << j) == 0 {
                     let result = byte_index as u64 * 8 + j;
-                    self.mark_used(io, result);
-                    return Some(result);
+                    self.mark_used(io, result)?;
+                    return Ok(result);
                 }
             }
         }
-        None
+        Err(Error::NoSpace)
     }
 
-    fn is_free(&self, index: BlockPointer) -> bool {
-        self.data[(index / 8) as usize] & (1 << (index % 8)) == 0
+    /// Allocates the first free block at or after `goal`, wrapping around at the end,
+    /// and extends the run with the blocks directly following it for as long as they
+    /// are free, up to `max_length` blocks in total.
+    pub fn allocate_run(
+        &mut self,
+        io: &mut IO,
+        goal: BlockPointer,
+        max_length: u64,
+    ) -> Result<(BlockPointer, u64), Error> {
+        let bits = self.data.len() as u64 * 8;
+        let start = (0..bits)
+            .map(|i| (goal + i) % bits)
+            .find(|index| self.is_free(*index))
+            .ok_or(Error::NoSpace)?;
+
+        let mut length = 1;
+        while length < max_length && start + length < bits && self.is_free(start + length) {
+            length += 1;
+        }
+
+        for index in start..start + length {
+            self.mark_used_mem(index);
+        }
+        let bits_per_block = bytes_per_block(io.get_block_size()) as u64 * 8;
+        for block in start / bits_per_block..=(start + length - 1) / bits_per_block {
+            self.write_block(io, block)?;
+        }
+        Ok((start, length))
+    }
+
+    /// Counts the free blocks, the padding past the end of the device is always used.
+    pub fn count_free(&self) -> u64 {
+        self.data.iter().map(|byte| byte.count_zeros() as u64).sum()
     }
 
-    fn is_used(&self, index: BlockPointer) -> bool {
-        !self.is_free(index)
+    pub(crate) fn is_free(&self, index: BlockPointer) -> bool {
+        self.data[(index / 8) as usize] & (1 << (index % 8)) == 0
     }
 
     fn mark_used_mem(&mut self, index: BlockPointer) {
         let byte_index = (index / 8) as usize;
         let bit_index = (index % 8) as usize;
-        println!("Marking Used: {} {} {}", index, byte_index, bit_index);
         self.data[byte_index] |= 1 << bit_index;
     }
 
-    pub(crate) fn mark_used(&mut self, io: &mut IO, index: BlockPointer) {
+    pub(crate) fn mark_used(&mut self, io: &mut IO, index: BlockPointer) -> Result<(), Error> {
         self.mark_used_mem(index);
-        self.write_part(io, index);
+        self.write_part(io, index)
     }
 
     fn mark_free_mem(&mut self, index: BlockPointer) {
         let byte_index = (index / 8) as usize;
         let bit_index = (index % 8) as usize;
-        println!("Marking Free: {} {} {}", index, byte_index, bit_index);
         self.data[byte_index] &= !(1 << bit_index);
     }
 
-    pub(crate) fn mark_free(&mut self, io: &mut IO, index: BlockPointer) {
+    pub(crate) fn mark_free(&mut self, io: &mut IO, index: BlockPointer) -> Result<(), Error> {
         self.mark_free_mem(index);
-        self.write_part(io, index);
+        self.write_part(io, index)
     }
 }
 
+// The bytes of the bitmap held by each block, in front of the checksum.
+fn bytes_per_block(block_size: usize) -> usize {
+    block_size - CHECKSUM_SIZE
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::driver::file_drive::FileDrive;
+    use crate::driver::memory_drive::MemoryDrive;
     use crate::io::IO;
 
     #[test]
     fn read_write() {
-        let drive = FileDrive::new("./test-images/blockmap_read_write.img", 1024 * 512, 512);
+        let drive = MemoryDrive::new(1024 * 512, 512);
         let mut io = IO::new(drive, 1024);
         let blockmap = super::BlockMap::new(1, 1024, 1024);
-        blockmap.write_full(&mut io);
-        assert_eq!(blockmap.data, super::BlockMap::read(&io, 1).data)
+        blockmap.write_full(&mut io).unwrap();
+        assert_eq!(blockmap.data, super::BlockMap::read(&io, 1).unwrap().data)
     }
 
     #[test]
     fn allocate() {
-        let drive = FileDrive::new("./test-images/blockmap_allocate.img", 1024 * 512, 512);
+        let drive = MemoryDrive::new(1024 * 512, 512);
         let mut io = IO::new(drive, 1024);
         let mut blockmap = super::BlockMap::new(1, 1024, 1024);
+        let free = blockmap.count_free();
+        assert_eq!(free, 1024 - 3);
         let index = blockmap.allocate(&mut io).unwrap();
-        assert_eq!(blockmap.is_used(index), true);
-        blockmap.mark_free(&mut io, index);
-        assert_eq!(blockmap.is_free(index), true);
-        assert_eq!(blockmap.data, super::BlockMap::read(&io, 1).data)
+        assert!(!blockmap.is_free(index));
+        assert_eq!(blockmap.count_free(), free - 1);
+        blockmap.mark_free(&mut io, index).unwrap();
+        assert!(blockmap.is_free(index));
+        assert_eq!(blockmap.count_free(), free);
+        assert_eq!(blockmap.data, super::BlockMap::read(&io, 1).unwrap().data)
+    }
+
+    #[test]
+    fn allocate_run() {
+        let drive = MemoryDrive::new(1024 * 512, 512);
+        let mut io = IO::new(drive, 1024);
+        let mut blockmap = super::BlockMap::new(1, 512, 1024);
+        blockmap.mark_used(&mut io, 104).unwrap();
+
+        assert_eq!(blockmap.allocate_run(&mut io, 100, 10).unwrap(), (100, 4));
+        assert_eq!(blockmap.allocate_run(&mut io, 100, 10).unwrap(), (105, 10));
+        // past the end of the device the search wraps around to the first free block
+        assert_eq!(blockmap.allocate_run(&mut io, 511, 10).unwrap(), (511, 1));
+        assert_eq!(blockmap.allocate_run(&mut io, 511, 10).unwrap(), (3, 10));
+        assert_eq!(blockmap.data, super::BlockMap::read(&io, 1).unwrap().data)
+    }
+
+    #[test]
+    fn allocate_full() {
+        let drive = MemoryDrive::new(1024 * 512, 512);
+        let mut io = IO::new(drive, 1024);
+        let mut blockmap = super::BlockMap::new(1, 8, 1024);
+        while blockmap.allocate(&mut io).is_ok() {}
+        assert_eq!(
+            blockmap.allocate(&mut io).unwrap_err().errno(),
+            libc::ENOSPC
+        );
+        let error = blockmap.allocate_run(&mut io, 0, 1).unwrap_err();
+        assert_eq!(error.errno(), libc::ENOSPC);
     }
 }
@@ -0,0 +1,103 @@
+use crate::consts::BlockPointer;
+use crate::io::IO;
+use crate::util::checksum::{seal, CHECKSUM_SIZE};
+use crate::util::error::{Error, Location};
+
+const ENTRY_SIZE: usize = size_of::<u32>();
+
+/// The checksums of the data blocks, one entry per block of the device. An entry of 0
+/// means nothing was recorded for the block. Like the bitmaps, every block of the table
+/// ends in a checksum of its own.
+pub struct ChecksumTable {
+    pub(crate) first_block: BlockPointer,
+    pub(crate) block_count: u64,
+}
+
+impl ChecksumTable {
+    /// Writes an empty table for a device of `device_blocks` blocks.
+    pub fn create(
+        io: &mut IO,
+        first_block: BlockPointer,
+        device_blocks: u64,
+    ) -> Result<ChecksumTable, Error> {
+        let table = ChecksumTable::new(first_block, device_blocks, io.get_block_size());
+        for i in first_block..first_block + table.block_count {
+            let mut block = vec![0; io.get_block_size()];
+            seal(&mut block, i);
+            io.write_block(i, &block)?;
+        }
+        Ok(table)
+    }
+
+    pub fn new(first_block: BlockPointer, device_blocks: u64, block_size: usize) -> ChecksumTable {
+        ChecksumTable {
+            first_block,
+            block_count: ChecksumTable::size_in_blocks(device_blocks, block_size),
+        }
+    }
+
+    /// The number of blocks taken up by the table of a device with `device_blocks` blocks.
+    pub fn size_in_blocks(device_blocks: u64, block_size: usize) -> u64 {
+        device_blocks.div_ceil(entries_per_block(block_size) as u64)
+    }
+
+    /// The checksum recorded for block `index`, 0 if there is none.
+    pub fn get(&self, io: &IO, index: BlockPointer) -> Result<u32, Error> {
+        let (table_block, offset) = self.position(index, io.get_block_size());
+        let block = io.read_block(table_block)?;
+        io.verify(&block, table_block, Location::Block(table_block))?;
+        Ok(u32::from_le_bytes(
+            block[offset..offset + ENTRY_SIZE].try_into().unwrap(),
+        ))
+    }
+
+    pub fn set(&self, io: &mut IO, index: BlockPointer, checksum: u32) -> Result<(), Error> {
+        let (table_block, offset) = self.position(index, io.get_block_size());
+        let mut block = io.read_block(table_block)?;
+        io.verify(&block, table_block, Location::Block(table_block))?;
+        block[offset..offset + ENTRY_SIZE].copy_from_slice(&checksum.to_le_bytes());
+        seal(&mut block, table_block);
+        io.write_block(table_block, &block)
+    }
+
+    // The table block holding the entry of `index`, and the entry's offset in it.
+    fn position(&self, index: BlockPointer, block_size: usize) -> (BlockPointer, usize) {
+        let entries = entries_per_block(block_size) as u64;
+        (
+            self.first_block + index / entries,
+            (index % entries) as usize * ENTRY_SIZE,
+        )
+    }
+}
+
+fn entries_per_block(block_size: usize) -> usize {
+    (block_size - CHECKSUM_SIZE) / ENTRY_SIZE
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::driver::memory_drive::MemoryDrive;
+    use crate::io::IO;
+    use crate::structure::checksum_table::ChecksumTable;
+
+    #[test]
+    fn read_write_checksums() {
+        let drive = MemoryDrive::new(1024 * 512, 512);
+        let mut io = IO::new(drive, 512);
+        // 127 entries fit into each block
+        let table = ChecksumTable::create(&mut io, 10, 1024).unwrap();
+        assert_eq!(table.block_count, 9);
+
+        table.set(&mut io, 0, 0x1234).unwrap();
+        table.set(&mut io, 127, 0xabcd).unwrap();
+        assert_eq!(table.get(&io, 0).unwrap(), 0x1234);
+        assert_eq!(table.get(&io, 127).unwrap(), 0xabcd);
+        assert_eq!(table.get(&io, 1023).unwrap(), 0);
+        assert_eq!(io.read_block(11).unwrap()[..4], 0xabcdu32.to_le_bytes());
+
+        let mut block = io.read_block(11).unwrap();
+        block[1] ^= 1;
+        io.write_block(11, &block).unwrap();
+        assert_eq!(table.get(&io, 127).unwrap_err().errno(), libc::EUCLEAN);
+    }
+}
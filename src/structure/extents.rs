@@ -0,0 +1,398 @@
+use crate::consts::BlockPointer;
+use crate::structure::inode::BlockUse;
+use crate::structure::Structure;
+use crate::util::error::{Error, Location};
+use crate::util::serializable::{ByteSerializable, KnownSize};
+use std::mem::size_of;
+
+const EXTENT_MAGIC: u16 = 0xe47e;
+const HEADER_SIZE: usize = 4 * size_of::<u16>();
+const ENTRY_SIZE: usize = 2 * size_of::<u32>() + size_of::<BlockPointer>();
+// number of entries that fit into the pointer area of an inode
+const INLINE_ENTRIES: usize = 7;
+
+/// A run of `length` physical blocks starting at `start`, mapped to the logical blocks
+/// starting at `logical`. In index nodes `start` points to the child node instead and
+/// `length` is unused.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Extent {
+    pub(crate) logical: u32,
+    pub(crate) length: u32,
+    pub(crate) start: BlockPointer,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+struct ExtentNode {
+    // 0 for leaves holding extents, otherwise the number of index levels below
+    depth: u16,
+    entries: Vec<Extent>,
+}
+
+impl ExtentNode {
+    fn to_bytes(&self, size: usize) -> Vec<u8> {
+        let mut bytes = Vec::<u8>::with_capacity(size);
+        bytes.extend_from_slice(&EXTENT_MAGIC.to_le_bytes());
+        bytes.extend_from_slice(&(self.entries.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&self.depth.to_le_bytes());
+        bytes.extend_from_slice(&0u16.to_le_bytes());
+        for entry in &self.entries {
+            bytes.extend_from_slice(&entry.logical.to_le_bytes());
+            bytes.extend_from_slice(&entry.length.to_le_bytes());
+            bytes.extend_from_slice(&entry.start.to_le_bytes());
+        }
+        bytes.resize(size, 0);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<ExtentNode, Error> {
+        if u16::from_le_bytes([bytes[0], bytes[1]]) != EXTENT_MAGIC {
+            return Err(Error::new("Invalid extent node", libc::EUCLEAN));
+        }
+
+        let count = u16::from_le_bytes([bytes[2], bytes[3]]) as usize;
+        let depth = u16::from_le_bytes([bytes[4], bytes[5]]);
+        if count > ExtentNode::capacity(bytes.len()) {
+            return Err(Error::new("Invalid extent node", libc::EUCLEAN));
+        }
+        let entries = bytes[HEADER_SIZE..HEADER_SIZE + count * ENTRY_SIZE]
+            .chunks(ENTRY_SIZE)
+            .map(|entry| Extent {
+                logical: u32::from_le_bytes(entry[0..4].try_into().unwrap()),
+                length: u32::from_le_bytes(entry[4..8].try_into().unwrap()),
+                start: BlockPointer::from_le_bytes(entry[8..16].try_into().unwrap()),
+            })
+            .collect();
+        Ok(ExtentNode { depth, entries })
+    }
+
+    fn capacity(size: usize) -> usize {
+        (size - HEADER_SIZE) / ENTRY_SIZE
+    }
+}
+
+/// Extent based block map. The root node lives inside the inode; once its entries
+/// overflow they are pushed down into blocks and the root turns into an index node.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ExtentTree {
+    root: ExtentNode,
+}
+
+impl ExtentTree {
+    pub fn new() -> ExtentTree {
+        ExtentTree {
+            root: ExtentNode {
+                depth: 0,
+                entries: Vec::new(),
+            },
+        }
+    }
+
+    pub fn size_on_disk() -> usize {
+        HEADER_SIZE + INLINE_ENTRIES * ENTRY_SIZE
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.root.to_bytes(ExtentTree::size_on_disk())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<ExtentTree, Error> {
+        Ok(ExtentTree {
+            root: ExtentNode::from_bytes(bytes)?,
+        })
+    }
+
+    /// The largest number of data blocks that can be addressed.
+    pub fn max_blocks() -> u64 {
+        u32::MAX as u64
+    }
+
+    /// Resolves the `index`th data block.
+    pub fn get<META: ByteSerializable + KnownSize>(
+        &self,
+        structure: &Structure<META>,
+        index: u64,
+    ) -> Result<BlockPointer, Error> {
+        ExtentTree::find(structure, &self.root, index)
+    }
+
+    /// Maps the `length` blocks starting at `start` to the logical blocks starting at
+    /// `logical`, which must directly follow the last mapped block. Runs continuing the
+    /// last extent on disk are merged into it.
+    pub fn append<META: ByteSerializable + KnownSize>(
+        &mut self,
+        structure: &mut Structure<META>,
+        logical: u64,
+        start: BlockPointer,
+        length: u64,
+    ) -> Result<(), Error> {
+        let extent = Extent {
+            logical: logical as u32,
+            length: length as u32,
+            start,
+        };
+
+        if let Some(sibling) =
+            ExtentTree::insert(structure, &mut self.root, extent, INLINE_ENTRIES)?
+        {
+            // the root is full: move its entries into a block of their own and grow the
+            // tree by one level
+            let child = ExtentTree::write_new_node(structure, &self.root)?;
+            self.root = ExtentNode {
+                depth: self.root.depth + 1,
+                entries: vec![child, sibling],
+            };
+        }
+        Ok(())
+    }
+
+    /// Unmaps the last data block. Tree nodes left empty by this are freed.
+    pub fn remove_last<META: ByteSerializable + KnownSize>(
+        &mut self,
+        structure: &mut Structure<META>,
+    ) -> Result<(), Error> {
+        ExtentTree::remove(structure, &mut self.root)?;
+        if self.root.entries.is_empty() {
+            self.root.depth = 0;
+        }
+        Ok(())
+    }
+
+    /// Calls `visit` for every block the tree refers to. Tree nodes are only read if
+    /// `visit` accepts them.
+    pub fn walk<META: ByteSerializable + KnownSize>(
+        &self,
+        structure: &Structure<META>,
+        visit: &mut dyn FnMut(BlockUse) -> bool,
+    ) -> Result<(), Error> {
+        ExtentTree::walk_node(structure, &self.root, visit)
+    }
+
+    fn walk_node<META: ByteSerializable + KnownSize>(
+        structure: &Structure<META>,
+        node: &ExtentNode,
+        visit: &mut dyn FnMut(BlockUse) -> bool,
+    ) -> Result<(), Error> {
+        for entry in &node.entries {
+            if node.depth == 0 {
+                for i in 0..entry.length as u64 {
+                    visit(BlockUse::Data(entry.logical as u64 + i, entry.start + i));
+                }
+            } else if visit(BlockUse::Node(entry.start)) {
+                let child = ExtentTree::read_node(structure, entry.start)?;
+                ExtentTree::walk_node(structure, &child, visit)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn find<META: ByteSerializable + KnownSize>(
+        structure: &Structure<META>,
+        node: &ExtentNode,
+        index: u64,
+    ) -> Result<BlockPointer, Error> {
+        let unmapped = || Error::new(&format!("Block {} is not mapped", index), libc::EUCLEAN);
+        let entry = node
+            .entries
+            .iter()
+            .rev()
+            .find(|entry| entry.logical as u64 <= index)
+            .ok_or_else(unmapped)?;
+
+        if node.depth == 0 {
+            if index >= entry.logical as u64 + entry.length as u64 {
+                return Err(unmapped());
+            }
+            Ok(entry.start + (index - entry.logical as u64))
+        } else {
+            let child = ExtentTree::read_node(structure, entry.start)?;
+            ExtentTree::find(structure, &child, index)
+        }
+    }
+
+    // Returns an index entry for a new sibling node if `node` had no room left.
+    fn insert<META: ByteSerializable + KnownSize>(
+        structure: &mut Structure<META>,
+        node: &mut ExtentNode,
+        extent: Extent,
+        capacity: usize,
+    ) -> Result<Option<Extent>, Error> {
+        if node.depth == 0 {
+            if let Some(last) = node.entries.last_mut() {
+                let contiguous = last.start + last.length as u64 == extent.start
+                    && last.logical + last.length == extent.logical;
+                if contiguous && last.length.checked_add(extent.length).is_some() {
+                    last.length += extent.length;
+                    return Ok(None);
+                }
+            }
+
+            if node.entries.len() < capacity {
+                node.entries.push(extent);
+                return Ok(None);
+            }
+
+            let leaf = ExtentNode {
+                depth: 0,
+                entries: vec![extent],
+            };
+            return Ok(Some(ExtentTree::write_new_node(structure, &leaf)?));
+        }
+
+        let child = node.entries.last().unwrap().start;
+        let mut child_node = ExtentTree::read_node(structure, child)?;
+        let block_capacity = ExtentNode::capacity(structure.get_block_size());
+        let sibling = match ExtentTree::insert(structure, &mut child_node, extent, block_capacity)?
+        {
+            None => {
+                ExtentTree::write_node(structure, child, &child_node)?;
+                return Ok(None);
+            }
+            Some(sibling) => sibling,
+        };
+
+        if node.entries.len() < capacity {
+            node.entries.push(sibling);
+            Ok(None)
+        } else {
+            let index = ExtentNode {
+                depth: node.depth,
+                entries: vec![sibling],
+            };
+            Ok(Some(ExtentTree::write_new_node(structure, &index)?))
+        }
+    }
+
+    fn remove<META: ByteSerializable + KnownSize>(
+        structure: &mut Structure<META>,
+        node: &mut ExtentNode,
+    ) -> Result<(), Error> {
+        let empty = || Error::new("Extent tree is empty", libc::EUCLEAN);
+        if node.depth == 0 {
+            let last = node.entries.last_mut().ok_or_else(empty)?;
+            last.length -= 1;
+            if last.length == 0 {
+                node.entries.pop();
+            }
+            return Ok(());
+        }
+
+        let child = node.entries.last().ok_or_else(empty)?.start;
+        let mut child_node = ExtentTree::read_node(structure, child)?;
+        ExtentTree::remove(structure, &mut child_node)?;
+        if child_node.entries.is_empty() {
+            structure.free_block(child)?;
+            node.entries.pop();
+            Ok(())
+        } else {
+            ExtentTree::write_node(structure, child, &child_node)
+        }
+    }
+
+    fn read_node<META: ByteSerializable + KnownSize>(
+        structure: &Structure<META>,
+        block: BlockPointer,
+    ) -> Result<ExtentNode, Error> {
+        ExtentNode::from_bytes(&structure.read_block(block)?)
+            .map_err(|error| error.at(Location::Block(block)))
+    }
+
+    fn write_node<META: ByteSerializable + KnownSize>(
+        structure: &mut Structure<META>,
+        block: BlockPointer,
+        node: &ExtentNode,
+    ) -> Result<(), Error> {
+        let bytes = node.to_bytes(structure.get_block_size());
+        structure.write_block(block, &bytes)
+    }
+
+    fn write_new_node<META: ByteSerializable + KnownSize>(
+        structure: &mut Structure<META>,
+        node: &ExtentNode,
+    ) -> Result<Extent, Error> {
+        let block = structure.allocate_block()?;
+        ExtentTree::write_node(structure, block, node)?;
+        Ok(Extent {
+            logical: node.entries[0].logical,
+            length: 0,
+            start: block,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structure::pointers::BlockPointers;
+    use crate::testing::{create_structure, memory_drive};
+
+    struct DummyMeta;
+
+    impl KnownSize for DummyMeta {
+        fn size_on_disk() -> usize {
+            0
+        }
+    }
+
+    impl ByteSerializable for DummyMeta {
+        fn to_bytes(&self) -> Vec<u8> {
+            Vec::new()
+        }
+
+        fn from_bytes(_bytes: &[u8]) -> Result<Self, Error> {
+            Ok(DummyMeta)
+        }
+    }
+
+    #[test]
+    fn test_extent_tree_round_trip() {
+        let mut tree = ExtentTree::new();
+        tree.root.entries.push(Extent {
+            logical: 0,
+            length: 10,
+            start: 42,
+        });
+        let bytes = tree.to_bytes();
+        assert_eq!(bytes.len(), BlockPointers::size_on_disk());
+        assert_eq!(ExtentTree::from_bytes(&bytes).unwrap(), tree);
+    }
+
+    #[test]
+    fn test_extent_tree_merges_contiguous_runs() {
+        let mut structure = create_structure::<DummyMeta>(memory_drive(), 512);
+
+        let mut tree = ExtentTree::new();
+        tree.append(&mut structure, 0, 1000, 4).unwrap();
+        tree.append(&mut structure, 4, 1004, 2).unwrap();
+        tree.append(&mut structure, 6, 1100, 1).unwrap();
+        assert_eq!(tree.root.entries.len(), 2);
+        assert_eq!(tree.get(&structure, 5).unwrap(), 1005);
+        assert_eq!(tree.get(&structure, 6).unwrap(), 1100);
+
+        tree.remove_last(&mut structure).unwrap();
+        assert_eq!(tree.root.entries.len(), 1);
+        assert_eq!(tree.root.entries[0].length, 6);
+    }
+
+    #[test]
+    fn test_extent_tree_grows_and_shrinks() {
+        let mut structure = create_structure::<DummyMeta>(memory_drive(), 512);
+
+        // every other block, so that no two extents can be merged
+        let count = 200;
+        let mut tree = ExtentTree::new();
+        for i in 0..count {
+            tree.append(&mut structure, i, 1500 + i * 2, 1).unwrap();
+        }
+        assert!(tree.root.depth >= 1);
+        for i in 0..count {
+            assert_eq!(tree.get(&structure, i).unwrap(), 1500 + i * 2);
+        }
+
+        let node = tree.root.entries[0].start;
+        for _ in 0..count {
+            tree.remove_last(&mut structure).unwrap();
+        }
+        assert_eq!(tree, ExtentTree::new());
+        assert!(structure.block_map.is_free(node));
+    }
+}
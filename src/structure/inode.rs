@@ -1,18 +1,38 @@
-use crate::consts::DirectPointers;
-use crate::consts::{BlockPointer, DIRECT_POINTERS};
+use crate::consts::BlockPointer;
+use crate::structure::extents::ExtentTree;
+use crate::structure::pointers::BlockPointers;
 use crate::structure::Structure;
+use crate::util::error::{Error, Location};
 use crate::util::serializable::{ByteSerializable, KnownSize};
 use std::mem::size_of;
 
-const DATA_SIZE: usize = 96;
-const NULL_POINTER: BlockPointer = 0;
+const INODE_FLAG_EXTENTS: u32 = 0x1;
+const INODE_FLAG_INLINE: u32 = 0x2;
 
 pub type InodeId = u64;
 
+/// How an inode finds its data blocks. The on-disk representation of each fills
+/// the same area of the inode. Small, fixed data such as short symlink targets can be
+/// kept inline in that area instead, without any blocks at all.
+#[derive(Debug, PartialEq, Clone)]
+pub enum BlockMapping {
+    Pointers(BlockPointers),
+    Extents(ExtentTree),
+    Inline(Vec<u8>),
+}
+
+/// A block referenced by an inode's block map: the `n`th data block, or a pointer
+/// block or extent tree node needed to find data blocks.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BlockUse {
+    Data(u64, BlockPointer),
+    Node(BlockPointer),
+}
+
 // TODO: probably doesn't need public members
 pub struct Inode<META: ByteSerializable + KnownSize> {
     pub(crate) id: Option<InodeId>,
-    pub(crate) pointers: DirectPointers,
+    pub(crate) mapping: BlockMapping,
     pub(crate) size: u64,
     pub(crate) meta: META,
     pub(crate) used_pointers: usize,
@@ -21,9 +41,34 @@ pub struct Inode<META: ByteSerializable + KnownSize> {
 
 impl<META: ByteSerializable + KnownSize> Inode<META> {
     pub fn new(meta: META) -> Inode<META> {
+        Inode::with_mapping(meta, BlockMapping::Pointers(BlockPointers::new()))
+    }
+
+    pub fn with_extents(meta: META) -> Inode<META> {
+        Inode::with_mapping(meta, BlockMapping::Extents(ExtentTree::new()))
+    }
+
+    /// Creates an inode holding `data` in place of its block map. Inline data cannot
+    /// be changed afterwards.
+    pub fn with_inline_data(meta: META, data: &[u8]) -> Result<Inode<META>, Error> {
+        if data.len() > Inode::<META>::inline_capacity() {
+            return Err(Error::new(
+                &format!(
+                    "Inline data cannot be larger than {} bytes",
+                    Inode::<META>::inline_capacity()
+                ),
+                libc::EFBIG,
+            ));
+        }
+        let mut inode = Inode::with_mapping(meta, BlockMapping::Inline(data.to_vec()));
+        inode.size = data.len() as u64;
+        Ok(inode)
+    }
+
+    fn with_mapping(meta: META, mapping: BlockMapping) -> Inode<META> {
         Inode {
             id: None,
-            pointers: [NULL_POINTER; 12],
+            mapping,
             size: 0,
             used_pointers: 0,
             allocated_size: 0,
@@ -38,160 +83,383 @@ impl<META: ByteSerializable + KnownSize> Inode<META> {
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::<u8>::new();
         bytes.extend_from_slice(&self.size.to_le_bytes());
-        bytes.extend_from_slice(Inode::<META>::pointers_to_bytes(self.pointers).as_slice());
+        match &self.mapping {
+            BlockMapping::Pointers(pointers) => {
+                bytes.extend_from_slice(&0u32.to_le_bytes());
+                bytes.extend_from_slice(pointers.to_bytes().as_slice());
+            }
+            BlockMapping::Extents(extents) => {
+                bytes.extend_from_slice(&INODE_FLAG_EXTENTS.to_le_bytes());
+                bytes.extend_from_slice(extents.to_bytes().as_slice());
+            }
+            BlockMapping::Inline(data) => {
+                bytes.extend_from_slice(&INODE_FLAG_INLINE.to_le_bytes());
+                bytes.extend_from_slice(data);
+                bytes.resize(
+                    bytes.len() + Inode::<META>::inline_capacity() - data.len(),
+                    0,
+                );
+            }
+        }
         bytes.extend_from_slice(&self.meta.to_bytes());
         bytes
     }
 
-    pub fn from_bytes(id: InodeId, bytes: &Vec<u8>, block_size: usize) -> Self {
-        let (size_bytes, remainder) = bytes.as_slice().split_at(size_of::<u64>());
-        let (pointer_bytes, meta_bytes) = remainder.split_at(DATA_SIZE);
+    pub fn from_bytes(id: InodeId, bytes: &[u8], block_size: usize) -> Result<Self, Error> {
+        let (size_bytes, remainder) = bytes.split_at(size_of::<u64>());
+        let (flag_bytes, remainder) = remainder.split_at(size_of::<u32>());
+        let (mapping_bytes, meta_bytes) = remainder.split_at(BlockPointers::size_on_disk());
         let size = u64::from_le_bytes(size_bytes.try_into().unwrap());
-        let pointers = Inode::<META>::bytes_to_pointers(pointer_bytes);
-        let meta = META::from_bytes(meta_bytes);
+        let flags = u32::from_le_bytes(flag_bytes.try_into().unwrap());
+        let meta = META::from_bytes(meta_bytes).map_err(|error| error.at(Location::Inode(id)))?;
+        let mut used_pointers = Inode::<META>::count_used_pointers(size, block_size);
+        let mapping = if flags & INODE_FLAG_INLINE != 0 {
+            if size > Inode::<META>::inline_capacity() as u64 {
+                return Err(Error::corrupted(
+                    &format!("Inode {} has invalid inline size {}", id, size),
+                    Location::Inode(id),
+                ));
+            }
+            used_pointers = 0;
+            BlockMapping::Inline(mapping_bytes[..size as usize].to_vec())
+        } else if flags & INODE_FLAG_EXTENTS != 0 {
+            BlockMapping::Extents(
+                ExtentTree::from_bytes(mapping_bytes)
+                    .map_err(|error| error.at(Location::Inode(id)))?,
+            )
+        } else {
+            BlockMapping::Pointers(BlockPointers::from_bytes(mapping_bytes))
+        };
 
-        Inode {
+        Ok(Inode {
             id: Some(id),
             meta,
             size,
-            pointers,
-            used_pointers: Inode::<META>::count_used_pointers(&pointers),
-            allocated_size: Inode::<META>::calculate_allocated_size(
-                Inode::<META>::count_used_pointers(&pointers),
-                block_size,
-            ),
-        }
+            mapping,
+            used_pointers,
+            allocated_size: Inode::<META>::calculate_allocated_size(used_pointers, block_size),
+        })
     }
 
     #[inline]
     pub fn size_on_disk() -> usize {
-        size_of::<u64>() + size_of::<DirectPointers>() + META::size_on_disk()
+        size_of::<u64>() + size_of::<u32>() + BlockPointers::size_on_disk() + META::size_on_disk()
+    }
+
+    /// The number of bytes that fit inline, in place of the block map.
+    pub fn inline_capacity() -> usize {
+        BlockPointers::size_on_disk()
+    }
+
+    /// The largest number of data blocks this inode can address.
+    pub fn max_blocks(&self, block_size: usize) -> u64 {
+        match self.mapping {
+            BlockMapping::Pointers(_) => BlockPointers::max_blocks(block_size),
+            BlockMapping::Extents(_) => ExtentTree::max_blocks(),
+            BlockMapping::Inline(_) => 0,
+        }
     }
 
     // TODO: chunks
-    pub fn set_data(&mut self, structure: &mut Structure<META>, data: Vec<u8>) {
-        self.ensure_size(structure, data.len() as u64);
+    pub fn set_data(
+        &mut self,
+        structure: &mut Structure<META>,
+        data: Vec<u8>,
+    ) -> Result<(), Error> {
+        self.ensure_size(structure, data.len() as u64)?;
         let chunks = data.chunks(structure.get_block_size());
         for (i, chunk) in chunks.enumerate() {
-            let block = self.pointers[i];
+            let block = self.block_pointer(structure, i)?;
             let mut data = chunk.to_vec();
             data.resize(structure.get_block_size(), 0);
-            structure.io.write_block(block, &data);
+            structure.write_block(block, &data)?;
+            structure.record_checksum(block, &data)?;
         }
+        Ok(())
     }
 
     // TODO: chunks
-    pub fn get_data(&self, structure: &Structure<META>) -> Vec<u8> {
+    pub fn get_data(&self, structure: &Structure<META>) -> Result<Vec<u8>, Error> {
+        if let BlockMapping::Inline(data) = &self.mapping {
+            return Ok(data[0..self.size as usize].to_vec());
+        }
+
         let mut result = Vec::<u8>::new();
 
         for i in 0..self.used_pointers {
-            result.append(&mut structure.read_block(self.pointers[i]));
+            result.append(&mut structure.read_data_block(self.block_pointer(structure, i)?)?);
         }
 
-        result[0..self.size as usize].to_vec()
+        result.truncate(self.size as usize);
+        Ok(result)
     }
 
-    pub fn append_data(&mut self, data: Vec<u8>) {
-        unimplemented!();
+    /// Reads up to `length` bytes starting at `offset`, touching only the blocks that
+    /// cover the requested range. Reads past the end of the data are cut short.
+    pub fn read_at(
+        &self,
+        structure: &Structure<META>,
+        offset: u64,
+        length: usize,
+    ) -> Result<Vec<u8>, Error> {
+        let end = u64::min(offset.saturating_add(length as u64), self.size);
+        if offset >= end {
+            return Ok(Vec::new());
+        }
+        if let BlockMapping::Inline(data) = &self.mapping {
+            return Ok(data[offset as usize..end as usize].to_vec());
+        }
+
+        let block_size = structure.get_block_size() as u64;
+        let mut result = Vec::<u8>::with_capacity((end - offset) as usize);
+        for index in offset / block_size..end.div_ceil(block_size) {
+            let block_start = index * block_size;
+            let from = u64::max(offset, block_start) - block_start;
+            let to = u64::min(end, block_start + block_size) - block_start;
+            let block =
+                structure.read_data_block(self.block_pointer(structure, index as usize)?)?;
+            result.extend_from_slice(&block[from as usize..to as usize]);
+        }
+        Ok(result)
     }
 
-    fn count_used_pointers(pointers: &DirectPointers) -> usize {
-        let mut count = 0;
+    /// Writes `data` at `offset`, allocating blocks as needed. Only blocks overlapping
+    /// the range are written; a gap between the old end of the data and `offset` is
+    /// zeroed first. Unlike `set_data`, the blocks are written as file contents.
+    pub fn write_at(
+        &mut self,
+        structure: &mut Structure<META>,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let old_size = self.size;
+        let end = offset
+            .checked_add(data.len() as u64)
+            .ok_or_else(|| Error::new("File too large", libc::EFBIG))?;
+        if end > self.size {
+            self.ensure_size(structure, end)?;
+        }
+        if offset > old_size {
+            self.zero_range(structure, old_size, offset)?;
+        }
+
+        let block_size = structure.get_block_size() as u64;
+        for index in offset / block_size..end.div_ceil(block_size) {
+            let block_start = index * block_size;
+            let from = u64::max(offset, block_start);
+            let to = u64::min(end, block_start + block_size);
+            let pointer = self.block_pointer(structure, index as usize)?;
 
-        for i in 0..DIRECT_POINTERS {
-            if pointers[i] != NULL_POINTER {
-                count += 1;
+            let mut block = if to - from == block_size {
+                vec![0; block_size as usize]
             } else {
-                break;
-            }
+                structure.read_data_block(pointer)?
+            };
+            block[(from - block_start) as usize..(to - block_start) as usize]
+                .copy_from_slice(&data[(from - offset) as usize..(to - offset) as usize]);
+            structure.write_data_block(pointer, &block)?;
         }
+        Ok(())
+    }
 
-        count
+    pub fn append_data(
+        &mut self,
+        structure: &mut Structure<META>,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        self.write_at(structure, self.size, data)
     }
 
-    fn calculate_allocated_size(used_pointers: usize, block_size: usize) -> u64 {
-        used_pointers as u64 * block_size as u64
+    /// Resizes the data to `size` bytes, freeing blocks when shrinking and zero filling
+    /// when growing.
+    pub fn truncate(&mut self, structure: &mut Structure<META>, size: u64) -> Result<(), Error> {
+        let old_size = self.size;
+        self.ensure_size(structure, size)?;
+        if size > old_size {
+            self.zero_range(structure, old_size, size)?;
+        }
+        Ok(())
     }
 
-    fn bytes_to_pointers(data: &[u8]) -> DirectPointers {
-        let mut pointers = [NULL_POINTER; 12];
-        let data = data;
-        for i in 0..DIRECT_POINTERS {
-            let mut bytes = [0u8; 8];
-            bytes.copy_from_slice(&data[i * 8..8 + i * 8]);
-            pointers[i] = u64::from_le_bytes(bytes);
+    // Bytes past `size` are never cleared when shrinking and fresh blocks may hold
+    // whatever a previous owner left behind, so growing has to zero them explicitly.
+    fn zero_range(
+        &mut self,
+        structure: &mut Structure<META>,
+        from: u64,
+        to: u64,
+    ) -> Result<(), Error> {
+        let block_size = structure.get_block_size() as u64;
+        for index in from / block_size..to.div_ceil(block_size) {
+            let block_start = index * block_size;
+            let start = u64::max(from, block_start) - block_start;
+            let end = u64::min(to, block_start + block_size) - block_start;
+            let pointer = self.block_pointer(structure, index as usize)?;
+
+            let mut block = if end - start == block_size {
+                vec![0; block_size as usize]
+            } else {
+                structure.read_data_block(pointer)?
+            };
+            block[start as usize..end as usize].fill(0);
+            structure.write_data_block(pointer, &block)?;
         }
-        pointers
+        Ok(())
     }
 
-    fn pointers_to_bytes(pointers: DirectPointers) -> Vec<u8> {
-        let mut data = Vec::<u8>::new();
-        for i in 0..12 {
-            data.extend_from_slice(&pointers[i].to_le_bytes());
+    /// Calls `visit` for every block the block map refers to. Map blocks are only read
+    /// if `visit` accepts them, so damaged pointers can be kept from being followed.
+    pub fn walk_blocks(
+        &self,
+        structure: &Structure<META>,
+        visit: &mut dyn FnMut(BlockUse) -> bool,
+    ) -> Result<(), Error> {
+        match &self.mapping {
+            BlockMapping::Pointers(pointers) => pointers.walk(structure, visit),
+            BlockMapping::Extents(extents) => extents.walk(structure, visit),
+            BlockMapping::Inline(_) => Ok(()),
         }
-        data
     }
 
-    fn ensure_size(&mut self, structure: &mut Structure<META>, new_size: u64) {
-        if new_size > (structure.get_block_size() as u64 * DIRECT_POINTERS as u64) {
-            panic!(
-                "File cannot be larger than {} bytes",
-                structure.get_block_size() * DIRECT_POINTERS
-            );
+    /// Replaces the block map with a fresh one holding exactly `blocks` as the data
+    /// blocks, and cuts the size down to fit. Blocks of the old map are not freed.
+    pub fn remap(
+        &mut self,
+        structure: &mut Structure<META>,
+        blocks: &[BlockPointer],
+    ) -> Result<(), Error> {
+        self.mapping = match self.mapping {
+            BlockMapping::Pointers(_) => BlockMapping::Pointers(BlockPointers::new()),
+            BlockMapping::Extents(_) => BlockMapping::Extents(ExtentTree::new()),
+            BlockMapping::Inline(_) if blocks.is_empty() => return Ok(()),
+            BlockMapping::Inline(_) => return Err(Inode::<META>::no_blocks()),
+        };
+        for (index, block) in blocks.iter().enumerate() {
+            match &mut self.mapping {
+                BlockMapping::Pointers(pointers) => {
+                    pointers.set(structure, index as u64, *block)?
+                }
+                BlockMapping::Extents(extents) => {
+                    extents.append(structure, index as u64, *block, 1)?
+                }
+                BlockMapping::Inline(_) => unreachable!(),
+            }
         }
+        self.used_pointers = blocks.len();
+        self.allocated_size =
+            Inode::<META>::calculate_allocated_size(self.used_pointers, structure.get_block_size());
+        self.size = u64::min(self.size, self.allocated_size);
+        Ok(())
+    }
 
-        let mut target_pointer_count = new_size / structure.get_block_size() as u64;
+    pub(crate) fn block_pointer(
+        &self,
+        structure: &Structure<META>,
+        index: usize,
+    ) -> Result<BlockPointer, Error> {
+        match &self.mapping {
+            BlockMapping::Pointers(pointers) => pointers.get(structure, index as u64),
+            BlockMapping::Extents(extents) => extents.get(structure, index as u64),
+            BlockMapping::Inline(_) => Err(Inode::<META>::no_blocks()),
+        }
+    }
+
+    fn no_blocks() -> Error {
+        Error::new("Inline data has no blocks", libc::EINVAL)
+    }
+
+    fn count_used_pointers(size: u64, block_size: usize) -> usize {
+        size.div_ceil(block_size as u64) as usize
+    }
 
-        if (new_size % structure.get_block_size() as u64) > 0 {
-            target_pointer_count += 1;
+    fn calculate_allocated_size(used_pointers: usize, block_size: usize) -> u64 {
+        used_pointers as u64 * block_size as u64
+    }
+
+    fn ensure_size(&mut self, structure: &mut Structure<META>, new_size: u64) -> Result<(), Error> {
+        let max_size =
+            self.max_blocks(structure.get_block_size()) * structure.get_block_size() as u64;
+        if new_size > max_size {
+            return Err(Error::new(
+                &format!("File cannot be larger than {} bytes", max_size),
+                libc::EFBIG,
+            ));
         }
 
-        if self.used_pointers < target_pointer_count as usize {
-            while self.used_pointers < target_pointer_count as usize {
-                self.allocate_block(structure);
-            }
+        let target_pointer_count = new_size.div_ceil(structure.get_block_size() as u64);
+
+        while self.used_pointers < target_pointer_count as usize {
+            self.allocate_blocks(structure, target_pointer_count - self.used_pointers as u64)?;
         }
 
-        if self.used_pointers > target_pointer_count as usize {
-            while self.used_pointers > target_pointer_count as usize {
-                self.deallocate_block(structure);
-            }
+        while self.used_pointers > target_pointer_count as usize {
+            self.deallocate_block(structure)?;
         }
 
         self.size = new_size;
+        Ok(())
     }
 
-    fn allocate_block(&mut self, structure: &mut Structure<META>) -> BlockPointer {
-        if self.used_pointers >= DIRECT_POINTERS {
-            panic!("All pointers are used");
+    // Allocates up to `count` blocks in one go, trying to keep them contiguous with
+    // the last block already in use.
+    fn allocate_blocks(
+        &mut self,
+        structure: &mut Structure<META>,
+        count: u64,
+    ) -> Result<(), Error> {
+        let goal = match self.used_pointers {
+            0 => 0,
+            used => self.block_pointer(structure, used - 1)? + 1,
+        };
+        let (start, length) = structure.allocate_blocks(goal, count)?;
+        let index = self.used_pointers as u64;
+        match &mut self.mapping {
+            BlockMapping::Pointers(pointers) => {
+                for i in 0..length {
+                    pointers.set(structure, index + i, start + i)?;
+                }
+            }
+            BlockMapping::Extents(extents) => extents.append(structure, index, start, length)?,
+            BlockMapping::Inline(_) => return Err(Inode::<META>::no_blocks()),
         }
-
-        let block = structure.allocate_block().unwrap();
-        self.pointers[self.used_pointers] = block;
-        self.used_pointers += 1;
+        self.used_pointers += length as usize;
         self.allocated_size =
             Inode::<META>::calculate_allocated_size(self.used_pointers, structure.get_block_size());
-        block
+        Ok(())
     }
 
-    fn deallocate_block(&mut self, structure: &mut Structure<META>) {
-        let block = self.pointers[self.used_pointers - 1];
-        structure.block_map.mark_free(&mut structure.io, block);
-        self.pointers[self.used_pointers - 1] = NULL_POINTER;
+    fn deallocate_block(&mut self, structure: &mut Structure<META>) -> Result<(), Error> {
+        let index = self.used_pointers - 1;
+        let block = self.block_pointer(structure, index)?;
+        structure.free_block(block)?;
+        match &mut self.mapping {
+            BlockMapping::Pointers(pointers) => pointers.clear(structure, index as u64)?,
+            BlockMapping::Extents(extents) => extents.remove_last(structure)?,
+            BlockMapping::Inline(_) => return Err(Inode::<META>::no_blocks()),
+        }
         self.used_pointers -= 1;
         self.allocated_size =
             Inode::<META>::calculate_allocated_size(self.used_pointers, structure.get_block_size());
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::driver::file_drive::FileDrive;
-    use crate::io::IO;
-    use crate::structure::inode::Inode;
-    use crate::structure::Structure;
+    use crate::driver::memory_drive::MemoryDrive;
+    use crate::driver::DeviceDriver;
+    use crate::structure::inode::{BlockMapping, Inode};
+    use crate::structure::pointers::BlockPointers;
+    use crate::testing::{create_structure, memory_drive};
+    use crate::util::error::Error;
     use crate::util::serializable::{ByteSerializable, KnownSize};
     use std::mem::size_of;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
 
     #[derive(Debug, PartialEq)]
     struct DummyMeta {
@@ -209,9 +477,33 @@ mod tests {
             self.magic.to_le_bytes().to_vec()
         }
 
-        fn from_bytes(bytes: &[u8]) -> Self {
+        fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
             let magic = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
-            DummyMeta { magic }
+            Ok(DummyMeta { magic })
+        }
+    }
+
+    struct CountingDrive {
+        drive: MemoryDrive,
+        writes: Arc<AtomicUsize>,
+    }
+
+    impl DeviceDriver for CountingDrive {
+        fn get_sector_count(&self) -> u64 {
+            self.drive.get_sector_count()
+        }
+
+        fn get_sector_size(&self) -> usize {
+            self.drive.get_sector_size()
+        }
+
+        fn read_sector(&self, index: u64) -> Result<Vec<u8>, Error> {
+            self.drive.read_sector(index)
+        }
+
+        fn write_sector(&mut self, index: u64, data: &[u8]) -> Result<(), Error> {
+            self.writes.fetch_add(1, Ordering::Relaxed);
+            self.drive.write_sector(index, data)
         }
     }
 
@@ -219,7 +511,10 @@ mod tests {
     fn test_inode_to_bytes() {
         let inode = Inode {
             id: Some(42),
-            pointers: [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12],
+            mapping: BlockMapping::Pointers(BlockPointers {
+                direct: [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12],
+                indirect: [13, 14, 15],
+            }),
             size: 12 * 512,
             meta: DummyMeta { magic: 42 },
             used_pointers: 12,
@@ -234,7 +529,10 @@ mod tests {
     fn test_inode_from_bytes() {
         let inode = Inode {
             id: Some(42),
-            pointers: [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12],
+            mapping: BlockMapping::Pointers(BlockPointers {
+                direct: [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12],
+                indirect: [13, 14, 15],
+            }),
             size: 12 * 512,
             meta: DummyMeta { magic: 42 },
             used_pointers: 12,
@@ -242,12 +540,12 @@ mod tests {
         };
 
         let bytes = inode.to_bytes();
-        let new_inode = Inode::<DummyMeta>::from_bytes(42, &bytes, 512);
+        let new_inode = Inode::<DummyMeta>::from_bytes(42, &bytes, 512).unwrap();
         assert_eq!(new_inode.id, Some(42));
         assert_eq!(new_inode.size, 12 * 512);
         assert_eq!(new_inode.used_pointers, 12);
         assert_eq!(new_inode.meta, DummyMeta { magic: 42 });
-        assert_eq!(new_inode.pointers, [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+        assert_eq!(new_inode.mapping, inode.mapping);
         assert_eq!(
             new_inode.allocated_size,
             Inode::<DummyMeta>::calculate_allocated_size(new_inode.used_pointers, 512)
@@ -256,15 +554,161 @@ mod tests {
 
     #[test]
     fn test_inode_data() {
-        let drive = FileDrive::new("./test-images/test_inode_data.img", 2048 * 512, 512);
-        let io = IO::new(drive, 512);
-        let mut structure = Structure::new(io, 512);
+        let mut structure = create_structure(memory_drive(), 512);
 
         let mut inode = Inode::new(DummyMeta { magic: 42 });
         let data = vec![0; 512 * 12];
-        inode.set_data(&mut structure, data.clone());
-        let read_data = inode.get_data(&mut structure);
+        inode.set_data(&mut structure, data.clone()).unwrap();
+        let read_data = inode.get_data(&structure).unwrap();
 
         assert_eq!(data, read_data);
     }
+
+    #[test]
+    fn test_free_unwritten_inode() {
+        let mut structure = create_structure(memory_drive(), 512);
+
+        let mut inode = Inode::new(DummyMeta { magic: 42 });
+        let error = structure.free_inode(&mut inode).err().unwrap();
+        assert_eq!(error.errno(), libc::EINVAL);
+    }
+
+    #[test]
+    fn test_inode_read_write_at() {
+        let mut structure = create_structure(memory_drive(), 512);
+
+        let mut inode = Inode::new(DummyMeta { magic: 42 });
+        let data: Vec<u8> = (0..2000).map(|i| (i % 251) as u8).collect();
+        inode.write_at(&mut structure, 0, &data).unwrap();
+        assert_eq!(inode.size, 2000);
+        assert_eq!(inode.read_at(&structure, 0, 2000).unwrap(), data);
+        assert_eq!(
+            inode.read_at(&structure, 500, 30).unwrap(),
+            data[500..530].to_vec()
+        );
+        assert_eq!(
+            inode.read_at(&structure, 1990, 100).unwrap(),
+            data[1990..].to_vec()
+        );
+
+        inode.write_at(&mut structure, 510, &[0xff; 4]).unwrap();
+        assert_eq!(
+            inode.read_at(&structure, 508, 8).unwrap(),
+            vec![data[508], data[509], 0xff, 0xff, 0xff, 0xff, data[514], data[515]]
+        );
+
+        inode.append_data(&mut structure, &[1, 2, 3]).unwrap();
+        assert_eq!(inode.size, 2003);
+        assert_eq!(inode.read_at(&structure, 2000, 10).unwrap(), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_inode_write_at_zeroes_gaps() {
+        let mut structure = create_structure(memory_drive(), 512);
+
+        let mut inode = Inode::new(DummyMeta { magic: 42 });
+        inode.write_at(&mut structure, 0, &[0xaa; 1024]).unwrap();
+        inode.truncate(&mut structure, 100).unwrap();
+        inode.write_at(&mut structure, 1500, &[0xbb; 10]).unwrap();
+
+        let data = inode.read_at(&structure, 0, 1510).unwrap();
+        assert_eq!(data[..100], [0xaa; 100]);
+        assert!(data[100..1500].iter().all(|byte| *byte == 0));
+        assert_eq!(data[1500..], [0xbb; 10]);
+    }
+
+    #[test]
+    fn test_inode_write_at_only_touches_covered_blocks() {
+        let writes = Arc::new(AtomicUsize::new(0));
+        let drive = CountingDrive {
+            drive: memory_drive(),
+            writes: writes.clone(),
+        };
+        let mut structure = create_structure(drive, 512);
+
+        let mut inode = Inode::new(DummyMeta { magic: 42 });
+        inode
+            .write_at(&mut structure, 0, &vec![0x42; 512 * 12])
+            .unwrap();
+
+        writes.store(0, Ordering::Relaxed);
+        inode
+            .write_at(&mut structure, 512 * 5, &[0x43; 512])
+            .unwrap();
+        assert_eq!(writes.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            inode.read_at(&structure, 512 * 5 - 1, 2).unwrap(),
+            vec![0x42, 0x43]
+        );
+    }
+
+    fn pointers(inode: &Inode<DummyMeta>) -> &BlockPointers {
+        match &inode.mapping {
+            BlockMapping::Pointers(pointers) => pointers,
+            _ => panic!("expected a pointer mapped inode"),
+        }
+    }
+
+    #[test]
+    fn test_inode_indirect_pointers() {
+        let mut structure = create_structure(memory_drive(), 512);
+
+        // 512 byte blocks hold 64 pointers, so this reaches into the double indirect tree
+        let blocks = 12 + 64 + 70;
+        let data: Vec<u8> = (0..512 * blocks).map(|i| (i % 251) as u8).collect();
+        let mut inode = Inode::new(DummyMeta { magic: 42 });
+        inode.write_at(&mut structure, 0, &data).unwrap();
+        assert_eq!(inode.used_pointers, blocks);
+        assert_ne!(pointers(&inode).indirect[0], 0);
+        assert_ne!(pointers(&inode).indirect[1], 0);
+        assert_eq!(pointers(&inode).indirect[2], 0);
+        assert_eq!(inode.get_data(&structure).unwrap(), data);
+
+        let single = pointers(&inode).indirect[0];
+        let double = pointers(&inode).indirect[1];
+        let last_data_block = inode.block_pointer(&structure, blocks - 1).unwrap();
+
+        inode.truncate(&mut structure, 512 * 12).unwrap();
+        assert_eq!(inode.used_pointers, 12);
+        assert_eq!(pointers(&inode).indirect, [0, 0, 0]);
+        assert!(structure.block_map.is_free(single));
+        assert!(structure.block_map.is_free(double));
+        assert!(structure.block_map.is_free(last_data_block));
+        assert_eq!(
+            inode.get_data(&structure).unwrap(),
+            data[..512 * 12].to_vec()
+        );
+    }
+
+    #[test]
+    fn test_inode_extents() {
+        let mut structure = create_structure(memory_drive(), 512);
+
+        let data: Vec<u8> = (0..512 * 100).map(|i| (i % 251) as u8).collect();
+        let mut inode = Inode::with_extents(DummyMeta { magic: 42 });
+        inode
+            .write_at(&mut structure, 0, &data[..512 * 40])
+            .unwrap();
+        inode
+            .append_data(&mut structure, &data[512 * 40..])
+            .unwrap();
+        assert_eq!(inode.used_pointers, 100);
+        assert_eq!(inode.get_data(&structure).unwrap(), data);
+
+        // an empty device hands out one contiguous run, which fits a single extent
+        let first = inode.block_pointer(&structure, 0).unwrap();
+        assert_eq!(inode.block_pointer(&structure, 99).unwrap(), first + 99);
+
+        let bytes = inode.to_bytes();
+        assert_eq!(bytes.len(), Inode::<DummyMeta>::size_on_disk());
+        let decoded = Inode::<DummyMeta>::from_bytes(42, &bytes, 512).unwrap();
+        assert_eq!(decoded.mapping, inode.mapping);
+
+        inode.truncate(&mut structure, 512 * 10).unwrap();
+        assert!(structure.block_map.is_free(first + 10));
+        assert_eq!(
+            inode.get_data(&structure).unwrap(),
+            data[..512 * 10].to_vec()
+        );
+    }
 }
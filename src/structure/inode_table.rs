@@ -1,11 +1,13 @@
-use crate::consts::{
-    BlockPointer, DirectPointers, InodePointer, BLOCKS_PER_INODE_MAP, DIRECT_POINTERS,
-};
+use crate::consts::{BlockPointer, InodePointer};
 use crate::io::IO;
 use crate::structure::inode::Inode;
+use crate::util::checksum::{seal, CHECKSUM_SIZE};
+use crate::util::error::{Error, Location};
 use crate::util::serializable::{ByteSerializable, KnownSize};
 use std::marker::PhantomData;
 
+/// The inode map, one bit per inode, followed by the inode records. Each map block and
+/// each record ends in a checksum.
 pub struct InodeTable<META: ByteSerializable + KnownSize> {
     map: Vec<u8>,
     map_index: u64,
@@ -16,99 +18,119 @@ pub struct InodeTable<META: ByteSerializable + KnownSize> {
 }
 
 impl<META: ByteSerializable + KnownSize> InodeTable<META> {
-    pub fn create(index: BlockPointer, io: &mut IO) -> InodeTable<META> {
-        let inode_count =
-            InodeTable::<META>::calculate_inode_count(io.get_block_count(), io.get_block_size());
-        let map_blocks = inode_count / 8 / io.get_block_size() as u64;
-        let inode_per_block = io.get_block_size() as u64 / Inode::<META>::size_on_disk() as u64;
-        let mut table_blocks = inode_count / inode_per_block;
-        if inode_count % inode_per_block != 0 {
-            table_blocks += 1;
-        }
+    pub fn create(
+        index: BlockPointer,
+        io: &mut IO,
+        inode_count: u64,
+    ) -> Result<InodeTable<META>, Error> {
+        let (map_blocks, table_blocks) =
+            InodeTable::<META>::table_blocks(inode_count, io.get_block_size());
         let total_blocks = map_blocks + table_blocks;
-        for i in 0..total_blocks {
-            io.write_block(index + i, &vec![0; io.get_block_size()]);
+        for i in map_blocks..total_blocks {
+            io.write_block(index + i, &vec![0; io.get_block_size()])?;
         }
-        InodeTable {
+        let table = InodeTable {
             map: vec![0u8; (inode_count / 8u64) as usize],
             map_index: index,
             inode_count,
             table_index: index + map_blocks,
             block_count: total_blocks as usize,
             meta: PhantomData,
-        }
+        };
+        table.write_map(io)?;
+        Ok(table)
     }
 
-    pub fn read(io: &IO, index: BlockPointer, inode_count: u64) -> InodeTable<META> {
-        let map_blocks = inode_count / 8 / io.get_block_size() as u64;
-        let inodes_per_block = io.get_block_size() / Inode::<META>::size_on_disk();
-        let mut table_blocks = inode_count / inodes_per_block as u64;
-        if inode_count % inodes_per_block as u64 != 0 {
-            table_blocks += 1;
-        }
+    pub fn read(io: &IO, index: BlockPointer, inode_count: u64) -> Result<InodeTable<META>, Error> {
+        let (map_blocks, table_blocks) =
+            InodeTable::<META>::table_blocks(inode_count, io.get_block_size());
         let total_blocks = map_blocks + table_blocks;
-        let map = InodeTable::<META>::read_map(io, index, inode_count);
-        InodeTable {
+        let map = InodeTable::<META>::read_map(io, index, inode_count)?;
+        Ok(InodeTable {
             map,
             map_index: index,
             inode_count,
             table_index: index + map_blocks,
             block_count: total_blocks as usize,
             meta: PhantomData,
-        }
+        })
     }
 
-    pub fn read_inode(&self, io: &IO, index: InodePointer) -> Inode<META> {
+    pub fn read_inode(&self, io: &IO, index: InodePointer) -> Result<Inode<META>, Error> {
+        self.check_index(index)?;
         let inode_block = self.inode_block(index, io.get_block_size());
         let offset = Self::inode_offset(index, io.get_block_size());
 
-        let block = io.read_block(inode_block);
-        let mut buffer = vec![0u8; Inode::<META>::size_on_disk()];
-        buffer.copy_from_slice(&block[offset..offset + Inode::<META>::size_on_disk()]);
-        let inode = Inode::<META>::from_bytes(index, &buffer, io.get_block_size());
-        inode
+        let block = io.read_block(inode_block)?;
+        let record = &block[offset..offset + Self::record_size()];
+        io.verify(record, index, Location::Inode(index))?;
+        Inode::<META>::from_bytes(index, record, io.get_block_size())
     }
 
-    pub fn write_inode(&mut self, io: &mut IO, inode: &mut Inode<META>) {
+    pub fn write_inode(&mut self, io: &mut IO, inode: &mut Inode<META>) -> Result<(), Error> {
         match inode.id {
             None => {
-                let index = self.allocate(io).unwrap();
+                let index = self.allocate(io)?;
                 inode.set_id(index);
-                self.write_inode(io, inode);
+                self.write_inode(io, inode)
             }
             Some(index) => {
+                self.check_index(index)?;
                 let inode_block = self.inode_block(index, io.get_block_size());
                 let offset = Self::inode_offset(index, io.get_block_size());
 
-                let mut block = io.read_block(inode_block);
-                block[offset..offset + Inode::<META>::size_on_disk()]
-                    .copy_from_slice(inode.to_bytes().as_slice());
-                io.write_block(inode_block, &block);
+                let mut block = io.read_block(inode_block)?;
+                let record = &mut block[offset..offset + Self::record_size()];
+                record[..Inode::<META>::size_on_disk()].copy_from_slice(&inode.to_bytes());
+                seal(record, index);
+                io.write_block(inode_block, &block)
             }
         }
     }
 
+    fn check_index(&self, index: InodePointer) -> Result<(), Error> {
+        if index >= self.inode_count {
+            return Err(Error::corrupted(
+                &format!("Inode {} out of range", index),
+                Location::Inode(index),
+            ));
+        }
+        Ok(())
+    }
+
+    /// The bytes an inode takes up in the table, including its checksum.
+    pub fn record_size() -> usize {
+        Inode::<META>::size_on_disk() + CHECKSUM_SIZE
+    }
+
     #[inline]
     fn inode_block(&self, index: InodePointer, block_size: usize) -> BlockPointer {
-        self.table_index + (index / (block_size / Inode::<META>::size_on_disk()) as u64)
+        self.table_index + (index / (block_size / Self::record_size()) as u64)
     }
 
     #[inline]
     fn inode_offset(index: InodePointer, block_size: usize) -> usize {
-        (index % (block_size / Inode::<META>::size_on_disk()) as u64) as usize
-            * Inode::<META>::size_on_disk()
+        (index % (block_size / Self::record_size()) as u64) as usize * Self::record_size()
     }
 
-    fn allocate(&mut self, io: &mut IO) -> Option<InodePointer> {
+    fn allocate(&mut self, io: &mut IO) -> Result<InodePointer, Error> {
         for i in 0..self.map.len() {
             for j in 0..8 {
                 if self.map[i] & (1 << j) == 0 {
-                    self.mark_used(io, (i * 8 + j) as u64);
-                    return Some((i * 8 + j) as u64);
+                    self.mark_used(io, (i * 8 + j) as u64)?;
+                    return Ok((i * 8 + j) as u64);
                 }
             }
         }
-        None
+        Err(Error::NoInodes)
+    }
+
+    pub fn count_free(&self) -> u64 {
+        self.map.iter().map(|byte| byte.count_zeros() as u64).sum()
+    }
+
+    pub(crate) fn is_free(&self, index: InodePointer) -> bool {
+        self.map[(index / 8) as usize] & (1 << (index % 8)) == 0
     }
 
     fn mark_used_mem(&mut self, index: InodePointer) {
@@ -117,9 +139,9 @@ impl<META: ByteSerializable + KnownSize> InodeTable<META> {
         self.map[byte as usize] |= 1 << bit;
     }
 
-    fn mark_used(&mut self, io: &mut IO, index: InodePointer) {
+    pub(crate) fn mark_used(&mut self, io: &mut IO, index: InodePointer) -> Result<(), Error> {
         self.mark_used_mem(index);
-        self.write_map(io);
+        self.write_map(io)
     }
 
     fn mark_free_mem(&mut self, index: u64) {
@@ -128,50 +150,71 @@ impl<META: ByteSerializable + KnownSize> InodeTable<META> {
         self.map[byte as usize] &= !(1 << bit);
     }
 
-    fn mark_free(&mut self, io: &mut IO, index: InodePointer) {
+    pub(crate) fn mark_free(&mut self, io: &mut IO, index: InodePointer) -> Result<(), Error> {
         self.mark_free_mem(index);
-        self.write_map(io);
+        self.write_map(io)
     }
 
-    fn calculate_inode_count(block_count: u64, block_size: usize) -> u64 {
-        let bits_per_block = (block_size * 8) as u64;
-        let blocks = block_count / BLOCKS_PER_INODE_MAP as u64;
-        if blocks == 0 {
-            bits_per_block
-        } else {
-            blocks * bits_per_block
-        }
+    /// The number of blocks taken up by a table of `inode_count` inodes.
+    pub fn size_in_blocks(inode_count: u64, block_size: usize) -> u64 {
+        let (map_blocks, table_blocks) = InodeTable::<META>::table_blocks(inode_count, block_size);
+        map_blocks + table_blocks
+    }
+
+    // Blocks for the inode map and for the inodes themselves.
+    fn table_blocks(inode_count: u64, block_size: usize) -> (u64, u64) {
+        let map_blocks = inode_count / 8 / map_bytes_per_block(block_size) as u64;
+        let inodes_per_block = (block_size / Self::record_size()) as u64;
+        (map_blocks, inode_count.div_ceil(inodes_per_block))
     }
 
-    fn read_map(io: &IO, index: BlockPointer, inode_count: u64) -> Vec<u8> {
+    /// One inode per `bytes_per_inode` bytes of the device, rounded up so that the
+    /// inode map fills whole blocks.
+    pub fn calculate_inode_count(block_count: u64, block_size: usize, bytes_per_inode: u64) -> u64 {
+        let bits_per_block = (map_bytes_per_block(block_size) * 8) as u64;
+        let inodes = (block_count * block_size as u64).div_ceil(bytes_per_inode);
+        u64::max(inodes.div_ceil(bits_per_block), 1) * bits_per_block
+    }
+
+    fn read_map(io: &IO, index: BlockPointer, inode_count: u64) -> Result<Vec<u8>, Error> {
         let mut map = vec![0u8; (inode_count / 8u64) as usize];
-        let map_blocks = inode_count / 8 / io.get_block_size() as u64;
-        for i in 0..map_blocks as usize {
-            let block = io.read_block(index + i as u64);
-            map[i * io.get_block_size()..(i + 1) * io.get_block_size()].copy_from_slice(&block);
+        let bytes_per_block = map_bytes_per_block(io.get_block_size());
+        for i in 0..map.len() / bytes_per_block {
+            let block_index = index + i as u64;
+            let block = io.read_block(block_index)?;
+            io.verify(&block, block_index, Location::Block(block_index))?;
+            map[i * bytes_per_block..(i + 1) * bytes_per_block]
+                .copy_from_slice(&block[..bytes_per_block]);
         }
-        map
+        Ok(map)
     }
 
     // TODO: optimize this
     // - only write affected blocks
     // - cache some values
-    fn write_map(&self, io: &mut IO) {
-        let blocks = self.map.len() / io.get_block_size();
-        for i in 0..blocks {
-            let start = i * io.get_block_size();
-            let end = start + io.get_block_size();
-            let block = self.map[start..end].to_vec();
-            io.write_block(self.map_index + i as u64, &block);
+    fn write_map(&self, io: &mut IO) -> Result<(), Error> {
+        let bytes_per_block = map_bytes_per_block(io.get_block_size());
+        for (i, bits) in self.map.chunks(bytes_per_block).enumerate() {
+            let mut block = bits.to_vec();
+            block.resize(io.get_block_size(), 0);
+            seal(&mut block, self.map_index + i as u64);
+            io.write_block(self.map_index + i as u64, &block)?;
         }
+        Ok(())
     }
 }
 
+// The bytes of the inode map held by each block, in front of the checksum.
+fn map_bytes_per_block(block_size: usize) -> usize {
+    block_size - CHECKSUM_SIZE
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::driver::file_drive::FileDrive;
+    use crate::driver::memory_drive::MemoryDrive;
     use crate::io::IO;
     use crate::structure::inode::Inode;
+    use crate::util::error::Error;
     use crate::util::serializable::{ByteSerializable, KnownSize};
 
     #[derive(Debug, PartialEq)]
@@ -190,56 +233,83 @@ mod tests {
             self.magic.to_le_bytes().to_vec()
         }
 
-        fn from_bytes(bytes: &[u8]) -> Self {
+        fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
             let magic = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
-            DummyMeta { magic }
+            Ok(DummyMeta { magic })
         }
     }
 
     #[test]
     fn read_write_table() {
-        let drive = FileDrive::new(
-            "./test-images/structure_inode_read_write_table.img",
-            2048 * 512,
-            512,
-        );
+        let drive = MemoryDrive::new(2048 * 512, 512);
         let mut io = IO::new(drive, 512);
 
-        let new_table = super::InodeTable::<DummyMeta>::create(1, &mut io);
-        assert_eq!(new_table.map.len(), 512);
+        let inode_count = super::InodeTable::<DummyMeta>::calculate_inode_count(2048, 512, 16384);
+        let new_table = super::InodeTable::<DummyMeta>::create(1, &mut io, inode_count).unwrap();
+        assert_eq!(new_table.map.len(), 508);
         assert_eq!(new_table.map_index, 1);
-        assert_eq!(new_table.inode_count, 512 * 8);
+        assert_eq!(new_table.inode_count, 508 * 8);
         assert_eq!(new_table.table_index, 2);
-        assert_eq!(new_table.block_count, 1025);
+        assert_eq!(new_table.block_count, 1356);
 
-        let inode_table = super::InodeTable::<DummyMeta>::read(&mut io, 1, new_table.inode_count);
-        assert_eq!(inode_table.map.len(), 512);
+        let inode_table =
+            super::InodeTable::<DummyMeta>::read(&io, 1, new_table.inode_count).unwrap();
+        assert_eq!(inode_table.map.len(), 508);
         assert_eq!(inode_table.map_index, 1);
-        assert_eq!(inode_table.inode_count, 512 * 8);
+        assert_eq!(inode_table.inode_count, 508 * 8);
         assert_eq!(inode_table.table_index, 2);
-        assert_eq!(inode_table.block_count, 1025);
+        assert_eq!(inode_table.block_count, 1356);
     }
 
     #[test]
     fn read_write_inode() {
-        let drive = FileDrive::new(
-            "./test-images/structure_inode_read_write_node.img",
-            2048 * 512,
-            512,
-        );
+        let drive = MemoryDrive::new(2048 * 512, 512);
         let mut io = IO::new(drive, 512);
 
-        let mut inode_table = super::InodeTable::create(1, &mut io);
+        let mut inode_table = super::InodeTable::create(1, &mut io, 508 * 8).unwrap();
         let mut memory_inode = Inode::<DummyMeta>::new(DummyMeta { magic: 42 });
-        inode_table.write_inode(&mut io, &mut memory_inode);
-        let mut fs_inode = inode_table.read_inode(&mut io, memory_inode.id.unwrap());
+        inode_table.write_inode(&mut io, &mut memory_inode).unwrap();
+        let mut fs_inode = inode_table
+            .read_inode(&io, memory_inode.id.unwrap())
+            .unwrap();
         assert_eq!(memory_inode.to_bytes(), fs_inode.to_bytes());
 
         memory_inode.meta.magic = 43;
 
-        inode_table.write_inode(&mut io, &mut memory_inode);
-        fs_inode = inode_table.read_inode(&io, memory_inode.id.unwrap());
+        inode_table.write_inode(&mut io, &mut memory_inode).unwrap();
+        fs_inode = inode_table
+            .read_inode(&io, memory_inode.id.unwrap())
+            .unwrap();
         assert_eq!(memory_inode.to_bytes(), fs_inode.to_bytes());
         assert_eq!(fs_inode.meta.magic, 43);
     }
+
+    #[test]
+    fn checksums() {
+        let drive = MemoryDrive::new(2048 * 512, 512);
+        let mut io = IO::new(drive, 512);
+        let mut inode_table = super::InodeTable::create(1, &mut io, 508 * 8).unwrap();
+        let mut first = Inode::<DummyMeta>::new(DummyMeta { magic: 1 });
+        let mut second = Inode::<DummyMeta>::new(DummyMeta { magic: 2 });
+        inode_table.write_inode(&mut io, &mut first).unwrap();
+        inode_table.write_inode(&mut io, &mut second).unwrap();
+
+        // the first record starts the table, damage to it leaves the second readable
+        let mut block = io.read_block(2).unwrap();
+        block[3] ^= 0x40;
+        io.write_block(2, &block).unwrap();
+        let error = inode_table.read_inode(&io, 0).err().unwrap();
+        assert_eq!(error.errno(), libc::EUCLEAN);
+        assert_eq!(inode_table.read_inode(&io, 1).unwrap().meta.magic, 2);
+        assert_eq!(io.checksum_errors(), 1);
+
+        let mut block = io.read_block(1).unwrap();
+        block[0] ^= 0x80;
+        io.write_block(1, &block).unwrap();
+        let error = super::InodeTable::<DummyMeta>::read(&io, 1, 508 * 8)
+            .err()
+            .unwrap();
+        assert_eq!(error.errno(), libc::EUCLEAN);
+        assert_eq!(io.checksum_errors(), 2);
+    }
 }
@@ -0,0 +1,523 @@
+use crate::consts::BlockPointer;
+use crate::io::IO;
+use crate::structure::layout::DataMode;
+use crate::util::checksum::{crc32c, crc32c_append};
+use crate::util::error::Error;
+use std::collections::{HashMap, HashSet};
+
+const HEADER_MAGIC: u32 = 0x4a52_4e4c;
+const DESCRIPTOR_MAGIC: u32 = 0x4a44_5343;
+const COMMIT_MAGIC: u32 = 0x4a43_4d54;
+// magic, block count and sequence number in front of the descriptor's block list
+const DESCRIPTOR_HEADER: usize = 16;
+
+// home locations and contents of the blocks in a transaction
+type Writes = Vec<(BlockPointer, Vec<u8>)>;
+
+/// Write-ahead log for metadata. The blocks written during a transaction are kept in
+/// memory until it ends, then go to the journal region together with a checksummed
+/// commit record, and only after that to their home locations. A crash in between is
+/// repaired by replaying the committed transaction on the next mount.
+///
+/// The region holds a header block with the sequence number of the next transaction,
+/// followed by the last transaction: a descriptor listing the home locations, the
+/// blocks themselves and the commit record.
+///
+/// A read-only mount replays into memory: the blocks of the last transaction are read
+/// from the journal instead of their home locations until the next read-write mount.
+///
+/// File contents are only journaled in `DataMode::Journal`. Otherwise they are written
+/// around the journal, before or after the commit depending on the mode.
+pub struct Journal {
+    pub(crate) start: BlockPointer,
+    pub(crate) blocks: u64,
+    pub(crate) data_mode: DataMode,
+    sequence: u64,
+    // writes of the open transaction, in the order they were first made
+    pending: Writes,
+    positions: HashMap<BlockPointer, usize>,
+    // file contents held back in `DataMode::Writeback` until after the commit
+    deferred: HashMap<BlockPointer, Vec<u8>>,
+    // the transaction replayed on a read-only mount
+    replayed: HashMap<BlockPointer, Vec<u8>>,
+    // home locations of the last committed transaction until it is checkpointed
+    committed: HashSet<BlockPointer>,
+    depth: usize,
+}
+
+impl Journal {
+    /// Initializes an empty journal in the `blocks` blocks starting at `start`.
+    pub fn create(io: &mut IO, start: BlockPointer, blocks: u64) -> Result<Journal, Error> {
+        let mut journal = Journal::new(start, blocks, 1);
+        io.write_block(start + 1, &vec![0; io.get_block_size()])?;
+        journal.write_header(io)?;
+        Ok(journal)
+    }
+
+    /// Opens the journal and replays the last transaction if it was committed but
+    /// possibly not yet written to its home locations, only into memory if `read_only`
+    /// is set. Returns the journal and whether anything was replayed.
+    pub fn open(
+        io: &mut IO,
+        start: BlockPointer,
+        blocks: u64,
+        read_only: bool,
+    ) -> Result<(Journal, bool), Error> {
+        let header = io.read_block(start)?;
+        if read_u32(&header, 0) != HEADER_MAGIC {
+            return Err(Error::new("Invalid journal header", libc::EUCLEAN));
+        }
+        let mut journal = Journal::new(start, blocks, read_u64(&header, 8));
+
+        let Some((sequence, writes)) = journal.read_transaction(io)? else {
+            return Ok((journal, false));
+        };
+        if read_only {
+            journal.replayed = writes.into_iter().collect();
+            return Ok((journal, true));
+        }
+        for (index, block) in &writes {
+            io.write_block(*index, block)?;
+        }
+        io.sync()?;
+        journal.sequence = sequence + 1;
+        journal.write_header(io)?;
+        io.sync()?;
+        Ok((journal, true))
+    }
+
+    fn new(start: BlockPointer, blocks: u64, sequence: u64) -> Journal {
+        Journal {
+            start,
+            blocks,
+            data_mode: DataMode::default(),
+            sequence,
+            pending: Vec::new(),
+            positions: HashMap::new(),
+            deferred: HashMap::new(),
+            replayed: HashMap::new(),
+            committed: HashSet::new(),
+            depth: 0,
+        }
+    }
+
+    /// The most blocks a single transaction can hold.
+    pub fn capacity(&self, block_size: usize) -> usize {
+        let listed = (block_size - DESCRIPTOR_HEADER) / size_of::<u64>();
+        usize::min(listed, self.blocks.saturating_sub(3) as usize)
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.depth > 0
+    }
+
+    /// Opens a transaction, or nests into the one already open.
+    pub fn begin(&mut self) {
+        self.depth += 1;
+    }
+
+    /// Closes the innermost transaction, returns whether it was the outermost one and
+    /// has to be committed.
+    pub fn end(&mut self) -> bool {
+        self.depth = self.depth.saturating_sub(1);
+        self.depth == 0
+    }
+
+    /// Adds a write to the open transaction. A transaction never spans more than one
+    /// commit, so one that outgrows the journal fails with `ENOSPC` and has to be
+    /// aborted.
+    pub fn stage(
+        &mut self,
+        index: BlockPointer,
+        block: &[u8],
+        block_size: usize,
+    ) -> Result<(), Error> {
+        self.deferred.remove(&index);
+        match self.positions.get(&index) {
+            Some(position) => self.pending[*position].1.copy_from_slice(block),
+            None if self.pending.len() >= self.capacity(block_size) => {
+                return Err(Error::new(
+                    "Transaction does not fit into the journal",
+                    libc::ENOSPC,
+                ))
+            }
+            None => {
+                self.positions.insert(index, self.pending.len());
+                self.pending.push((index, block.to_vec()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a write of file contents to the open transaction, or forgets an earlier
+    /// metadata write of the block if the contents go to the device right away.
+    pub fn stage_data(
+        &mut self,
+        index: BlockPointer,
+        block: &[u8],
+        block_size: usize,
+    ) -> Result<(), Error> {
+        match self.data_mode {
+            DataMode::Journal => return self.stage(index, block, block_size),
+            DataMode::Ordered => self.unstage(index),
+            DataMode::Writeback => {
+                self.unstage(index);
+                self.deferred.insert(index, block.to_vec());
+            }
+        }
+        Ok(())
+    }
+
+    // A block freed and reused for file contents in the same transaction must not be
+    // overwritten with its old metadata on commit.
+    fn unstage(&mut self, index: BlockPointer) {
+        if let Some(position) = self.positions.remove(&index) {
+            self.pending.swap_remove(position);
+            if let Some((moved, _)) = self.pending.get(position) {
+                self.positions.insert(*moved, position);
+            }
+        }
+    }
+
+    /// Drops the open transaction, however deeply nested, without writing anything of it.
+    pub fn abort(&mut self) {
+        self.pending.clear();
+        self.positions.clear();
+        self.deferred.clear();
+        self.depth = 0;
+    }
+
+    /// The block as written by the open transaction, or by the transaction replayed
+    /// into memory, if either touched it.
+    pub fn staged(&self, index: BlockPointer) -> Option<&Vec<u8>> {
+        self.positions
+            .get(&index)
+            .map(|position| &self.pending[*position].1)
+            .or_else(|| self.deferred.get(&index))
+            .or_else(|| self.replayed.get(&index))
+    }
+
+    /// Writes the pending blocks to the journal, then to their home locations. `io`
+    /// must not route writes back into this journal.
+    pub fn commit(&mut self, io: &mut IO) -> Result<(), Error> {
+        let writes = std::mem::take(&mut self.pending);
+        self.positions.clear();
+        if !writes.is_empty() {
+            self.write_transaction(io, &writes)?;
+        }
+        let deferred = std::mem::take(&mut self.deferred);
+        if deferred.keys().any(|index| self.is_committed(*index)) {
+            self.checkpoint(io)?;
+        }
+        for (index, block) in deferred {
+            io.write_block(index, &block)?;
+        }
+        Ok(())
+    }
+
+    fn write_transaction(&mut self, io: &mut IO, writes: &Writes) -> Result<(), Error> {
+        let block_size = io.get_block_size();
+        // the previous transaction must be home before its journal copy is overwritten,
+        // and in ordered mode the file contents written since before this one commits
+        io.sync()?;
+        let mut descriptor = Vec::with_capacity(block_size);
+        descriptor.extend_from_slice(&DESCRIPTOR_MAGIC.to_le_bytes());
+        descriptor.extend_from_slice(&(writes.len() as u32).to_le_bytes());
+        descriptor.extend_from_slice(&self.sequence.to_le_bytes());
+        for (index, _) in writes {
+            descriptor.extend_from_slice(&index.to_le_bytes());
+        }
+        descriptor.resize(block_size, 0);
+        io.write_block(self.start + 1, &descriptor)?;
+
+        let mut checksum = crc32c(&descriptor);
+        for (i, (_, block)) in writes.iter().enumerate() {
+            checksum = crc32c_append(checksum, block);
+            io.write_block(self.start + 2 + i as u64, block)?;
+        }
+        let mut commit = Vec::with_capacity(block_size);
+        commit.extend_from_slice(&COMMIT_MAGIC.to_le_bytes());
+        commit.extend_from_slice(&checksum.to_le_bytes());
+        commit.extend_from_slice(&self.sequence.to_le_bytes());
+        commit.resize(block_size, 0);
+        io.write_block(self.start + 2 + writes.len() as u64, &commit)?;
+        io.sync()?;
+
+        for (index, block) in writes {
+            io.write_block(*index, block)?;
+        }
+        self.sequence += 1;
+        self.committed = writes.iter().map(|(index, _)| *index).collect();
+        Ok(())
+    }
+
+    /// Records that every committed transaction reached its home locations, so that
+    /// none of them is replayed again. Does nothing if none was committed since the
+    /// last checkpoint.
+    pub fn checkpoint(&mut self, io: &mut IO) -> Result<(), Error> {
+        if self.committed.is_empty() {
+            return Ok(());
+        }
+        io.sync()?;
+        self.write_header(io)?;
+        io.sync()?;
+        self.committed.clear();
+        Ok(())
+    }
+
+    /// Whether replaying the journal would write block `index`. Such a block has to be
+    /// checkpointed before it is written around the journal, or a crash would bring
+    /// back its old contents, say metadata over the file contents it was reused for.
+    pub fn is_committed(&self, index: BlockPointer) -> bool {
+        self.committed.contains(&index)
+    }
+
+    // The transaction in the journal if it is committed and not yet checkpointed.
+    fn read_transaction(&self, io: &IO) -> Result<Option<(u64, Writes)>, Error> {
+        let descriptor = io.read_block(self.start + 1)?;
+        let count = read_u32(&descriptor, 4) as usize;
+        let sequence = read_u64(&descriptor, 8);
+        if read_u32(&descriptor, 0) != DESCRIPTOR_MAGIC
+            || sequence < self.sequence
+            || count > self.capacity(io.get_block_size())
+        {
+            return Ok(None);
+        }
+
+        let mut checksum = crc32c(&descriptor);
+        let mut writes = Vec::with_capacity(count);
+        for i in 0..count {
+            let index = read_u64(&descriptor, DESCRIPTOR_HEADER + i * size_of::<u64>());
+            let block = io.read_block(self.start + 2 + i as u64)?;
+            checksum = crc32c_append(checksum, &block);
+            writes.push((index, block));
+        }
+        let commit = io.read_block(self.start + 2 + count as u64)?;
+        if read_u32(&commit, 0) != COMMIT_MAGIC
+            || read_u32(&commit, 4) != checksum
+            || read_u64(&commit, 8) != sequence
+        {
+            // torn before the commit record made it, nothing of it reached home
+            return Ok(None);
+        }
+        if let Some((index, _)) = writes
+            .iter()
+            .find(|(index, _)| *index >= io.get_block_count())
+        {
+            return Err(Error::new(
+                &format!("Journal refers to block {} out of range", index),
+                libc::EUCLEAN,
+            ));
+        }
+        Ok(Some((sequence, writes)))
+    }
+
+    fn write_header(&mut self, io: &mut IO) -> Result<(), Error> {
+        let mut header = Vec::with_capacity(io.get_block_size());
+        header.extend_from_slice(&HEADER_MAGIC.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&self.sequence.to_le_bytes());
+        header.resize(io.get_block_size(), 0);
+        io.write_block(self.start, &header)
+    }
+}
+
+fn read_u32(block: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(block[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(block: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(block[offset..offset + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::driver::memory_drive::MemoryDrive;
+    use crate::driver::DeviceDriver;
+    use crate::io::IO;
+    use crate::structure::journal::Journal;
+    use crate::structure::layout::DataMode;
+
+    const BLOCK_SIZE: usize = 512;
+    const START: u64 = 40;
+    const BLOCKS: u64 = 16;
+
+    fn journaled(drive: &MemoryDrive) -> IO {
+        journaled_with(drive, DataMode::Ordered)
+    }
+
+    fn journaled_with(drive: &MemoryDrive, data_mode: DataMode) -> IO {
+        let mut io = IO::new(drive.clone(), BLOCK_SIZE);
+        let mut journal = Journal::create(&mut io, START, BLOCKS).unwrap();
+        journal.data_mode = data_mode;
+        io.set_journal(journal);
+        io
+    }
+
+    // commits a transaction writing 3 and 5, then undoes their home writes as if the
+    // power was lost before they reached the device
+    fn commit_and_lose(drive: &MemoryDrive, io: &mut IO) {
+        io.begin();
+        io.write_block(3, &vec![3; BLOCK_SIZE]).unwrap();
+        io.write_block(5, &vec![5; BLOCK_SIZE]).unwrap();
+        assert_eq!(io.read_block(3).unwrap(), vec![3; BLOCK_SIZE]);
+        assert_eq!(drive.read_sector(3).unwrap(), vec![0; BLOCK_SIZE]);
+        io.commit().unwrap();
+
+        let mut drive = drive.clone();
+        drive.write_sector(3, &vec![0; BLOCK_SIZE]).unwrap();
+        drive.write_sector(5, &vec![0; BLOCK_SIZE]).unwrap();
+    }
+
+    #[test]
+    fn test_replay_committed_transaction() {
+        let drive = MemoryDrive::new(64 * BLOCK_SIZE as u64, BLOCK_SIZE);
+        let mut io = journaled(&drive);
+        commit_and_lose(&drive, &mut io);
+
+        let mut io = IO::new(drive.clone(), BLOCK_SIZE);
+        let (_, replayed) = Journal::open(&mut io, START, BLOCKS, false).unwrap();
+        assert!(replayed);
+        assert_eq!(drive.read_sector(3).unwrap(), vec![3; BLOCK_SIZE]);
+        assert_eq!(drive.read_sector(5).unwrap(), vec![5; BLOCK_SIZE]);
+
+        let (_, replayed) = Journal::open(&mut io, START, BLOCKS, false).unwrap();
+        assert!(!replayed);
+    }
+
+    #[test]
+    fn test_replay_read_only() {
+        let drive = MemoryDrive::new(64 * BLOCK_SIZE as u64, BLOCK_SIZE);
+        let mut io = journaled(&drive);
+        commit_and_lose(&drive, &mut io);
+        let image = drive.snapshot();
+
+        let mut io = IO::new(drive.clone(), BLOCK_SIZE);
+        let (journal, replayed) = Journal::open(&mut io, START, BLOCKS, true).unwrap();
+        assert!(replayed);
+        io.set_journal(journal);
+        assert_eq!(io.read_block(3).unwrap(), vec![3; BLOCK_SIZE]);
+        assert_eq!(io.read_block(4).unwrap(), vec![0; BLOCK_SIZE]);
+        io.sync().unwrap();
+        assert!(drive.snapshot() == image);
+
+        // the transaction is still there for a read-write mount
+        let mut io = IO::new(drive.clone(), BLOCK_SIZE);
+        let (_, replayed) = Journal::open(&mut io, START, BLOCKS, false).unwrap();
+        assert!(replayed);
+        assert_eq!(drive.read_sector(3).unwrap(), vec![3; BLOCK_SIZE]);
+    }
+
+    #[test]
+    fn test_skip_uncommitted_transaction() {
+        let drive = MemoryDrive::new(64 * BLOCK_SIZE as u64, BLOCK_SIZE);
+        let mut io = journaled(&drive);
+        commit_and_lose(&drive, &mut io);
+
+        // the commit record follows the descriptor and the two blocks
+        let mut torn = drive.clone();
+        torn.write_sector(START + 4, &vec![0; BLOCK_SIZE]).unwrap();
+        let mut io = IO::new(drive.clone(), BLOCK_SIZE);
+        let (_, replayed) = Journal::open(&mut io, START, BLOCKS, false).unwrap();
+        assert!(!replayed);
+        assert_eq!(drive.read_sector(3).unwrap(), vec![0; BLOCK_SIZE]);
+
+        let mut io = IO::new(
+            MemoryDrive::new(64 * BLOCK_SIZE as u64, BLOCK_SIZE),
+            BLOCK_SIZE,
+        );
+        assert_eq!(
+            Journal::open(&mut io, START, BLOCKS, false)
+                .err()
+                .unwrap()
+                .errno(),
+            libc::EUCLEAN
+        );
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let drive = MemoryDrive::new(64 * BLOCK_SIZE as u64, BLOCK_SIZE);
+        let mut io = journaled(&drive);
+        commit_and_lose(&drive, &mut io);
+        io.sync().unwrap();
+
+        let mut io = IO::new(drive.clone(), BLOCK_SIZE);
+        let (_, replayed) = Journal::open(&mut io, START, BLOCKS, false).unwrap();
+        assert!(!replayed);
+    }
+
+    #[test]
+    fn test_reused_block_is_not_replayed() {
+        let drive = MemoryDrive::new(64 * BLOCK_SIZE as u64, BLOCK_SIZE);
+        let mut io = journaled(&drive);
+        io.begin();
+        io.write_block(3, &vec![3; BLOCK_SIZE]).unwrap();
+        io.commit().unwrap();
+
+        // the metadata in block 3 was freed and the block now holds file contents,
+        // which go to the device ahead of the transaction that reuses it
+        io.begin();
+        io.write_data_block(3, &vec![0xdd; BLOCK_SIZE]).unwrap();
+        assert_eq!(drive.read_sector(3).unwrap(), vec![0xdd; BLOCK_SIZE]);
+
+        // power lost before the commit, the old copy in the journal is not replayed
+        let mut io = IO::new(drive.clone(), BLOCK_SIZE);
+        let (_, replayed) = Journal::open(&mut io, START, BLOCKS, false).unwrap();
+        assert!(!replayed);
+        assert_eq!(drive.read_sector(3).unwrap(), vec![0xdd; BLOCK_SIZE]);
+    }
+
+    #[test]
+    fn test_transaction_too_large() {
+        let drive = MemoryDrive::new(64 * BLOCK_SIZE as u64, BLOCK_SIZE);
+        let mut io = journaled(&drive);
+        // descriptor, header and commit record take 3 of the blocks
+        let capacity = BLOCKS - 3;
+
+        io.begin();
+        for i in 1..=capacity {
+            io.write_block(i, &vec![i as u8; BLOCK_SIZE]).unwrap();
+        }
+        // rewriting a block already in the transaction takes no more room
+        io.write_block(1, &vec![1; BLOCK_SIZE]).unwrap();
+        let error = io.write_block(capacity + 1, &vec![0xff; BLOCK_SIZE]);
+        assert_eq!(error.unwrap_err().errno(), libc::ENOSPC);
+        // nothing went out before the transaction was complete
+        assert_eq!(drive.read_sector(1).unwrap(), vec![0; BLOCK_SIZE]);
+
+        io.abort();
+        assert_eq!(io.read_block(1).unwrap(), vec![0; BLOCK_SIZE]);
+        io.write_block(1, &vec![1; BLOCK_SIZE]).unwrap();
+        assert_eq!(drive.read_sector(1).unwrap(), vec![1; BLOCK_SIZE]);
+    }
+
+    #[test]
+    fn test_data_modes() {
+        let block = |value: u8| vec![value; BLOCK_SIZE];
+        for data_mode in [DataMode::Journal, DataMode::Ordered, DataMode::Writeback] {
+            let drive = MemoryDrive::new(64 * BLOCK_SIZE as u64, BLOCK_SIZE);
+            let mut io = journaled_with(&drive, data_mode);
+            io.begin();
+            io.write_block(7, &block(1)).unwrap();
+            io.write_data_block(7, &block(2)).unwrap();
+            io.write_data_block(8, &block(3)).unwrap();
+            assert_eq!(io.read_block(7).unwrap(), block(2), "{:?}", data_mode);
+
+            let written = drive.read_sector(8).unwrap() == block(3);
+            assert_eq!(written, data_mode == DataMode::Ordered, "{:?}", data_mode);
+            io.commit().unwrap();
+            assert_eq!(drive.read_sector(7).unwrap(), block(2), "{:?}", data_mode);
+            assert_eq!(drive.read_sector(8).unwrap(), block(3), "{:?}", data_mode);
+
+            // only journaled contents come back after losing their home writes
+            let mut lost = drive.clone();
+            lost.write_sector(8, &block(0)).unwrap();
+            let mut io = IO::new(drive.clone(), BLOCK_SIZE);
+            let (_, replayed) = Journal::open(&mut io, START, BLOCKS, false).unwrap();
+            assert_eq!(replayed, data_mode == DataMode::Journal, "{:?}", data_mode);
+            let restored = drive.read_sector(8).unwrap() == block(3);
+            assert_eq!(restored, data_mode == DataMode::Journal, "{:?}", data_mode);
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use crate::consts::{BlockPointer, DEFAULT_BYTES_PER_INODE};
+use crate::util::format::pretty_size_from_bytes;
+use crate::util::uuid::{self, Uuid};
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+/// Everything that can be chosen when formatting a device.
+pub struct FormatOptions {
+    pub block_size: usize,
+    // one inode is reserved for every this many bytes of the device
+    pub bytes_per_inode: u64,
+    pub features: u32,
+    pub label: String,
+    pub uuid: Uuid,
+    // the size of the journal in blocks, picked from the device size if `None`
+    pub journal_blocks: Option<u64>,
+}
+
+impl Default for FormatOptions {
+    fn default() -> FormatOptions {
+        FormatOptions {
+            block_size: 4096,
+            bytes_per_inode: DEFAULT_BYTES_PER_INODE,
+            features: 0,
+            label: String::new(),
+            uuid: [0; 16],
+            journal_blocks: None,
+        }
+    }
+}
+
+/// How file contents are written relative to the metadata journal, as in ext3/4.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum DataMode {
+    /// File contents go through the journal like metadata.
+    Journal,
+    /// File contents reach the device before the transaction referring to them is
+    /// committed, so a crash never exposes stale blocks.
+    #[default]
+    Ordered,
+    /// File contents are written after the transaction is committed. Fastest, but a
+    /// crash can leave stale blocks in recently written files.
+    Writeback,
+}
+
+impl FromStr for DataMode {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<DataMode, ()> {
+        match value {
+            "journal" => Ok(DataMode::Journal),
+            "ordered" => Ok(DataMode::Ordered),
+            "writeback" => Ok(DataMode::Writeback),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Everything that can be chosen when mounting a device.
+#[derive(Default)]
+pub struct MountOptions {
+    // ignored on filesystems without a journal, and with data checksums, which
+    // always use `DataMode::Journal`
+    pub data_mode: DataMode,
+    // read the superblock from the backup in this block instead of block 0
+    pub backup: Option<BlockPointer>,
+    // refuse all writes, a dirty journal is only replayed into memory
+    pub read_only: bool,
+}
+
+/// How much of a mounted filesystem is in use.
+pub struct Usage {
+    pub block_size: usize,
+    pub block_count: u64,
+    pub free_blocks: u64,
+    pub inode_count: u64,
+    pub free_inodes: u64,
+}
+
+/// Where everything ended up on a formatted device.
+pub struct Layout {
+    pub sector_size: usize,
+    pub sector_count: u64,
+    pub block_size: usize,
+    pub block_count: u64,
+    pub block_map_blocks: u64,
+    pub inode_size: usize,
+    pub inode_count: u64,
+    pub inode_table_blocks: u64,
+    pub journal_blocks: u64,
+    pub checksum_blocks: u64,
+    pub backups: Vec<BlockPointer>,
+    pub features: u32,
+    pub label: String,
+    pub uuid: Uuid,
+}
+
+impl Display for Layout {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let device_size = self.sector_size as u64 * self.sector_count;
+        let blocks = |count: u64| {
+            let size = pretty_size_from_bytes(count * self.block_size as u64);
+            format!("{} blocks ({})", count, size)
+        };
+
+        writeln!(f, "Label:        {}", self.label)?;
+        writeln!(f, "UUID:         {}", uuid::to_string(&self.uuid))?;
+        writeln!(f, "Features:     {:#x}", self.features)?;
+        writeln!(f, "Device size:  {}", pretty_size_from_bytes(device_size))?;
+        writeln!(f, "Sector size:  {}", self.sector_size)?;
+        writeln!(f, "Sector count: {}", self.sector_count)?;
+        writeln!(f, "Block size:   {}", self.block_size)?;
+        writeln!(f, "Block count:  {}", self.block_count)?;
+        writeln!(f, "Inode size:   {}", self.inode_size)?;
+        writeln!(f, "Inode count:  {}", self.inode_count)?;
+        writeln!(f, "Block map:    {}", blocks(self.block_map_blocks))?;
+        writeln!(f, "Inode table:  {}", blocks(self.inode_table_blocks))?;
+        writeln!(f, "Journal:      {}", blocks(self.journal_blocks))?;
+        writeln!(f, "Checksums:    {}", blocks(self.checksum_blocks))?;
+        let backups: Vec<String> = self.backups.iter().map(u64::to_string).collect();
+        write!(f, "Backups:      {}", backups.join(", "))
+    }
+}
@@ -1,153 +1,626 @@
-use crate::consts::{BlockPointer, SUPERBLOCK_SIZE};
+use crate::consts::{BlockPointer, MAX_JOURNAL_BLOCKS, MIN_JOURNAL_BLOCKS, SUPERBLOCK_SIZE};
 use crate::io::IO;
 use crate::structure::blockmap::BlockMap;
+use crate::structure::checksum_table::ChecksumTable;
 use crate::structure::inode::{Inode, InodeId};
 use crate::structure::inode_table::InodeTable;
-use crate::structure::superblock::SuperBlock;
-use crate::util::format::pretty_size_from_bytes;
+use crate::structure::journal::Journal;
+use crate::structure::layout::{DataMode, FormatOptions, Layout, MountOptions, Usage};
+use crate::structure::superblock::{
+    SuperBlock, FEATURE_DATA_CHECKSUMS, FEATURE_EXTENTS, FEATURE_JOURNAL,
+};
+use crate::util::checksum::checksum;
+use crate::util::error::{Error, Location};
 use crate::util::serializable::{ByteSerializable, KnownSize};
 
 pub(crate) mod blockmap;
+mod checksum_table;
+pub(crate) mod extents;
 pub(crate) mod inode;
 mod inode_table;
+pub(crate) mod journal;
+pub(crate) mod layout;
+pub(crate) mod pointers;
 pub(crate) mod superblock;
 
+// journal blocks a transaction may need on top of those for the file contents it
+// writes: the inode, the superblock, new index blocks and the partial blocks at either
+// end
+const TRANSACTION_OVERHEAD: usize = 8;
+
 pub struct Structure<META: ByteSerializable + KnownSize> {
     io: IO,
     pub(crate) super_block: SuperBlock,
     pub(crate) block_map: BlockMap,
     pub(crate) inode_table: InodeTable<META>,
+    checksum_table: Option<ChecksumTable>,
+    // the free counts in block 0, which `mount` does not trust, and in the backups
+    stored_free_counts: (u64, u64),
+    backup_free_counts: (u64, u64),
+    // the free counts changed since block 0 was last written
+    counts_changed: bool,
+    // the superblock changed since the backups were last written
+    backups_changed: bool,
 }
 
 impl<META: ByteSerializable + KnownSize> Structure<META> {
-    pub fn is_initialized(io: &IO) -> bool {
+    /// Whether the device holds a filesystem, also one of which only a backup of the
+    /// superblock is left.
+    pub fn is_initialized(io: &mut IO) -> Result<bool, Error> {
         match SuperBlock::read(io) {
-            Some(_) => true,
-            None => false,
+            Ok(Some(_)) => Ok(true),
+            Err(error) if error.errno() != libc::EUCLEAN => Err(error),
+            result => match SuperBlock::find_backup(io) {
+                Some(_) => Ok(true),
+                None => result.map(|super_block| super_block.is_some()),
+            },
         }
     }
 
-    pub fn new(mut io: IO, block_size: usize) -> Structure<META> {
+    /// Writes a fresh superblock, block map and inode table to the device.
+    pub fn format(mut io: IO, options: &FormatOptions) -> Result<Structure<META>, Error> {
+        let block_size = options.block_size;
         if block_size < io.get_sector_size() {
-            panic!("Block size must be greater than or equal to sector size");
+            return Err(Error::new(
+                "Block size must be greater than or equal to sector size",
+                libc::EINVAL,
+            ));
         }
 
-        if block_size % io.get_sector_size() != 0 {
-            panic!("Block size must be a multiple of sector size");
+        if !block_size.is_multiple_of(io.get_sector_size()) {
+            return Err(Error::new(
+                "Block size must be a multiple of sector size",
+                libc::EINVAL,
+            ));
         }
 
-        println!("Building structure...");
-        println!("Inode size: {}", Inode::<META>::size_on_disk());
-        println!(
-            "Drive size: {}",
-            pretty_size_from_bytes(
-                io.drive.get_sector_size() as u64 * io.drive.get_sector_count() as u64
-            )
-        );
-        println!("Sector size: {}", io.get_sector_size());
-        println!("Sector count: {}", io.get_sector_count());
-        println!("Block size: {}", block_size);
-        println!("Block count: {}", io.block_count);
-
         io.set_block_size(block_size);
+        let journal_blocks = Structure::<META>::journal_size(io.block_count, options);
+        if journal_blocks != 0 && journal_blocks < 4 {
+            return Err(Error::new(
+                "The journal needs at least 4 blocks",
+                libc::EINVAL,
+            ));
+        }
         let mut super_block = SuperBlock::new(block_size, io.block_count);
-        super_block.write(&mut io);
+        super_block.features = options.features;
+        super_block.uuid = options.uuid;
+        super_block.label = options.label.clone();
+        super_block.write(&mut io)?;
 
         let mut block_map = BlockMap::new(
-            (SUPERBLOCK_SIZE / io.get_block_size()) as u64,
+            Structure::<META>::block_map_index(block_size),
             super_block.block_count,
             block_size,
         );
-        block_map.write_full(&mut io);
-
-        println!(
-            "Block map size: {}",
-            pretty_size_from_bytes(
-                (block_map.last_block - block_map.first_block + 1) * block_size as u64
-            )
-        );
-        println!(
-            "Block map blocks: {}",
-            block_map.last_block - block_map.first_block + 1
-        );
+        block_map.write_full(&mut io)?;
 
         let inode_index = block_map.last_block + 1;
-        let inode_table = InodeTable::create(inode_index, &mut io);
+        let inode_count = InodeTable::<META>::calculate_inode_count(
+            super_block.block_count,
+            block_size,
+            options.bytes_per_inode,
+        );
+        let mut inode_table = InodeTable::create(inode_index, &mut io, inode_count)?;
+        // inode 0 is never handed out, which puts the root directory at FUSE_ROOT_ID
+        inode_table.mark_used(&mut io, 0)?;
         for i in 0..inode_table.block_count {
-            block_map.mark_used(&mut io, inode_index + i as u64);
+            block_map.mark_used(&mut io, inode_index + i as u64)?;
         }
-        super_block.set_inode_count(&mut io, inode_table.inode_count);
+        super_block.set_inode_count(&mut io, inode_table.inode_count)?;
 
-        println!(
-            "Inode table size: {}",
-            pretty_size_from_bytes(inode_table.block_count as u64 * block_size as u64)
-        );
-        println!("Inode table blocks: {}", inode_table.block_count);
+        if journal_blocks != 0 {
+            let journal_start = inode_index + inode_table.block_count as u64;
+            let mut journal = Journal::create(&mut io, journal_start, journal_blocks)?;
+            journal.data_mode = Structure::<META>::data_mode(options.features, DataMode::default());
+            for i in 0..journal_blocks {
+                block_map.mark_used(&mut io, journal_start + i)?;
+            }
+            io.set_journal(journal);
+            super_block.features |= FEATURE_JOURNAL;
+            super_block.journal_start = journal_start;
+            super_block.journal_blocks = journal_blocks;
+        }
 
-        Structure {
+        let mut checksum_table = None;
+        if super_block.features & FEATURE_DATA_CHECKSUMS != 0 {
+            let table_start = inode_index + inode_table.block_count as u64 + journal_blocks;
+            let table = ChecksumTable::create(&mut io, table_start, super_block.block_count)?;
+            for i in 0..table.block_count {
+                block_map.mark_used(&mut io, table_start + i)?;
+            }
+            checksum_table = Some(table);
+        }
+
+        let first_data_block = inode_index
+            + inode_table.block_count as u64
+            + journal_blocks
+            + checksum_table.as_ref().map_or(0, |table| table.block_count);
+        super_block.backups =
+            SuperBlock::backup_locations(super_block.block_count, first_data_block);
+        for location in &super_block.backups {
+            block_map.mark_used(&mut io, *location)?;
+        }
+        super_block.free_blocks = block_map.count_free();
+        super_block.free_inodes = inode_table.count_free();
+        super_block.write(&mut io)?;
+
+        Ok(Structure {
             io,
+            stored_free_counts: (super_block.free_blocks, super_block.free_inodes),
+            backup_free_counts: (super_block.free_blocks, super_block.free_inodes),
             super_block,
             block_map,
             inode_table,
+            checksum_table,
+            counts_changed: false,
+            backups_changed: false,
+        })
+    }
+
+    pub fn mount(mut io: IO, options: &MountOptions) -> Result<Structure<META>, Error> {
+        let mut super_block = Structure::<META>::read_super_block(&mut io, options)?;
+        io.set_block_size(super_block.block_size);
+        io.set_read_only(options.read_only);
+        if super_block.features & FEATURE_JOURNAL != 0 {
+            let (mut journal, replayed) = Journal::open(
+                &mut io,
+                super_block.journal_start,
+                super_block.journal_blocks,
+                options.read_only,
+            )?;
+            journal.data_mode =
+                Structure::<META>::data_mode(super_block.features, options.data_mode);
+            io.set_journal(journal);
+            if replayed {
+                // the superblock may have been part of the transaction
+                super_block = Structure::<META>::read_super_block(&mut io, options)?;
+            }
+        }
+        let block_map = BlockMap::read(
+            &io,
+            Structure::<META>::block_map_index(super_block.block_size),
+        )?;
+        let inode_table = InodeTable::read(&io, block_map.last_block + 1, super_block.inode_count)?;
+        let checksum_table = (super_block.features & FEATURE_DATA_CHECKSUMS != 0).then(|| {
+            ChecksumTable::new(
+                block_map.last_block
+                    + 1
+                    + inode_table.block_count as u64
+                    + super_block.journal_blocks,
+                super_block.block_count,
+                super_block.block_size,
+            )
+        });
+        // the bitmaps are authoritative, stale counters are corrected in memory and
+        // written out with the next allocation, until then `check` reports them
+        let stored_free_counts = (super_block.free_blocks, super_block.free_inodes);
+        super_block.free_blocks = block_map.count_free();
+        super_block.free_inodes = inode_table.count_free();
+        Ok(Structure {
+            io,
+            super_block,
+            block_map,
+            inode_table,
+            checksum_table,
+            stored_free_counts,
+            backup_free_counts: stored_free_counts,
+            counts_changed: false,
+            backups_changed: false,
+        })
+    }
+
+    // Reads the superblock from block 0, or from the backup chosen in `options`. A
+    // damaged superblock points to the backup to recover from.
+    fn read_super_block(io: &mut IO, options: &MountOptions) -> Result<SuperBlock, Error> {
+        if let Some(location) = options.backup {
+            return SuperBlock::read_backup(io, location);
+        }
+        match SuperBlock::read(io) {
+            Ok(Some(super_block)) => Ok(super_block),
+            Err(error) if error.errno() != libc::EUCLEAN => Err(error),
+            result => match SuperBlock::find_backup(io) {
+                Some(location) => Err(Error::new(
+                    &format!(
+                        "The superblock is damaged, a backup is at block {}",
+                        location
+                    ),
+                    libc::EUCLEAN,
+                )),
+                None => result?.ok_or_else(|| Error::new("No superblock found", libc::EINVAL)),
+            },
+        }
+    }
+
+    // Overwritten contents and their checksums must reach the device together, so
+    // filesystems with data checksums journal the contents as well.
+    fn data_mode(features: u32, requested: DataMode) -> DataMode {
+        match features & FEATURE_DATA_CHECKSUMS {
+            0 => requested,
+            _ => DataMode::Journal,
+        }
+    }
+
+    /// The number of blocks `format` reserves for its own structures on a device of
+    /// `block_count` blocks.
+    pub fn reserved_blocks(block_count: u64, options: &FormatOptions) -> u64 {
+        let block_size = options.block_size;
+        let inode_count = InodeTable::<META>::calculate_inode_count(
+            block_count,
+            block_size,
+            options.bytes_per_inode,
+        );
+        // the inode table starts one block after the end of the block map
+        let first_data_block = Structure::<META>::block_map_index(block_size)
+            + BlockMap::size_in_blocks(block_count, block_size)
+            + 1
+            + InodeTable::<META>::size_in_blocks(inode_count, block_size)
+            + Structure::<META>::journal_size(block_count, options)
+            + Structure::<META>::checksum_table_size(block_count, options);
+        first_data_block + SuperBlock::backup_locations(block_count, first_data_block).len() as u64
+    }
+
+    // the journal takes 1/64 of the device unless its size was given
+    fn journal_size(block_count: u64, options: &FormatOptions) -> u64 {
+        options
+            .journal_blocks
+            .unwrap_or_else(|| (block_count / 64).clamp(MIN_JOURNAL_BLOCKS, MAX_JOURNAL_BLOCKS))
+    }
+
+    fn checksum_table_size(block_count: u64, options: &FormatOptions) -> u64 {
+        match options.features & FEATURE_DATA_CHECKSUMS {
+            0 => 0,
+            _ => ChecksumTable::size_in_blocks(block_count, options.block_size),
         }
     }
 
-    pub fn mount(mut io: IO) -> Structure<META> {
-        match SuperBlock::read(&mut io) {
-            Some(super_block) => {
-                io.set_block_size(super_block.block_size);
-                let block_map =
-                    BlockMap::read(&mut io, (SUPERBLOCK_SIZE / super_block.block_size) as u64);
-                let inode_table =
-                    InodeTable::read(&mut io, block_map.last_block + 1, super_block.inode_count);
-                Structure {
-                    io,
-                    super_block,
-                    block_map,
-                    inode_table,
+    /// Block and inode totals and how many of them are still free.
+    pub fn usage(&self) -> Usage {
+        Usage {
+            block_size: self.super_block.block_size,
+            block_count: self.super_block.block_count,
+            free_blocks: self.super_block.free_blocks,
+            inode_count: self.inode_table.inode_count,
+            free_inodes: self.super_block.free_inodes,
+        }
+    }
+
+    /// Describes how the device is laid out.
+    pub fn layout(&self) -> Layout {
+        Layout {
+            sector_size: self.io.get_sector_size(),
+            sector_count: self.io.get_sector_count(),
+            block_size: self.super_block.block_size,
+            block_count: self.super_block.block_count,
+            block_map_blocks: self.block_map.last_block - self.block_map.first_block + 1,
+            inode_size: InodeTable::<META>::record_size(),
+            inode_count: self.inode_table.inode_count,
+            inode_table_blocks: self.inode_table.block_count as u64,
+            journal_blocks: self.super_block.journal_blocks,
+            checksum_blocks: self.checksum_table_blocks(),
+            backups: self.super_block.backups.clone(),
+            features: self.super_block.features,
+            label: self.super_block.label.clone(),
+            uuid: self.super_block.uuid,
+        }
+    }
+
+    // the block map starts right after the superblock
+    fn block_map_index(block_size: usize) -> BlockPointer {
+        SUPERBLOCK_SIZE.div_ceil(block_size) as BlockPointer
+    }
+
+    /// Brings the backups of the superblock up to date and flushes everything to the
+    /// device.
+    pub fn sync(&mut self) -> Result<(), Error> {
+        if self.backups_changed {
+            self.begin();
+            match self.write_super_block() {
+                Ok(()) => self.commit()?,
+                Err(error) => {
+                    self.abort()?;
+                    return Err(error);
                 }
             }
-            None => panic!("No superblock found"),
         }
+        self.io.sync()
     }
 
-    pub fn set_root_inode(&mut self, inode: &mut Inode<META>) {
-        self.super_block
-            .set_root_inode(&mut self.io, inode.id.unwrap());
+    /// Groups all writes until the matching `commit` into one journal transaction.
+    /// Transactions nest, only the outermost one is committed.
+    pub fn begin(&mut self) {
+        self.io.begin()
     }
 
-    pub fn get_root_inode(&self) -> Inode<META> {
+    /// Writes the free counts to block 0 if they changed and commits the transaction.
+    pub fn commit(&mut self) -> Result<(), Error> {
+        if self.counts_changed {
+            if let Err(error) = self.super_block.write_primary(&mut self.io) {
+                self.abort()?;
+                return Err(error);
+            }
+            self.stored_free_counts = (self.super_block.free_blocks, self.super_block.free_inodes);
+            self.counts_changed = false;
+        }
+        self.io.commit()
+    }
+
+    /// Drops the open transaction and reloads the bitmaps and free counts, which the
+    /// failed operation may have changed in memory only.
+    pub fn abort(&mut self) -> Result<(), Error> {
+        self.io.abort();
+        self.block_map = BlockMap::read(&self.io, self.block_map.first_block)?;
+        self.inode_table = InodeTable::read(
+            &self.io,
+            self.block_map.last_block + 1,
+            self.inode_table.inode_count,
+        )?;
+        self.super_block.free_blocks = self.block_map.count_free();
+        self.super_block.free_inodes = self.inode_table.count_free();
+        // only what was committed before is still pending
+        let counts = (self.super_block.free_blocks, self.super_block.free_inodes);
+        self.counts_changed &= counts != self.stored_free_counts;
+        self.backups_changed &= counts != self.backup_free_counts;
+        Ok(())
+    }
+
+    /// How many blocks of file contents one transaction can write or free without
+    /// outgrowing the journal, at least one. Unlimited without a journal.
+    pub fn blocks_per_transaction(&self) -> u64 {
+        let Some(journal) = self.io.journal() else {
+            return u64::MAX;
+        };
+        // each block may need its own block map and index block, its checksum and,
+        // with `DataMode::Journal`, its contents in the journal
+        let mut per_block = 2;
+        if self.has_data_checksums() {
+            per_block += 1;
+        }
+        if journal.data_mode == DataMode::Journal {
+            per_block += 1;
+        }
+        let capacity = journal.capacity(self.get_block_size());
+        (capacity.saturating_sub(TRANSACTION_OVERHEAD) / per_block).max(1) as u64
+    }
+
+    pub fn set_root_inode(&mut self, inode: &mut Inode<META>) -> Result<(), Error> {
+        self.super_block.root_inode = inode
+            .id
+            .ok_or(Error::new("Inode was never written", libc::EINVAL))?;
+        self.write_super_block()
+    }
+
+    pub fn get_root_inode(&self) -> Result<Inode<META>, Error> {
         self.inode_table
             .read_inode(&self.io, self.super_block.root_inode)
     }
 
-    pub fn create_inode(&mut self, meta: META) -> Inode<META> {
-        let mut inode = Inode::new(meta);
-        self.inode_table.write_inode(&mut self.io, &mut inode);
-        inode
+    pub fn create_inode(&mut self, meta: META) -> Result<Inode<META>, Error> {
+        let mut inode = if self.super_block.features & FEATURE_EXTENTS != 0 {
+            Inode::with_extents(meta)
+        } else {
+            Inode::new(meta)
+        };
+        self.inode_table.write_inode(&mut self.io, &mut inode)?;
+        self.update_free_counts(0, -1);
+        Ok(inode)
+    }
+
+    /// Creates an inode that keeps `data` inline instead of in data blocks.
+    pub fn create_inline_inode(&mut self, meta: META, data: &[u8]) -> Result<Inode<META>, Error> {
+        let mut inode = Inode::with_inline_data(meta, data)?;
+        self.inode_table.write_inode(&mut self.io, &mut inode)?;
+        self.update_free_counts(0, -1);
+        Ok(inode)
     }
 
-    pub fn read_inode(&self, id: InodeId) -> Inode<META> {
+    pub fn read_inode(&self, id: InodeId) -> Result<Inode<META>, Error> {
         self.inode_table.read_inode(&self.io, id)
     }
 
-    pub fn write_inode(&mut self, inode: &mut Inode<META>) {
-        self.inode_table.write_inode(&mut self.io, inode);
+    pub fn write_inode(&mut self, inode: &mut Inode<META>) -> Result<(), Error> {
+        self.inode_table.write_inode(&mut self.io, inode)
+    }
+
+    /// Frees all data blocks of `inode` and returns its slot in the inode table.
+    pub fn free_inode(&mut self, inode: &mut Inode<META>) -> Result<(), Error> {
+        let id = inode
+            .id
+            .ok_or(Error::new("Inode was never written", libc::EINVAL))?;
+        inode.truncate(self, 0)?;
+        if self.inode_table.is_free(id) {
+            return Ok(());
+        }
+        self.inode_table.mark_free(&mut self.io, id)?;
+        self.update_free_counts(0, 1);
+        Ok(())
     }
 
     pub fn get_block_size(&self) -> usize {
         self.super_block.block_size
     }
 
-    pub fn allocate_block(&mut self) -> Option<BlockPointer> {
-        self.block_map.allocate(&mut self.io)
+    pub fn block_count(&self) -> u64 {
+        self.super_block.block_count
+    }
+
+    pub fn inode_count(&self) -> u64 {
+        self.inode_table.inode_count
+    }
+
+    /// The first block after the superblock, block map, inode table, journal and
+    /// checksum table.
+    pub fn first_data_block(&self) -> BlockPointer {
+        self.block_map.last_block
+            + 1
+            + self.inode_table.block_count as u64
+            + self.super_block.journal_blocks
+            + self.checksum_table_blocks()
+    }
+
+    /// Whether the block belongs to the filesystem's own structures, which includes
+    /// the backups of the superblock in the data area.
+    pub fn is_reserved_block(&self, index: BlockPointer) -> bool {
+        index < self.first_data_block() || self.super_block.backups.contains(&index)
+    }
+
+    /// Writes the superblock to block 0 and all backups again, which repairs the
+    /// primary after mounting from a backup.
+    pub fn write_super_block(&mut self) -> Result<(), Error> {
+        self.super_block.write(&mut self.io)?;
+        self.stored_free_counts = (self.super_block.free_blocks, self.super_block.free_inodes);
+        self.backup_free_counts = self.stored_free_counts;
+        self.counts_changed = false;
+        self.backups_changed = false;
+        Ok(())
     }
 
-    pub fn write_block(&mut self, index: BlockPointer, block: &Vec<u8>) {
-        self.io.write_block(index, block);
+    /// The free block and inode counts as last written to block 0. They only differ
+    /// from `usage` while changes are pending, or if the stored ones were wrong.
+    pub fn stored_free_counts(&self) -> (u64, u64) {
+        self.stored_free_counts
     }
 
-    pub fn read_block(&self, index: BlockPointer) -> Vec<u8> {
+    fn checksum_table_blocks(&self) -> u64 {
+        self.checksum_table
+            .as_ref()
+            .map_or(0, |table| table.block_count)
+    }
+
+    pub fn is_block_free(&self, index: BlockPointer) -> bool {
+        self.block_map.is_free(index)
+    }
+
+    pub fn mark_block_used(&mut self, index: BlockPointer) -> Result<(), Error> {
+        self.check_block(index)?;
+        if !self.block_map.is_free(index) {
+            return Ok(());
+        }
+        self.block_map.mark_used(&mut self.io, index)?;
+        self.update_free_counts(-1, 0);
+        Ok(())
+    }
+
+    pub fn is_inode_free(&self, id: InodeId) -> bool {
+        self.inode_table.is_free(id)
+    }
+
+    pub fn allocate_block(&mut self) -> Result<BlockPointer, Error> {
+        let index = self.block_map.allocate(&mut self.io)?;
+        self.update_free_counts(-1, 0);
+        Ok(index)
+    }
+
+    /// Allocates a run of up to `count` contiguous blocks, starting the search at `goal`.
+    pub fn allocate_blocks(
+        &mut self,
+        goal: BlockPointer,
+        count: u64,
+    ) -> Result<(BlockPointer, u64), Error> {
+        let (start, length) = self.block_map.allocate_run(&mut self.io, goal, count)?;
+        self.update_free_counts(-(length as i64), 0);
+        Ok((start, length))
+    }
+
+    pub fn free_block(&mut self, index: BlockPointer) -> Result<(), Error> {
+        self.check_block(index)?;
+        if self.block_map.is_free(index) {
+            return Ok(());
+        }
+        self.block_map.mark_free(&mut self.io, index)?;
+        self.update_free_counts(1, 0);
+        Ok(())
+    }
+
+    pub fn write_block(&mut self, index: BlockPointer, block: &[u8]) -> Result<(), Error> {
+        self.io.write_block(index, block)
+    }
+
+    /// Writes a block of file contents, see `DataMode` for how it is journaled.
+    pub fn write_data_block(&mut self, index: BlockPointer, block: &[u8]) -> Result<(), Error> {
+        self.io.write_data_block(index, block)?;
+        self.record_checksum(index, block)
+    }
+
+    /// Records the checksum of a data block that was just written, if the filesystem
+    /// keeps them. The table goes through the journal along with the contents, which
+    /// `mount` ensures by always using `DataMode::Journal` on such filesystems.
+    pub fn record_checksum(&mut self, index: BlockPointer, block: &[u8]) -> Result<(), Error> {
+        match &self.checksum_table {
+            Some(table) => table.set(&mut self.io, index, checksum(block, index)),
+            None => Ok(()),
+        }
+    }
+
+    /// The checksum recorded for data block `index`, `None` if the filesystem keeps no
+    /// checksums or none was recorded for the block.
+    pub fn data_checksum(&self, index: BlockPointer) -> Result<Option<u32>, Error> {
+        match &self.checksum_table {
+            Some(table) => Ok(Some(table.get(&self.io, index)?).filter(|crc| *crc != 0)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn has_data_checksums(&self) -> bool {
+        self.checksum_table.is_some()
+    }
+
+    /// Reads a data block and checks it against its recorded checksum. Mismatches are
+    /// counted along with those of the metadata.
+    pub fn read_data_block(&self, index: BlockPointer) -> Result<Vec<u8>, Error> {
+        let block = self.io.read_block(index)?;
+        match self.data_checksum(index)? {
+            Some(expected) if expected != checksum(&block, index) => {
+                self.io.count_checksum_error();
+                Err(Error::new(
+                    &format!("Data checksum mismatch in block {}", index),
+                    libc::EIO,
+                ))
+            }
+            _ => Ok(block),
+        }
+    }
+
+    /// Reads one copy of a block from a mirrored device, see `DeviceDriver::copies`.
+    pub fn read_block_copy(&self, index: BlockPointer, copy: usize) -> Result<Vec<u8>, Error> {
+        self.io.read_block_copy(index, copy)
+    }
+
+    pub fn copies(&self) -> usize {
+        self.io.copies()
+    }
+
+    /// Checks a structure sealed with `checksum::seal`, see `IO::verify`.
+    pub fn verify(&self, bytes: &[u8], seed: u64, location: Location) -> Result<(), Error> {
+        self.io.verify(bytes, seed, location)
+    }
+
+    /// The checksum mismatches found since mounting.
+    pub fn checksum_errors(&self) -> u64 {
+        self.io.checksum_errors()
+    }
+
+    pub fn read_block(&self, index: BlockPointer) -> Result<Vec<u8>, Error> {
         self.io.read_block(index)
     }
+
+    // the counts are written to block 0 on commit and to the backups on sync
+    fn update_free_counts(&mut self, blocks: i64, inodes: i64) {
+        let super_block = &mut self.super_block;
+        super_block.free_blocks = super_block.free_blocks.saturating_add_signed(blocks);
+        super_block.free_inodes = super_block.free_inodes.saturating_add_signed(inodes);
+        self.counts_changed = true;
+        self.backups_changed = true;
+    }
+
+    // a corrupted block map entry must not let us touch bits past the end of the map
+    fn check_block(&self, index: BlockPointer) -> Result<(), Error> {
+        if index >= self.super_block.block_count {
+            return Err(Error::corrupted(
+                &format!("Block {} out of range", index),
+                Location::Block(index),
+            ));
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,319 @@
+use crate::consts::{BlockPointer, DIRECT_POINTERS, INDIRECT_POINTERS};
+use crate::consts::{DirectPointers, IndirectPointers};
+use crate::structure::inode::BlockUse;
+use crate::structure::Structure;
+use crate::util::error::Error;
+use crate::util::serializable::{ByteSerializable, KnownSize};
+use std::mem::size_of;
+
+pub(crate) const NULL_POINTER: BlockPointer = 0;
+
+// Where a data block's pointer lives: a slot in the inode itself, or a level of
+// indirection plus the slot to follow in each pointer block along the way.
+pub(crate) enum PointerPath {
+    Direct(usize),
+    Indirect(usize, Vec<usize>),
+}
+
+/// Classic block map: twelve direct pointers followed by single, double and triple
+/// indirect pointer blocks.
+#[derive(Debug, PartialEq, Clone)]
+pub struct BlockPointers {
+    pub(crate) direct: DirectPointers,
+    // single, double and triple indirect pointer blocks, in that order
+    pub(crate) indirect: IndirectPointers,
+}
+
+impl BlockPointers {
+    pub fn new() -> BlockPointers {
+        BlockPointers {
+            direct: [NULL_POINTER; DIRECT_POINTERS],
+            indirect: [NULL_POINTER; INDIRECT_POINTERS],
+        }
+    }
+
+    pub fn size_on_disk() -> usize {
+        size_of::<DirectPointers>() + size_of::<IndirectPointers>()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = BlockPointers::pointers_to_bytes(&self.direct);
+        bytes.extend_from_slice(&BlockPointers::pointers_to_bytes(&self.indirect));
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> BlockPointers {
+        let (direct_bytes, indirect_bytes) = bytes.split_at(size_of::<DirectPointers>());
+        BlockPointers {
+            direct: BlockPointers::bytes_to_pointers(direct_bytes),
+            indirect: BlockPointers::bytes_to_pointers(indirect_bytes),
+        }
+    }
+
+    /// The largest number of data blocks that can be addressed.
+    pub fn max_blocks(block_size: usize) -> u64 {
+        let per_block = BlockPointers::pointers_per_block(block_size);
+        DIRECT_POINTERS as u64 + per_block + per_block.pow(2) + per_block.pow(3)
+    }
+
+    /// Resolves the `index`th data block, walking the indirect pointer blocks as needed.
+    pub fn get<META: ByteSerializable + KnownSize>(
+        &self,
+        structure: &Structure<META>,
+        index: u64,
+    ) -> Result<BlockPointer, Error> {
+        match BlockPointers::locate(index, structure.get_block_size())? {
+            PointerPath::Direct(slot) => Ok(self.direct[slot]),
+            PointerPath::Indirect(level, path) => {
+                let mut current = self.indirect[level];
+                for slot in path {
+                    current = BlockPointers::read_pointer(structure, current, slot)?;
+                }
+                Ok(current)
+            }
+        }
+    }
+
+    /// Stores `pointer` as the `index`th data block, allocating any indirect pointer
+    /// blocks on the way that do not exist yet.
+    pub fn set<META: ByteSerializable + KnownSize>(
+        &mut self,
+        structure: &mut Structure<META>,
+        index: u64,
+        pointer: BlockPointer,
+    ) -> Result<(), Error> {
+        match BlockPointers::locate(index, structure.get_block_size())? {
+            PointerPath::Direct(slot) => self.direct[slot] = pointer,
+            PointerPath::Indirect(level, path) => {
+                if self.indirect[level] == NULL_POINTER {
+                    self.indirect[level] = BlockPointers::allocate_pointer_block(structure)?;
+                }
+
+                let mut current = self.indirect[level];
+                for (depth, slot) in path.iter().enumerate() {
+                    let mut block = structure.read_block(current)?;
+                    if depth == path.len() - 1 {
+                        BlockPointers::write_pointer(&mut block, *slot, pointer);
+                        structure.write_block(current, &block)?;
+                    } else {
+                        let mut next = BlockPointers::read_pointer_from(&block, *slot);
+                        if next == NULL_POINTER {
+                            next = BlockPointers::allocate_pointer_block(structure)?;
+                            BlockPointers::write_pointer(&mut block, *slot, next);
+                            structure.write_block(current, &block)?;
+                        }
+                        current = next;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Forgets the `index`th data block, which must be the last one in use. Indirect
+    /// pointer blocks left empty by this are freed.
+    pub fn clear<META: ByteSerializable + KnownSize>(
+        &mut self,
+        structure: &mut Structure<META>,
+        index: u64,
+    ) -> Result<(), Error> {
+        match BlockPointers::locate(index, structure.get_block_size())? {
+            PointerPath::Direct(slot) => self.direct[slot] = NULL_POINTER,
+            PointerPath::Indirect(level, path) => {
+                let mut tables = vec![self.indirect[level]];
+                for slot in &path[..path.len() - 1] {
+                    let table = *tables.last().unwrap();
+                    tables.push(BlockPointers::read_pointer(structure, table, *slot)?);
+                }
+
+                // blocks are only ever removed from the end, so a table is empty once
+                // its first slot is cleared
+                for depth in (0..path.len()).rev() {
+                    if path[depth] == 0 {
+                        structure.free_block(tables[depth])?;
+                    } else {
+                        let mut block = structure.read_block(tables[depth])?;
+                        BlockPointers::write_pointer(&mut block, path[depth], NULL_POINTER);
+                        return structure.write_block(tables[depth], &block);
+                    }
+                }
+                self.indirect[level] = NULL_POINTER;
+            }
+        }
+        Ok(())
+    }
+
+    /// Calls `visit` for every block the map refers to. Pointer blocks are only read
+    /// if `visit` accepts them.
+    pub fn walk<META: ByteSerializable + KnownSize>(
+        &self,
+        structure: &Structure<META>,
+        visit: &mut dyn FnMut(BlockUse) -> bool,
+    ) -> Result<(), Error> {
+        for (slot, pointer) in self.direct.iter().enumerate() {
+            if *pointer != NULL_POINTER {
+                visit(BlockUse::Data(slot as u64, *pointer));
+            }
+        }
+
+        let per_block = BlockPointers::pointers_per_block(structure.get_block_size());
+        let mut first = DIRECT_POINTERS as u64;
+        for (level, pointer) in self.indirect.iter().enumerate() {
+            if *pointer != NULL_POINTER {
+                BlockPointers::walk_table(structure, *pointer, level as u32, first, visit)?;
+            }
+            first += per_block.pow(level as u32 + 1);
+        }
+        Ok(())
+    }
+
+    // `first` is the index of the first data block reachable through `table`, which
+    // has `level` more pointer blocks below it.
+    fn walk_table<META: ByteSerializable + KnownSize>(
+        structure: &Structure<META>,
+        table: BlockPointer,
+        level: u32,
+        first: u64,
+        visit: &mut dyn FnMut(BlockUse) -> bool,
+    ) -> Result<(), Error> {
+        if !visit(BlockUse::Node(table)) {
+            return Ok(());
+        }
+
+        let per_block = BlockPointers::pointers_per_block(structure.get_block_size());
+        let block = structure.read_block(table)?;
+        for slot in 0..per_block as usize {
+            let pointer = BlockPointers::read_pointer_from(&block, slot);
+            if pointer == NULL_POINTER {
+                continue;
+            }
+            let index = first + slot as u64 * per_block.pow(level);
+            if level == 0 {
+                visit(BlockUse::Data(index, pointer));
+            } else {
+                BlockPointers::walk_table(structure, pointer, level - 1, index, visit)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn locate(index: u64, block_size: usize) -> Result<PointerPath, Error> {
+        if index < DIRECT_POINTERS as u64 {
+            return Ok(PointerPath::Direct(index as usize));
+        }
+
+        let per_block = BlockPointers::pointers_per_block(block_size);
+        let mut remaining = index - DIRECT_POINTERS as u64;
+        for level in 0..INDIRECT_POINTERS {
+            let capacity = per_block.pow(level as u32 + 1);
+            if remaining < capacity {
+                let path = (0..=level as u32)
+                    .rev()
+                    .map(|depth| ((remaining / per_block.pow(depth)) % per_block) as usize)
+                    .collect();
+                return Ok(PointerPath::Indirect(level, path));
+            }
+            remaining -= capacity;
+        }
+
+        Err(Error::new(
+            &format!(
+                "File cannot be larger than {} blocks",
+                BlockPointers::max_blocks(block_size)
+            ),
+            libc::EFBIG,
+        ))
+    }
+
+    #[inline]
+    fn pointers_per_block(block_size: usize) -> u64 {
+        (block_size / size_of::<BlockPointer>()) as u64
+    }
+
+    fn allocate_pointer_block<META: ByteSerializable + KnownSize>(
+        structure: &mut Structure<META>,
+    ) -> Result<BlockPointer, Error> {
+        let block = structure.allocate_block()?;
+        structure.write_block(block, &vec![0; structure.get_block_size()])?;
+        Ok(block)
+    }
+
+    fn read_pointer<META: ByteSerializable + KnownSize>(
+        structure: &Structure<META>,
+        block: BlockPointer,
+        slot: usize,
+    ) -> Result<BlockPointer, Error> {
+        Ok(BlockPointers::read_pointer_from(
+            &structure.read_block(block)?,
+            slot,
+        ))
+    }
+
+    fn read_pointer_from(block: &[u8], slot: usize) -> BlockPointer {
+        let offset = slot * size_of::<BlockPointer>();
+        BlockPointer::from_le_bytes(block[offset..offset + 8].try_into().unwrap())
+    }
+
+    fn write_pointer(block: &mut [u8], slot: usize, pointer: BlockPointer) {
+        let offset = slot * size_of::<BlockPointer>();
+        block[offset..offset + 8].copy_from_slice(&pointer.to_le_bytes());
+    }
+
+    fn bytes_to_pointers<const N: usize>(data: &[u8]) -> [BlockPointer; N] {
+        let mut pointers = [NULL_POINTER; N];
+        for (i, pointer) in pointers.iter_mut().enumerate() {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&data[i * 8..8 + i * 8]);
+            *pointer = u64::from_le_bytes(bytes);
+        }
+        pointers
+    }
+
+    fn pointers_to_bytes(pointers: &[BlockPointer]) -> Vec<u8> {
+        let mut data = Vec::<u8>::new();
+        for pointer in pointers {
+            data.extend_from_slice(&pointer.to_le_bytes());
+        }
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pointers_round_trip() {
+        let pointers = BlockPointers {
+            direct: [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12],
+            indirect: [13, 14, 15],
+        };
+        let bytes = pointers.to_bytes();
+        assert_eq!(bytes.len(), BlockPointers::size_on_disk());
+        assert_eq!(BlockPointers::from_bytes(&bytes), pointers);
+    }
+
+    #[test]
+    fn test_pointers_locate() {
+        fn path(index: u64) -> Vec<usize> {
+            match BlockPointers::locate(index, 512).unwrap() {
+                PointerPath::Direct(slot) => vec![slot],
+                PointerPath::Indirect(level, mut path) => {
+                    path.insert(0, 100 + level);
+                    path
+                }
+            }
+        }
+
+        assert_eq!(path(11), vec![11]);
+        assert_eq!(path(12), vec![100, 0]);
+        assert_eq!(path(12 + 63), vec![100, 63]);
+        assert_eq!(path(12 + 64), vec![101, 0, 0]);
+        assert_eq!(path(12 + 64 + 65), vec![101, 1, 1]);
+        assert_eq!(path(12 + 64 + 64 * 64), vec![102, 0, 0, 0]);
+        assert_eq!(
+            BlockPointers::max_blocks(512),
+            12 + 64 + 64 * 64 + 64 * 64 * 64
+        );
+    }
+}
@@ -1,8 +1,24 @@
-use crate::consts::SUPERBLOCK_SIZE;
+use crate::consts::{BlockPointer, SUPERBLOCK_SIZE};
 use crate::io::IO;
 use crate::structure::inode::InodeId;
+use crate::util::checksum::{is_sealed, seal, CHECKSUM_SIZE};
+use crate::util::error::{Error, Location};
+use crate::util::uuid::Uuid;
 
 const MAGIC: u32 = 0xdeadbeef;
+// the fields and the checksum that follows them
+const SEALED_SIZE: usize = 116 + CHECKSUM_SIZE;
+pub const LABEL_LENGTH: usize = 16;
+const MAX_BACKUPS: usize = 2;
+// the largest block size looked for when searching for a backup
+const MAX_PROBED_BLOCK_SIZE: usize = 64 * 1024;
+
+/// New inodes map their data with extents instead of block pointers.
+pub const FEATURE_EXTENTS: u32 = 0x1;
+/// Metadata writes go through the journal at `journal_start`.
+pub const FEATURE_JOURNAL: u32 = 0x2;
+/// Data blocks have their checksums recorded in a table following the journal.
+pub const FEATURE_DATA_CHECKSUMS: u32 = 0x4;
 
 #[derive(Debug, PartialEq)]
 pub struct SuperBlock {
@@ -11,6 +27,16 @@ pub struct SuperBlock {
     pub block_count: u64,
     pub inode_count: u64,
     pub root_inode: InodeId,
+    pub features: u32,
+    pub uuid: Uuid,
+    // at most `LABEL_LENGTH` bytes, padded with zeros on disk
+    pub label: String,
+    pub free_blocks: u64,
+    pub free_inodes: u64,
+    pub journal_start: u64,
+    pub journal_blocks: u64,
+    // blocks holding copies of the superblock, kept up to date by `write`
+    pub backups: Vec<BlockPointer>,
 }
 
 impl SuperBlock {
@@ -21,38 +47,120 @@ impl SuperBlock {
             block_count,
             inode_count: 0,
             root_inode: 0,
+            features: 0,
+            uuid: [0; 16],
+            label: String::new(),
+            free_blocks: 0,
+            free_inodes: 0,
+            journal_start: 0,
+            journal_blocks: 0,
+            backups: Vec::new(),
         }
     }
 
-    pub fn set_inode_count(&mut self, io: &mut IO, inode_count: u64) {
-        self.inode_count = inode_count;
-        self.write(io);
+    /// Where `format` puts the backups on a device of `block_count` blocks: the middle
+    /// and the last block, unless they are taken by the filesystem's own structures
+    /// in front of `first_data_block`.
+    pub fn backup_locations(block_count: u64, first_data_block: BlockPointer) -> Vec<BlockPointer> {
+        let mut locations = vec![block_count / 2, block_count.saturating_sub(1)];
+        locations.retain(|location| *location >= first_data_block && *location != 0);
+        locations.dedup();
+        locations
     }
 
-    pub fn set_root_inode(&mut self, io: &mut IO, root_inode: InodeId) {
-        self.root_inode = root_inode;
-        self.write(io);
+    pub fn set_inode_count(&mut self, io: &mut IO, inode_count: u64) -> Result<(), Error> {
+        self.inode_count = inode_count;
+        self.write(io)
     }
 
-    pub fn read(io: &IO) -> Option<SuperBlock> {
-        let mut buffer = io.read_block(0);
+    /// Reads the superblock, or returns `None` if the device was never formatted.
+    pub fn read(io: &IO) -> Result<Option<SuperBlock>, Error> {
+        let mut buffer = io.read_block(0)?;
 
         if u32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) != MAGIC {
-            return None;
+            return Ok(None);
         }
 
-        if io.get_block_size() >= SUPERBLOCK_SIZE {
-            Some(SuperBlock::from_buffer(&buffer))
-        } else {
+        if io.get_block_size() < SUPERBLOCK_SIZE {
             let block_count = SUPERBLOCK_SIZE / io.get_block_size();
             for i in 1..(block_count - 1) {
-                buffer.append(&mut io.read_block(i as u64))
+                buffer.append(&mut io.read_block(i as u64)?)
+            }
+        }
+        io.verify(&buffer[..SEALED_SIZE], 0, Location::Block(0))?;
+        let super_block = SuperBlock::from_buffer(&buffer);
+        if !super_block.block_size.is_power_of_two()
+            || super_block.block_size < io.get_sector_size()
+        {
+            return Err(Error::new(
+                &format!(
+                    "Invalid block size {} in superblock",
+                    super_block.block_size
+                ),
+                libc::EUCLEAN,
+            ));
+        }
+        Ok(Some(super_block))
+    }
+
+    /// Reads the backup at `location`. The block size is unknown without the primary
+    /// superblock, so every size the backup may have been written with is tried.
+    pub fn read_backup(io: &mut IO, location: BlockPointer) -> Result<SuperBlock, Error> {
+        let (_, super_block) = SuperBlock::probe(io, |_| location).ok_or_else(|| {
+            Error::new(
+                &format!("No backup superblock found at block {}", location),
+                libc::EINVAL,
+            )
+        })?;
+        Ok(super_block)
+    }
+
+    /// Looks for the backup in the last block of the device and returns its location.
+    pub fn find_backup(io: &mut IO) -> Option<BlockPointer> {
+        let (location, _) = SuperBlock::probe(io, |block_count| block_count.saturating_sub(1))?;
+        Some(location)
+    }
+
+    // Tries every block size with the location `locate` picks for the block count at
+    // that size, and restores the block size afterwards.
+    fn probe(
+        io: &mut IO,
+        locate: impl Fn(u64) -> BlockPointer,
+    ) -> Option<(BlockPointer, SuperBlock)> {
+        let original = io.get_block_size();
+        let mut block_size = io.get_sector_size();
+        let mut found = None;
+        while found.is_none() && block_size <= MAX_PROBED_BLOCK_SIZE {
+            io.set_block_size(block_size);
+            let location = locate(io.get_block_count());
+            if location != 0 && location < io.get_block_count() {
+                found = io
+                    .read_block(location)
+                    .ok()
+                    .and_then(|block| SuperBlock::from_backup(&block, location, block_size))
+                    .map(|super_block| (location, super_block));
             }
-            Some(SuperBlock::from_buffer(&buffer))
+            block_size *= 2;
         }
+        io.set_block_size(original);
+        found
     }
 
-    fn from_buffer(buffer: &Vec<u8>) -> SuperBlock {
+    // The superblock in `block` if it is a backup written to `location` with blocks of
+    // `block_size` bytes.
+    fn from_backup(block: &[u8], location: BlockPointer, block_size: usize) -> Option<SuperBlock> {
+        if block.len() < SEALED_SIZE
+            || block[0..4] != MAGIC.to_le_bytes()
+            || !is_sealed(&block[..SEALED_SIZE], location)
+        {
+            return None;
+        }
+        Some(SuperBlock::from_buffer(block)).filter(|super_block| {
+            super_block.block_size == block_size && super_block.backups.contains(&location)
+        })
+    }
+
+    fn from_buffer(buffer: &[u8]) -> SuperBlock {
         let magic = u32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]);
         let block_size = u32::from_le_bytes([buffer[4], buffer[5], buffer[6], buffer[7]]) as usize;
         let block_count = u64::from_le_bytes([
@@ -67,45 +175,153 @@ impl SuperBlock {
             buffer[24], buffer[25], buffer[26], buffer[27], buffer[28], buffer[29], buffer[30],
             buffer[31],
         ]);
+        let features = u32::from_le_bytes([buffer[32], buffer[33], buffer[34], buffer[35]]);
+        let uuid = buffer[36..52].try_into().unwrap();
+        let label_bytes = &buffer[52..52 + LABEL_LENGTH];
+        let label_length = label_bytes
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(LABEL_LENGTH);
+        let label = String::from_utf8_lossy(&label_bytes[..label_length]).into_owned();
+        let free_blocks = u64::from_le_bytes(buffer[68..76].try_into().unwrap());
+        let free_inodes = u64::from_le_bytes(buffer[76..84].try_into().unwrap());
+        let journal_start = u64::from_le_bytes(buffer[84..92].try_into().unwrap());
+        let journal_blocks = u64::from_le_bytes(buffer[92..100].try_into().unwrap());
+        let backups = buffer[100..100 + MAX_BACKUPS * 8]
+            .chunks(8)
+            .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+            .filter(|location| *location != 0)
+            .collect();
         SuperBlock {
             magic,
             block_size,
             block_count,
             inode_count,
             root_inode: root_node,
+            features,
+            uuid,
+            label,
+            free_blocks,
+            free_inodes,
+            journal_start,
+            journal_blocks,
+            backups,
         }
     }
 
-    fn to_buffer(&self) -> Vec<u8> {
+    // `seed` is the block the buffer is written to, 0 for the primary superblock
+    fn to_buffer(&self, seed: u64) -> Vec<u8> {
         let mut buffer = Vec::new();
         buffer.extend_from_slice(&self.magic.to_le_bytes());
         buffer.extend_from_slice(&(self.block_size as u32).to_le_bytes());
         buffer.extend_from_slice(&self.block_count.to_le_bytes());
         buffer.extend_from_slice(&self.inode_count.to_le_bytes());
         buffer.extend_from_slice(&self.root_inode.to_le_bytes());
+        buffer.extend_from_slice(&self.features.to_le_bytes());
+        buffer.extend_from_slice(&self.uuid);
+        let mut label = self.label.as_bytes().to_vec();
+        label.resize(LABEL_LENGTH, 0);
+        buffer.extend_from_slice(&label);
+        buffer.extend_from_slice(&self.free_blocks.to_le_bytes());
+        buffer.extend_from_slice(&self.free_inodes.to_le_bytes());
+        buffer.extend_from_slice(&self.journal_start.to_le_bytes());
+        buffer.extend_from_slice(&self.journal_blocks.to_le_bytes());
+        let mut backups = self.backups.clone();
+        backups.resize(MAX_BACKUPS, 0);
+        for location in backups {
+            buffer.extend_from_slice(&location.to_le_bytes());
+        }
+        buffer.resize(SEALED_SIZE, 0);
+        seal(&mut buffer, seed);
         buffer
     }
 
-    pub fn write(&self, io: &mut IO) {
-        let mut buffer = self.to_buffer();
-        buffer.append(&mut vec![0; self.block_size - buffer.len()]);
-        io.write_block(0, &buffer);
+    /// Writes the superblock to block 0 and to all of its backups.
+    pub fn write(&self, io: &mut IO) -> Result<(), Error> {
+        for location in std::iter::once(0).chain(self.backups.iter().copied()) {
+            self.write_to(io, location)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the superblock to block 0 only, leaving the backups as they are.
+    pub fn write_primary(&self, io: &mut IO) -> Result<(), Error> {
+        self.write_to(io, 0)
+    }
+
+    fn write_to(&self, io: &mut IO, location: BlockPointer) -> Result<(), Error> {
+        let mut buffer = self.to_buffer(location);
+        buffer.resize(self.block_size, 0);
+        io.write_block(location, &buffer)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::driver::file_drive::FileDrive;
+    use crate::driver::memory_drive::MemoryDrive;
     use crate::io::IO;
 
     #[test]
     fn read_write_superblock() {
-        let drive = FileDrive::new("./test-images/test_superblock.img", 1024 * 512, 512);
+        let drive = MemoryDrive::new(1024 * 512, 512);
         let mut io = IO::new(drive, 512);
         let mut superblock = super::SuperBlock::new(512, 1024);
-        superblock.write(&mut io);
-        superblock.set_root_inode(&mut io, 42);
-        let drive_superblock = super::SuperBlock::read(&mut io).unwrap();
+        superblock.features = super::FEATURE_EXTENTS;
+        superblock.uuid = [7; 16];
+        superblock.label = String::from("volume");
+        superblock.free_blocks = 1000;
+        superblock.free_inodes = 100;
+        superblock.journal_start = 300;
+        superblock.journal_blocks = 64;
+        superblock.backups = vec![512, 1023];
+        superblock.write(&mut io).unwrap();
+        superblock.root_inode = 42;
+        superblock.write_primary(&mut io).unwrap();
+        let drive_superblock = super::SuperBlock::read(&io).unwrap().unwrap();
         assert_eq!(superblock, drive_superblock);
+
+        // the backups keep what was written with them
+        let backup = super::SuperBlock::read_backup(&mut io, 1023).unwrap();
+        assert_eq!(backup.root_inode, 0);
+    }
+
+    #[test]
+    fn backups() {
+        let drive = MemoryDrive::new(1024 * 512, 512);
+        let mut io = IO::new(drive.clone(), 1024);
+        let mut superblock = super::SuperBlock::new(1024, 512);
+        superblock.backups = super::SuperBlock::backup_locations(512, 10);
+        assert_eq!(superblock.backups, vec![256, 511]);
+        superblock.write(&mut io).unwrap();
+
+        // found at any block size, the block size in use is left alone
+        let mut io = IO::new(drive, 512);
+        assert_eq!(super::SuperBlock::find_backup(&mut io), Some(511));
+        assert_eq!(
+            super::SuperBlock::read_backup(&mut io, 256).unwrap(),
+            superblock
+        );
+        assert_eq!(io.get_block_size(), 512);
+        // a backup only verifies where it was written
+        assert!(super::SuperBlock::read_backup(&mut io, 255).is_err());
+        assert!(super::SuperBlock::read_backup(&mut io, 0).is_err());
+
+        assert_eq!(super::SuperBlock::backup_locations(512, 300), vec![511]);
+        assert_eq!(super::SuperBlock::backup_locations(512, 600), vec![]);
+    }
+
+    #[test]
+    fn superblock_checksum() {
+        let drive = MemoryDrive::new(1024 * 512, 512);
+        let mut io = IO::new(drive, 512);
+        let superblock = super::SuperBlock::new(512, 1024);
+        superblock.write(&mut io).unwrap();
+
+        let mut block = io.read_block(0).unwrap();
+        block[20] ^= 1;
+        io.write_block(0, &block).unwrap();
+        let error = super::SuperBlock::read(&io).unwrap_err();
+        assert_eq!(error.errno(), libc::EUCLEAN);
+        assert_eq!(io.checksum_errors(), 1);
     }
 }
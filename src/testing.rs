@@ -0,0 +1,44 @@
+// Fixtures shared by the tests of all modules.
+
+use crate::driver::memory_drive::MemoryDrive;
+use crate::driver::DeviceDriver;
+use crate::io::IO;
+use crate::ops::JourneyFS;
+use crate::structure::layout::FormatOptions;
+use crate::structure::Structure;
+use crate::util::serializable::{ByteSerializable, KnownSize};
+
+pub const SECTOR_SIZE: usize = 512;
+// the block size of the filesystems `create_fs` formats
+pub const BLOCK_SIZE: usize = 1024;
+// room for the journal and a few files spanning indirect blocks
+pub const DEVICE_SIZE: u64 = 2048 * 1024 * 5;
+
+/// An empty device of `DEVICE_SIZE` bytes.
+pub fn memory_drive() -> MemoryDrive {
+    MemoryDrive::new(DEVICE_SIZE, SECTOR_SIZE)
+}
+
+/// Formats `drive` with blocks of `block_size` bytes and the default options, without
+/// a root directory.
+pub fn create_structure<META: ByteSerializable + KnownSize>(
+    drive: impl DeviceDriver + 'static,
+    block_size: usize,
+) -> Structure<META> {
+    let options = FormatOptions {
+        block_size,
+        ..FormatOptions::default()
+    };
+    Structure::format(IO::new(drive, SECTOR_SIZE), &options).unwrap()
+}
+
+/// Formats `drive` with blocks of `BLOCK_SIZE` bytes and `features`, with a root
+/// directory owned by root.
+pub fn create_fs<D: DeviceDriver + 'static>(drive: D, features: u32) -> JourneyFS {
+    let options = FormatOptions {
+        block_size: BLOCK_SIZE,
+        features,
+        ..FormatOptions::default()
+    };
+    JourneyFS::format(drive, &options, 0, 0, false).unwrap()
+}
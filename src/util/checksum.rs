@@ -0,0 +1,81 @@
+// CRC32C (Castagnoli), reflected, as used by ext4 and btrfs
+const POLYNOMIAL: u32 = 0x82f6_3b78;
+
+const TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+pub fn crc32c(data: &[u8]) -> u32 {
+    crc32c_append(0, data)
+}
+
+/// Continues `crc`, the checksum of everything before `data`.
+pub fn crc32c_append(crc: u32, data: &[u8]) -> u32 {
+    !data.iter().fold(!crc, |crc, byte| {
+        TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Bytes taken by a checksum at the end of a sealed structure.
+pub const CHECKSUM_SIZE: usize = 4;
+
+/// Stores the checksum of `bytes` in its last `CHECKSUM_SIZE` bytes. `seed` ties the
+/// checksum to where the bytes belong, so that a structure written to the wrong place
+/// does not verify either.
+pub fn seal(bytes: &mut [u8], seed: u64) {
+    let (data, stored) = bytes.split_at_mut(bytes.len() - CHECKSUM_SIZE);
+    stored.copy_from_slice(&checksum(data, seed).to_le_bytes());
+}
+
+/// Whether the last `CHECKSUM_SIZE` bytes of `bytes` hold the checksum of the rest.
+pub fn is_sealed(bytes: &[u8], seed: u64) -> bool {
+    if bytes.len() < CHECKSUM_SIZE {
+        return false;
+    }
+    let (data, stored) = bytes.split_at(bytes.len() - CHECKSUM_SIZE);
+    stored == checksum(data, seed).to_le_bytes()
+}
+
+/// The checksum `seal` stores for `data`.
+pub fn checksum(data: &[u8], seed: u64) -> u32 {
+    crc32c_append(crc32c(&seed.to_le_bytes()), data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32c() {
+        assert_eq!(crc32c(b""), 0);
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+        assert_eq!(crc32c_append(crc32c(b"1234"), b"56789"), 0xe306_9283);
+    }
+
+    #[test]
+    fn test_seal() {
+        let mut bytes = b"some metadata\0\0\0\0".to_vec();
+        seal(&mut bytes, 7);
+        assert!(is_sealed(&bytes, 7));
+        assert!(!is_sealed(&bytes, 8));
+        bytes[2] ^= 0x10;
+        assert!(!is_sealed(&bytes, 7));
+        assert!(!is_sealed(&bytes[..3], 7));
+    }
+}
@@ -1,19 +1,134 @@
+use std::fmt;
 use std::os::raw::c_int;
 
 type ErrorNum = c_int;
 
+/// Where on the device a corruption was found.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Location {
+    Block(u64),
+    Inode(u64),
+}
+
 #[derive(Debug)]
-pub struct Error {
-    pub message: String,
-    pub error_num: ErrorNum,
+pub enum Error {
+    /// The underlying device failed.
+    Io { message: String, source: Option<std::io::Error> },
+    /// On-disk structures are inconsistent.
+    Corrupted { message: String, location: Option<Location> },
+    NoSpace,
+    NoInodes,
+    InvalidArgument(String),
+    NotFound(String),
+    PermissionDenied(String),
+    /// Any other POSIX condition, kept as its raw errno.
+    Other { message: String, errno: ErrorNum },
 }
 
 impl Error {
-    pub fn new(message: &str, error_num: Option<ErrorNum>) -> Error {
-        Error {
-            message: message.to_string(),
-            error_num: error_num.unwrap_or(libc::EOPNOTSUPP),
+    /// Picks the kind matching `errno`.
+    pub fn new(message: &str, errno: ErrorNum) -> Error {
+        let message = message.to_string();
+        match errno {
+            libc::EIO => Error::Io { message, source: None },
+            libc::EUCLEAN => Error::Corrupted { message, location: None },
+            libc::EINVAL => Error::InvalidArgument(message),
+            libc::ENOENT => Error::NotFound(message),
+            libc::EACCES => Error::PermissionDenied(message),
+            errno => Error::Other { message, errno },
+        }
+    }
+
+    pub fn io(message: &str, source: std::io::Error) -> Error {
+        Error::Io { message: message.to_string(), source: Some(source) }
+    }
+
+    pub fn corrupted(message: &str, location: Location) -> Error {
+        Error::Corrupted { message: message.to_string(), location: Some(location) }
+    }
+
+    /// Attaches `location` to a corruption error that doesn't have one yet.
+    pub fn at(self, location: Location) -> Error {
+        match self {
+            Error::Corrupted { message, location: None } => Error::Corrupted { message, location: Some(location) },
+            error => error,
+        }
+    }
+
+    /// The errno reported to the kernel for this error.
+    pub fn errno(&self) -> ErrorNum {
+        match self {
+            Error::Io { .. } => libc::EIO,
+            Error::Corrupted { .. } => libc::EUCLEAN,
+            Error::NoSpace | Error::NoInodes => libc::ENOSPC,
+            Error::InvalidArgument(_) => libc::EINVAL,
+            Error::NotFound(_) => libc::ENOENT,
+            Error::PermissionDenied(_) => libc::EACCES,
+            Error::Other { errno, .. } => *errno,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io { message, source: Some(source) } => write!(f, "{}: {}", message, source),
+            Error::Io { message, source: None } => write!(f, "{}", message),
+            Error::Corrupted { message, location: Some(Location::Block(block)) } => write!(f, "{} (block {})", message, block),
+            Error::Corrupted { message, location: Some(Location::Inode(inode)) } => write!(f, "{} (inode {})", message, inode),
+            Error::Corrupted { message, location: None } => write!(f, "{}", message),
+            Error::NoSpace => write!(f, "No space left on device"),
+            Error::NoInodes => write!(f, "No free inodes left"),
+            Error::InvalidArgument(message) | Error::NotFound(message) | Error::PermissionDenied(message) => write!(f, "{}", message),
+            Error::Other { message, .. } => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source: Some(source), .. } => Some(source),
+            _ => None,
         }
     }
 }
 
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Error {
+        Error::io("I/O error", error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Error, Location};
+    use std::error::Error as _;
+
+    #[test]
+    fn errno_mapping() {
+        assert_eq!(Error::new("", libc::EINVAL).errno(), libc::EINVAL);
+        assert!(matches!(Error::new("", libc::EINVAL), Error::InvalidArgument(_)));
+        assert!(matches!(Error::new("", libc::ENOENT), Error::NotFound(_)));
+        assert!(matches!(Error::new("", libc::EEXIST), Error::Other { errno: libc::EEXIST, .. }));
+        assert_eq!(Error::NoSpace.errno(), libc::ENOSPC);
+        assert_eq!(Error::NoInodes.errno(), libc::ENOSPC);
+        assert_eq!(Error::corrupted("Bad node", Location::Block(3)).errno(), libc::EUCLEAN);
+    }
+
+    #[test]
+    fn corruption_location() {
+        let error = Error::new("Bad node", libc::EUCLEAN).at(Location::Inode(7));
+        assert_eq!(error.to_string(), "Bad node (inode 7)");
+        let error = error.at(Location::Block(1));
+        assert_eq!(error.to_string(), "Bad node (inode 7)");
+    }
+
+    #[test]
+    fn io_source() {
+        let error = Error::from(std::io::Error::from_raw_os_error(libc::ENOSPC));
+        assert_eq!(error.errno(), libc::EIO);
+        assert!(error.source().is_some());
+        assert!(Error::NoSpace.source().is_none());
+    }
+}
@@ -16,3 +16,35 @@ pub fn pretty_size_from_bytes(bytes: u64) -> String {
         format!("{:.2} TB", bytes as f64 / TERRA_BYTE as f64)
     }
 }
+
+/// Parses a byte count with an optional `K`, `M`, `G` or `T` suffix (powers of 1024).
+pub fn parse_size(text: &str) -> Option<u64> {
+    let (digits, unit) = match text.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((index, _)) => text.split_at(index),
+        None => (text, ""),
+    };
+    let multiplier = match unit.to_ascii_uppercase().trim_end_matches(['B', 'I']) {
+        "" => 1,
+        "K" => KILO_BYTE,
+        "M" => MEGA_BYTE,
+        "G" => GIGA_BYTE,
+        "T" => TERRA_BYTE,
+        _ => return None,
+    };
+    digits.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("4K"), Some(4096));
+        assert_eq!(parse_size("10MiB"), Some(10 * MEGA_BYTE));
+        assert_eq!(parse_size("2g"), Some(2 * GIGA_BYTE));
+        assert_eq!(parse_size("G"), None);
+        assert_eq!(parse_size("12X"), None);
+    }
+}
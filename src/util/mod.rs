@@ -1,4 +1,6 @@
+pub mod checksum;
 pub mod error;
 pub mod format;
 pub mod mode;
 pub mod serializable;
+pub mod uuid;
@@ -1,6 +1,8 @@
-pub trait ByteSerializable {
+use crate::util::error::Error;
+
+pub trait ByteSerializable: Sized {
     fn to_bytes(&self) -> Vec<u8>;
-    fn from_bytes(bytes: &[u8]) -> Self;
+    fn from_bytes(bytes: &[u8]) -> Result<Self, Error>;
 }
 
 pub trait KnownSize: ByteSerializable {
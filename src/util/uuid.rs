@@ -0,0 +1,61 @@
+use crate::util::error::Error;
+use std::fs::File;
+use std::io::Read;
+
+pub type Uuid = [u8; 16];
+
+/// Generates a random (version 4) UUID.
+pub fn generate() -> Result<Uuid, Error> {
+    let mut uuid = [0u8; 16];
+    File::open("/dev/urandom")
+        .and_then(|mut random| random.read_exact(&mut uuid))
+        .map_err(|error| Error::io("Failed to read random bytes", error))?;
+    uuid[6] = (uuid[6] & 0x0f) | 0x40;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+    Ok(uuid)
+}
+
+/// Parses the usual `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx` notation.
+pub fn parse(text: &str) -> Option<Uuid> {
+    let groups: Vec<&str> = text.split('-').collect();
+    let lengths: Vec<usize> = groups.iter().map(|group| group.len()).collect();
+    if lengths != [8, 4, 4, 4, 12] {
+        return None;
+    }
+
+    let digits = groups.concat();
+    let mut uuid = [0u8; 16];
+    for (i, byte) in uuid.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(digits.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(uuid)
+}
+
+pub fn to_string(uuid: &Uuid) -> String {
+    let hex: String = uuid.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uuid_round_trip() {
+        let uuid = generate().unwrap();
+        assert_eq!(uuid[6] >> 4, 4);
+        assert_eq!(parse(&to_string(&uuid)), Some(uuid));
+
+        let text = "0123abcd-4567-89ef-0123-456789abcdef";
+        assert_eq!(to_string(&parse(text).unwrap()), text);
+        assert_eq!(parse("0123abcd-4567-89ef-0123-456789abcde"), None);
+        assert_eq!(parse("0123abcd-4567-89ef-0123-456789abcdeg"), None);
+    }
+}
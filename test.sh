@@ -1 +1 @@
-rm -rf ./test-images && mkdir ./test-images && RUST_BACKTRACE=1 cargo test
+RUST_BACKTRACE=1 cargo test